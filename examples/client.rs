@@ -39,6 +39,9 @@ async fn main() -> Result<()> {
     loop {
         match receiver.recv().await {
             Some(event) => match event {
+                ClientEvent::Connecting => {
+                    println!("Connecting to server...");
+                }
                 ClientEvent::Connected => {
                     println!("Connected to server!");
 
@@ -74,14 +77,17 @@ async fn main() -> Result<()> {
                         }
                     });
                 }
-                ClientEvent::Received(data) => {
+                ClientEvent::Received { data, .. } => {
                     println!(
                         "Received from server: {}",
                         std::str::from_utf8(&data).unwrap()
                     );
                 }
-                ClientEvent::Disconnected => {
-                    println!("Disconnected from server!");
+                ClientEvent::Disconnected { reason } => {
+                    println!("Disconnected from server ({:?})!", reason);
+                }
+                ClientEvent::Reconnected => {
+                    println!("Reconnected to server!");
                 }
             },
             None => {
@@ -70,10 +70,17 @@ fn main() -> Result<()> {
                     loop {
                         match server_receiver.recv().await {
                             Some(event) => match event {
-                                ServerEvent::Connected { id, claim } => {
-                                    println!("SERVER - Client {}, connected! Claim: {}", id, claim);
+                                ServerEvent::Connected {
+                                    id,
+                                    claim,
+                                    peer_address,
+                                } => {
+                                    println!(
+                                        "SERVER - Client {}, connected from {}! Claim: {}",
+                                        id, peer_address, claim
+                                    );
                                 }
-                                ServerEvent::Received { id, data } => {
+                                ServerEvent::Received { id, data, .. } => {
                                     println!(
                                         "SERVER - Received from client ({}): {}",
                                         id,
@@ -84,8 +91,30 @@ fn main() -> Result<()> {
                                     data.extend(b" - seen by server.");
                                     server_sender.reliable(id, data).unwrap();
                                 }
-                                ServerEvent::Disconnected { id } => {
-                                    println!("SERVER - Client {}, disconnected!", id);
+                                ServerEvent::Disconnected { id, reason } => {
+                                    println!(
+                                        "SERVER - Client {}, disconnected ({:?})!",
+                                        id, reason
+                                    );
+                                }
+                                ServerEvent::DuplicateConnection {
+                                    existing_id,
+                                    new_id,
+                                    ..
+                                } => {
+                                    println!(
+                                        "SERVER - Client {}, is a duplicate of client {}!",
+                                        new_id, existing_id
+                                    );
+                                }
+                                ServerEvent::Stats(stats) => {
+                                    println!(
+                                        "SERVER - Client {}, sent {} bytes and received {} bytes.",
+                                        stats.id, stats.bytes_sent, stats.bytes_received
+                                    );
+                                }
+                                ServerEvent::Error { id, error } => {
+                                    println!("SERVER - Client {}, error: {:?}", id, error);
                                 }
                             },
                             None => {
@@ -131,6 +160,9 @@ fn main() -> Result<()> {
                     loop {
                         match client_receiver.recv().await {
                             Some(event) => match event {
+                                ClientEvent::Connecting => {
+                                    log::info!("CLIENT: Connecting to server...");
+                                }
                                 ClientEvent::Connected => {
                                     log::info!("CLIENT: Connected to server!");
 
@@ -150,14 +182,17 @@ fn main() -> Result<()> {
                                         }
                                     });
                                 }
-                                ClientEvent::Received(data) => {
+                                ClientEvent::Received { data, .. } => {
                                     log::info!(
                                         "CLIENT: Received from server: {}",
                                         std::str::from_utf8(&data).unwrap()
                                     );
                                 }
-                                ClientEvent::Disconnected => {
-                                    log::info!("CLIENT: Disconnected from server!");
+                                ClientEvent::Disconnected { reason } => {
+                                    log::info!("CLIENT: Disconnected from server ({:?})!", reason);
+                                }
+                                ClientEvent::Reconnected => {
+                                    log::info!("CLIENT: Reconnected to server!");
                                 }
                             },
                             None => {
@@ -0,0 +1,74 @@
+#[cfg(feature = "rustls")]
+fn main() {
+    eprintln!("This example does not use TLS, run it with: cargo run --example echo_plain --no-default-features");
+}
+
+#[cfg(not(feature = "rustls"))]
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    use tokio::time::{sleep, Duration};
+    use zelda::{Client, ClientEvent, Config, Server, ServerEvent};
+
+    env_logger::init();
+
+    let address = "127.0.0.1:10000";
+
+    let (server_sender, mut server_receiver, _, server_task) =
+        Server::listen(address, Config::default(), |token| {
+            if token == b"TOKEN" {
+                Some("Hunter2")
+            } else {
+                None
+            }
+        });
+    tokio::spawn(server_task);
+    tokio::spawn(async move {
+        while let Some(event) = server_receiver.recv().await {
+            match event {
+                ServerEvent::Connected {
+                    id,
+                    claim,
+                    peer_address,
+                } => {
+                    println!(
+                        "SERVER - Client {}, connected from {}! Claim: {}",
+                        id, peer_address, claim
+                    );
+                }
+                ServerEvent::Received { id, data, .. } => {
+                    let mut data = data;
+                    data.extend(b" - seen by server.");
+                    server_sender.reliable(id, data).unwrap();
+                }
+                ServerEvent::Disconnected { id, reason } => {
+                    println!("SERVER - Client {}, disconnected ({:?})!", id, reason);
+                }
+                _ => {}
+            }
+        }
+    });
+
+    sleep(Duration::from_millis(500)).await;
+
+    let (client_sender, mut client_receiver, client_task) =
+        Client::connect(address, Config::default(), b"TOKEN".to_vec());
+    tokio::spawn(client_task);
+
+    let mut interval = tokio::time::interval(Duration::from_millis(500));
+    for _ in 0..10 {
+        tokio::select! {
+            _ = interval.tick() => {
+                client_sender.reliable(b"Hello, world!".to_vec())?;
+                client_sender.unreliable(b"Hello, world!".to_vec())?;
+            },
+            Some(event) = client_receiver.recv() => match event {
+                ClientEvent::Received { data, .. } => {
+                    println!("CLIENT - Received from server: {}", std::str::from_utf8(&data)?);
+                }
+                event => println!("CLIENT - {:?}", event),
+            },
+        }
+    }
+
+    Ok(())
+}
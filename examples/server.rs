@@ -54,8 +54,10 @@ async fn main() -> Result<()> {
             loop {
                 match receiver.recv().await {
                     Some(event) => match event {
-                        ServerEvent::Connected { id, claim: _ } => {
-                            println!("SERVER - Client {}, connected!", id);
+                        ServerEvent::Connected {
+                            id, peer_address, ..
+                        } => {
+                            println!("SERVER - Client {}, connected from {}!", id, peer_address);
 
                             let disconnector = disconnector.clone();
                             tokio::spawn(async move {
@@ -63,7 +65,7 @@ async fn main() -> Result<()> {
                                 let _ = disconnector.disconnect(id);
                             });
                         }
-                        ServerEvent::Received { id, data } => {
+                        ServerEvent::Received { id, data, .. } => {
                             println!(
                                 "SERVER - Received from client ({}): {}",
                                 id,
@@ -74,8 +76,27 @@ async fn main() -> Result<()> {
                             data.extend(b" - seen by server.");
                             sender.reliable(id, data).unwrap();
                         }
-                        ServerEvent::Disconnected { id } => {
-                            println!("SERVER - Client {}, disconnected!", id);
+                        ServerEvent::Disconnected { id, reason } => {
+                            println!("SERVER - Client {}, disconnected ({:?})!", id, reason);
+                        }
+                        ServerEvent::DuplicateConnection {
+                            existing_id,
+                            new_id,
+                            ..
+                        } => {
+                            println!(
+                                "SERVER - Client {}, is a duplicate of client {}!",
+                                new_id, existing_id
+                            );
+                        }
+                        ServerEvent::Stats(stats) => {
+                            println!(
+                                "SERVER - Client {}, sent {} bytes and received {} bytes.",
+                                stats.id, stats.bytes_sent, stats.bytes_received
+                            );
+                        }
+                        ServerEvent::Error { id, error } => {
+                            println!("SERVER - Client {}, error: {:?}", id, error);
                         }
                     },
                     None => {
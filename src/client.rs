@@ -1,47 +1,339 @@
-use futures::StreamExt;
-use std::future::Future;
+use futures::{
+    channel::{mpsc, oneshot},
+    SinkExt, StreamExt,
+};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 use tokio::{
-    io::split,
-    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    io::{split, ReadHalf},
+    net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs, UdpSocket},
+    runtime,
+    task::JoinHandle,
+    time::{interval, sleep, sleep_until, timeout},
 };
 
 use crate::{
-    connection::ConnectionError, receiver, sender, Config, Connection, Delivery, Receiver, Sender,
+    clock::Clock,
+    connection::{self, ConnectionError},
+    datagram::{self, Payload},
+    netsim::{self, Simulator},
+    receiver,
+    sender::{self, Queued, SendError},
+    span::{self, Instrument},
+    Config, ConfigError, Connection, Delivery, DisconnectReason, Features, Header, Hooks, Receiver,
+    Sender,
 };
 
 #[cfg(feature = "rustls")]
-use tokio_rustls::{rustls::ClientConfig, webpki::DNSName, TlsConnector};
-
+use crate::{tls, TlsInfo};
 #[cfg(feature = "rustls")]
-use std::sync::Arc;
+use tokio_rustls::{rustls::ClientConfig, webpki::DNSName, TlsConnector};
 
 #[derive(Debug, Clone)]
 pub enum ClientEvent {
+    /// The client is about to connect to the server and perform the handshake, either initially or to reconnect, see [`Config::reconnect`].
+    /// It is followed by [`ClientEvent::Connected`] (or [`ClientEvent::Reconnected`]), or by the task ending with a [`ClientError`] describing the stage that failed.
+    Connecting,
     Connected,
-    Received(Vec<u8>),
-    Disconnected,
+    /// A message was received from the server.
+    /// `received_at` is captured from the monotonic clock of this process when the frame (reliable) or datagram (unreliable) was read from the socket,
+    /// it does not include the time the event spent in the event queue.
+    Received {
+        header: Header,
+        data: Vec<u8>,
+        received_at: Instant,
+    },
+    /// The connection ended, either by [`ClientSender::disconnect`], or because it was lost and not reestablished, see [`Config::reconnect`].
+    Disconnected {
+        reason: DisconnectReason,
+    },
+    /// The connection was lost and the client reconnected, see [`Config::reconnect`].
+    /// Messages sent before the connection was lost may not have been delivered.
+    Reconnected,
+}
+
+impl receiver::Timestamped for ClientEvent {
+    fn received_at(&self) -> Option<Instant> {
+        match self {
+            ClientEvent::Received { received_at, .. } => Some(*received_at),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum ClientError {
     #[error("Unable to create client.")]
     Io(#[from] std::io::Error),
+    /// The server could not be reached: the address did not resolve, or the TCP connection was refused or timed out.
+    #[error("Unable to reach the server.")]
+    Unreachable(#[source] std::io::Error),
+    /// The TLS handshake failed, for instance because the certificate of the server was not trusted.
+    #[error("Unable to establish a secure connection.")]
+    Tls(#[source] std::io::Error),
+    /// The server supports none of the application protocols offered by the client, see [`ClientConfig::set_protocols`].
+    #[cfg(feature = "rustls")]
+    #[error("Server supports none of the application protocols.")]
+    NoApplicationProtocol,
     #[error("Unable to establish connection.")]
     Connection(#[from] ConnectionError),
+    /// The server is full, see [`Config::max_connections`].
+    #[error("Server rejected the connection.")]
+    Rejected,
+    #[error("Server rejected the token.")]
+    InvalidToken,
+    /// No local port was available for the UDP socket or the TCP stream, typically because too many connections are open.
+    #[error("Unable to bind a local port.")]
+    PortExhausted(#[source] std::io::Error),
+    /// The connection was not established within [`Config::connect_timeout`].
+    #[error("Timed out connecting to the server.")]
+    Timeout,
     #[error("Unable to dispatch event.")]
     Event(#[from] receiver::TrySendError<ClientEvent>),
+    /// The [`Config`] is invalid, see [`Config::validate`]. The client task resolves with this error before connecting.
+    #[error("Invalid configuration.")]
+    Config(#[from] ConfigError),
+}
+
+impl ClientError {
+    /// Maps errors caused by running out of local ports to [`ClientError::PortExhausted`].
+    fn from_bind(err: std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable => Self::PortExhausted(err),
+            _ => Self::Io(err),
+        }
+    }
+
+    /// Maps errors caused by running out of local ports to [`ClientError::PortExhausted`], and other errors to [`ClientError::Unreachable`].
+    fn from_connect(err: std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable => Self::PortExhausted(err),
+            _ => Self::Unreachable(err),
+        }
+    }
+}
+
+/// Commands dispatched from a [`ClientSender`] to the client task.
+#[derive(Debug)]
+pub enum ClientCommand {
+    Send {
+        header: Header,
+        data: Vec<u8>,
+        delivery: Delivery,
+    },
+    /// Send a reliable message and reply once the server has read it, see [`ClientSender::reliable_with_ack`].
+    SendWithReceipt {
+        header: Header,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<(), SendError>>,
+    },
+    /// Measure the round trip time with a time request, see [`ClientSender::ping`].
+    Ping {
+        reply: oneshot::Sender<Result<Duration, SendError>>,
+    },
+    /// Close the connection and end the client task, see [`ClientSender::disconnect`].
+    Disconnect,
+    /// Write the reliable messages sent before and reply once they are written, see [`ClientSender::flush`].
+    Flush {
+        reply: oneshot::Sender<Result<(), SendError>>,
+    },
+}
+
+/// State of the connection of a client, see [`ClientSender::state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting to the server for the first time, which is also the state until the client task runs.
+    Connecting,
+    Connected,
+    /// The connection was lost and the client is reconnecting, see [`Config::reconnect`].
+    Reconnecting,
+    /// The client task has ended, or was dropped after it started running.
+    Disconnected,
+}
+
+impl ConnectionState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => Self::Connecting,
+            1 => Self::Connected,
+            2 => Self::Reconnecting,
+            _ => Self::Disconnected,
+        }
+    }
 }
 
-pub type ClientSender = Sender<(Vec<u8>, Delivery)>;
+/// State of the client task that can be queried through a [`ClientSender`].
+#[derive(Debug, Default)]
+pub struct ClientState {
+    /// A [`ConnectionState`] as `u8`.
+    connection_state: AtomicU8,
+    pub(crate) clock: Clock,
+    packet_loss: Mutex<Option<f32>>,
+    pub(crate) queued: Queued,
+    /// Local addresses of the UDP socket and the TCP stream.
+    local_addresses: Mutex<Option<(SocketAddr, SocketAddr)>>,
+    #[cfg(feature = "rustls")]
+    tls: Mutex<Option<TlsInfo>>,
+}
+
+impl ClientState {
+    pub(crate) fn connection_state(&self) -> ConnectionState {
+        ConnectionState::from_u8(self.connection_state.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_connection_state(&self, connection_state: ConnectionState) {
+        self.connection_state
+            .store(connection_state as u8, Ordering::Relaxed);
+    }
+
+    /// Marks the client as [`ConnectionState::Disconnected`] once the returned guard is dropped, which also covers the client task being dropped.
+    /// Messages the task has not taken by then are discarded, so they no longer count as queued.
+    pub(crate) fn disconnect_on_drop(&self) -> impl Drop + '_ {
+        struct Guard<'a>(&'a ClientState);
+
+        impl Drop for Guard<'_> {
+            fn drop(&mut self) {
+                self.0.set_connection_state(ConnectionState::Disconnected);
+                self.0.queued.reset();
+            }
+        }
+
+        Guard(self)
+    }
+
+    pub(crate) fn packet_loss(&self) -> Option<f32> {
+        *self.packet_loss.lock().unwrap()
+    }
+
+    fn set_packet_loss(&self, packet_loss: Option<f32>) {
+        *self.packet_loss.lock().unwrap() = packet_loss;
+    }
+
+    pub(crate) fn local_addresses(&self) -> Option<(SocketAddr, SocketAddr)> {
+        *self.local_addresses.lock().unwrap()
+    }
+
+    #[cfg(feature = "rustls")]
+    pub(crate) fn tls(&self) -> Option<TlsInfo> {
+        self.tls.lock().unwrap().clone()
+    }
+}
+
+pub type ClientSender = Sender<ClientCommand, ClientState>;
 pub type ClientReceiver = Receiver<ClientEvent>;
 
+#[cfg(not(feature = "rustls"))]
+type Stream = TcpStream;
+
+#[cfg(feature = "rustls")]
+type Stream = tokio_rustls::client::TlsStream<TcpStream>;
+
+/// An established connection to the server.
+struct Session {
+    socket: UdpSocket,
+    read_stream: ReadHalf<Stream>,
+    id: u32,
+    connection: Connection<Stream>,
+}
+
+/// Aborts the task reading reliable frames when the session ends.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Takes the commands kept from before a reconnect first, then waits for the next command sent.
+async fn next_command(
+    pending: &mut VecDeque<ClientCommand>,
+    outbound_receiver: &mut sender::PriorityReceiver<ClientCommand>,
+) -> Option<ClientCommand> {
+    match pending.pop_front() {
+        Some(command) => Some(command),
+        None => outbound_receiver.next().await,
+    }
+}
+
+/// Reads reliable frames on a task of their own, since [`Connection::read`] is not cancel-safe:
+/// reading in the same `select!` as the other branches of [`Client::run`] would drop partly read frames whenever another branch completes first.
+/// The task ends after the first error, which is passed on like the frames.
+fn spawn_reader(
+    mut read_stream: ReadHalf<Stream>,
+    max_size: u32,
+    span: span::Span,
+) -> (mpsc::Receiver<io::Result<Vec<u8>>>, AbortOnDrop) {
+    let (mut frame_sender, frame_receiver) = mpsc::channel(1);
+    let reader = tokio::spawn(
+        async move {
+            loop {
+                let result = Connection::read(&mut read_stream, max_size).await;
+                let failed = result.is_err();
+                if frame_sender.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        }
+        .instrument(span),
+    );
+
+    (frame_receiver, AbortOnDrop(reader))
+}
+
+/// Handle to a client running on its own thread, see [`Client::connect_blocking`].
+#[derive(Debug)]
+pub struct ClientHandle {
+    thread: thread::JoinHandle<Result<(), ClientError>>,
+    stop: oneshot::Sender<()>,
+}
+
+impl ClientHandle {
+    /// Stops the client, closing the connection without dispatching [`ClientEvent::Disconnected`], and waits for its thread to finish.
+    pub fn stop(self) -> Result<(), ClientError> {
+        let _ = self.stop.send(());
+        Self::join_thread(self.thread)
+    }
+
+    /// Waits for the client to end on its own, that is, until the connection is lost (and not reconnected) or fails.
+    pub fn join(self) -> Result<(), ClientError> {
+        Self::join_thread(self.thread)
+    }
+
+    /// Whether the client has ended.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    fn join_thread(thread: thread::JoinHandle<Result<(), ClientError>>) -> Result<(), ClientError> {
+        thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
 pub struct Client;
 
 impl Client {
     /// Connect to a server.
     /// Returns a [`Sender`], [`Receiver`] and a [`Future`] which must be awaited in an async executor (see the examples in the [repository](https://github.com/oskarbraten/zelda/)).
     /// The client can run in a separate thread and messages/events can be sent/received in a synchronous context.
+    ///
+    /// Messages can be sent right away. Messages sent before [`ClientEvent::Connected`] are queued and written in the order they were sent once the handshake completes.
+    /// Reliable messages follow the handshake on the same stream, so the server receives each of them exactly once after [`ServerEvent::Connected`](crate::ServerEvent::Connected).
+    /// Unreliable messages may reach the server before it has finished the handshake, in which case they are dropped like any lost datagram.
+    /// If the connection cannot be established, the task ends with a [`ClientError`] and the queued messages are discarded.
     pub fn connect<A: ToSocketAddrs>(
         address: A,
         config: Config,
@@ -49,13 +341,43 @@ impl Client {
         #[cfg(feature = "rustls")] client_config: ClientConfig,
         token: Vec<u8>,
     ) -> (
-        Sender<(Vec<u8>, Delivery)>,
-        Receiver<ClientEvent>,
+        ClientSender,
+        ClientReceiver,
         impl Future<Output = Result<(), ClientError>>,
     ) {
-        let (outbound_sender, outbound_receiver) = sender::channel::<(Vec<u8>, Delivery)>();
+        Self::connect_with_hooks(
+            address,
+            config,
+            #[cfg(feature = "rustls")]
+            domain,
+            #[cfg(feature = "rustls")]
+            client_config,
+            token,
+            Hooks::default(),
+        )
+    }
+
+    /// Connect to a server like [`Client::connect`], invoking [`Hooks::on_send`] and [`Hooks::on_receive`] on the payload of every message.
+    pub fn connect_with_hooks<A: ToSocketAddrs>(
+        address: A,
+        config: Config,
+        #[cfg(feature = "rustls")] domain: DNSName,
+        #[cfg(feature = "rustls")] client_config: ClientConfig,
+        token: Vec<u8>,
+        hooks: Hooks,
+    ) -> (
+        ClientSender,
+        ClientReceiver,
+        impl Future<Output = Result<(), ClientError>>,
+    ) {
+        // A config built without [`ConfigBuilder::build`](crate::ConfigBuilder::build) has not been validated yet.
+        let validated = config.validate();
+        let (outbound_sender, outbound_receiver) = sender::priority_channel::<ClientCommand>();
         let (inbound_sender, inbound_receiver) =
             receiver::channel::<ClientEvent>(config.event_capacity);
+        let max_event_age = config.max_event_age;
+        let state = Arc::new(ClientState::default());
+        let hooks = Arc::new(hooks);
 
         let task = Self::task(
             address,
@@ -65,91 +387,624 @@ impl Client {
             #[cfg(feature = "rustls")]
             client_config,
             token,
+            hooks.clone(),
             inbound_sender,
             outbound_receiver,
+            state.clone(),
         );
 
+        let receiver = Receiver::new(inbound_receiver).max_age(max_event_age);
+        let close_on_drop = receiver.close_on_drop();
+        let task = async move {
+            let _close_on_drop = close_on_drop;
+            validated?;
+            task.await
+        }
+        .instrument(span::client());
+
         (
-            Sender::new(outbound_sender),
-            Receiver::new(inbound_receiver),
+            Sender::new(outbound_sender, state, config, hooks),
+            receiver,
             task,
         )
     }
 
+    /// Connect to a server without an async runtime.
+    /// The client runs on a new thread with its own single-threaded runtime, and stops when the returned [`ClientHandle`] is stopped.
+    /// Messages can be sent with the [`ClientSender`] as usual, and events received with [`Receiver::try_recv`] or [`Receiver::recv_blocking`].
+    pub fn connect_blocking<A: ToSocketAddrs + Send + 'static>(
+        address: A,
+        config: Config,
+        #[cfg(feature = "rustls")] domain: DNSName,
+        #[cfg(feature = "rustls")] client_config: ClientConfig,
+        token: Vec<u8>,
+    ) -> (ClientSender, ClientReceiver, ClientHandle) {
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (handles_sender, handles_receiver) = std::sync::mpsc::sync_channel(1);
+
+        // The task is created on the thread that runs it, since it is not `Send` for every address type.
+        let thread = thread::spawn(move || {
+            let (sender, receiver, task) = Self::connect(
+                address,
+                config,
+                #[cfg(feature = "rustls")]
+                domain,
+                #[cfg(feature = "rustls")]
+                client_config,
+                token,
+            );
+            let _ = handles_sender.send((sender, receiver));
+
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+
+            runtime.block_on(async move {
+                tokio::select! {
+                    result = task => result,
+                    _ = stopped => Ok(()),
+                }
+            })
+        });
+        let (sender, receiver) = handles_receiver.recv().unwrap();
+
+        (sender, receiver, ClientHandle { thread, stop })
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn task<A: ToSocketAddrs>(
         address: A,
         config: Config,
         #[cfg(feature = "rustls")] domain: DNSName,
         #[cfg(feature = "rustls")] client_config: ClientConfig,
         token: Vec<u8>,
+        hooks: Arc<Hooks>,
         mut inbound_sender: receiver::InnerSender<ClientEvent>,
-        mut outbound_receiver: sender::InnerReceiver<(Vec<u8>, Delivery)>,
+        mut outbound_receiver: sender::PriorityReceiver<ClientCommand>,
+        state: Arc<ClientState>,
     ) -> Result<(), ClientError> {
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.connect(&address).await?;
+        #[cfg(feature = "rustls")]
+        let client_config = Arc::new(client_config);
+
+        let establish = || {
+            let establish = Self::establish(
+                &address,
+                config,
+                #[cfg(feature = "rustls")]
+                &domain,
+                #[cfg(feature = "rustls")]
+                &client_config,
+                token.clone(),
+                &state,
+            );
+
+            // Dropping the handshake on timeout closes the sockets it opened.
+            async move {
+                match config.connect_timeout {
+                    Some(connect_timeout) => timeout(connect_timeout, establish)
+                        .await
+                        .map_err(|_| ClientError::Timeout)?,
+                    None => establish.await,
+                }
+            }
+        };
+
+        let _disconnected = state.disconnect_on_drop();
+        receiver::dispatch(
+            &mut inbound_sender,
+            ClientEvent::Connecting,
+            config.event_overflow,
+        )
+        .await?;
+
+        let mut session = establish().await?;
+        state.set_connection_state(ConnectionState::Connected);
+        receiver::dispatch(
+            &mut inbound_sender,
+            ClientEvent::Connected,
+            config.event_overflow,
+        )
+        .await?;
+
+        // Commands kept from before a reconnect, handled before those still in the channel.
+        let mut pending = VecDeque::new();
+        loop {
+            let connection_span = session.connection.span.clone();
+            let err = match Self::run(
+                session,
+                config,
+                &hooks,
+                &mut inbound_sender,
+                &mut outbound_receiver,
+                std::mem::take(&mut pending),
+                &state,
+            )
+            .instrument(connection_span)
+            .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+
+            // Only a lost connection is worth reconnecting, the other errors would recur.
+            let reason = match &err {
+                ClientError::Io(err) => Some(connection::disconnect_reason(err)),
+                _ => None,
+            };
+            let (policy, reason) = match (config.reconnect, reason) {
+                (Some(policy), Some(reason)) => (policy, reason),
+                _ => {
+                    if let Some(reason) = reason {
+                        receiver::dispatch(
+                            &mut inbound_sender,
+                            ClientEvent::Disconnected { reason },
+                            config.event_overflow,
+                        )
+                        .await?;
+                    }
+                    return Err(err);
+                }
+            };
+            log::debug!("Connection lost, reconnecting: {}", err);
+            state.set_connection_state(ConnectionState::Reconnecting);
+
+            let mut attempt = 0;
+            session = loop {
+                if attempt == policy.max_attempts {
+                    // The reason is the one the connection was lost for, not why reconnecting failed.
+                    receiver::dispatch(
+                        &mut inbound_sender,
+                        ClientEvent::Disconnected { reason },
+                        config.event_overflow,
+                    )
+                    .await?;
+                    return Err(err);
+                }
+
+                sleep(policy.backoff(attempt)).await;
+                attempt += 1;
+
+                receiver::dispatch(
+                    &mut inbound_sender,
+                    ClientEvent::Connecting,
+                    config.event_overflow,
+                )
+                .await?;
+
+                match establish().await {
+                    Ok(session) => break session,
+                    Err(err) => log::debug!("Error reconnecting (attempt {}): {}", attempt, err),
+                }
+            };
+
+            if !policy.retain_messages {
+                while let Some(command) = outbound_receiver.try_recv() {
+                    match command {
+                        // Dropping the reply of a message sent with a receipt fails it.
+                        ClientCommand::Send { .. } | ClientCommand::SendWithReceipt { .. } => {
+                            state.queued.pop()
+                        }
+                        ClientCommand::Disconnect => {
+                            if let Err(err) = session.connection.close().await {
+                                log::debug!("Error closing connection (TCP): {}", err);
+                            }
+                            receiver::dispatch(
+                                &mut inbound_sender,
+                                ClientEvent::Disconnected {
+                                    reason: DisconnectReason::ClosedLocally,
+                                },
+                                config.event_overflow,
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                        command => pending.push_back(command),
+                    }
+                }
+            }
 
-        let stream = TcpStream::connect(&address).await?;
+            state.set_connection_state(ConnectionState::Connected);
+            receiver::dispatch(
+                &mut inbound_sender,
+                ClientEvent::Reconnected,
+                config.event_overflow,
+            )
+            .await?;
+        }
+    }
+
+    /// Binds the UDP socket, connects the TCP stream and performs the handshake.
+    async fn establish<A: ToSocketAddrs>(
+        address: &A,
+        config: Config,
+        #[cfg(feature = "rustls")] domain: &DNSName,
+        #[cfg(feature = "rustls")] client_config: &Arc<ClientConfig>,
+        token: Vec<u8>,
+        state: &ClientState,
+    ) -> Result<Session, ClientError> {
+        let stream = match config.local_address {
+            Some(local_address) => Self::connect_from(local_address, address).await,
+            None => TcpStream::connect(address).await,
+        }
+        .map_err(ClientError::from_connect)?;
         stream.set_nodelay(true).unwrap();
+        let peer_address = stream.peer_addr()?;
+
+        // The UDP socket follows the TCP stream to the same server address, which also decides between IPv4 and IPv6.
+        let local_address = config.local_address.unwrap_or_else(|| match peer_address {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        });
+        let socket = UdpSocket::bind(local_address)
+            .await
+            .map_err(ClientError::from_bind)?;
+        config.apply_udp_options(&socket)?;
+        socket
+            .connect(peer_address)
+            .await
+            .map_err(ClientError::Unreachable)?;
+        let local_address = socket.local_addr()?;
+        let tcp_local_address = stream.local_addr()?;
 
         #[cfg(not(feature = "rustls"))]
         let (mut read_stream, write_stream) = split(stream);
 
         #[cfg(feature = "rustls")]
-        let (mut read_stream, write_stream) = {
-            let connector = TlsConnector::from(Arc::new(client_config));
-            let stream = connector.connect(domain.as_ref(), stream).await?;
-            split(stream)
+        let (tls, key, (mut read_stream, write_stream)) = {
+            let stream = TlsConnector::from(client_config.clone())
+                .connect(domain.as_ref(), stream)
+                .instrument(span::handshake(peer_address))
+                .await
+                .map_err(|err| {
+                    if tls::is_no_application_protocol(&err) {
+                        ClientError::NoApplicationProtocol
+                    } else {
+                        ClientError::Tls(err)
+                    }
+                })?;
+
+            let tls = TlsInfo::new(stream.get_ref().1);
+            let key = tls::export_key(stream.get_ref().1).map_err(ClientError::Tls)?;
+            // A server without ALPN ignores the offered protocols rather than failing the handshake.
+            if tls.alpn_protocol.is_none() && !client_config.alpn_protocols.is_empty() {
+                return Err(ClientError::NoApplicationProtocol);
+            }
+
+            (tls, key, split(stream))
         };
 
-        let (id, connection) =
-            Connection::connect(&socket, &mut read_stream, write_stream, token).await?;
-        inbound_sender.try_send(ClientEvent::Connected)?;
+        let (id, connection) = Connection::connect(
+            &socket,
+            &mut read_stream,
+            write_stream,
+            peer_address,
+            token,
+            #[cfg(feature = "rustls")]
+            key,
+            config,
+        )
+        .instrument(span::handshake(peer_address))
+        .await
+        .map_err(|err| match err {
+            ConnectionError::Rejected => ClientError::Rejected,
+            err => err.into(),
+        })?;
+
+        *state.local_addresses.lock().unwrap() = Some((local_address, tcp_local_address));
+        #[cfg(feature = "rustls")]
+        {
+            *state.tls.lock().unwrap() = Some(tls);
+        }
 
-        let mut recv_buffer = [0u8; std::u16::MAX as usize];
+        Ok(Session {
+            socket,
+            read_stream,
+            id,
+            connection,
+        })
+    }
+
+    /// Connects a TCP stream bound to the local address, trying the resolved addresses of the same family in turn.
+    async fn connect_from<A: ToSocketAddrs>(
+        local_address: SocketAddr,
+        address: &A,
+    ) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for address in lookup_host(address).await? {
+            if address.is_ipv4() != local_address.is_ipv4() {
+                continue;
+            }
+
+            let socket = if local_address.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            // The port may still be in TIME_WAIT from a previous connection, see [`Config::reconnect`].
+            socket.set_reuseaddr(true)?;
+            socket.bind(local_address)?;
+
+            match socket.connect(address).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "No address of the same family as the local address.",
+            )
+        }))
+    }
+
+    /// Runs an established session until the connection is lost, which is reported as [`ClientError::Io`], or closed with [`ClientSender::disconnect`].
+    async fn run(
+        session: Session,
+        config: Config,
+        hooks: &Hooks,
+        inbound_sender: &mut receiver::InnerSender<ClientEvent>,
+        outbound_receiver: &mut sender::PriorityReceiver<ClientCommand>,
+        mut pending: VecDeque<ClientCommand>,
+        state: &ClientState,
+    ) -> Result<(), ClientError> {
+        let Session {
+            socket,
+            read_stream,
+            id,
+            connection,
+        } = session;
+        let (mut frames, _reader) = spawn_reader(
+            read_stream,
+            config.max_reliable_size,
+            connection.span.clone(),
+        );
+
+        let time_sync = config
+            .time_sync_interval
+            .filter(|interval| !interval.is_zero())
+            .filter(|_| connection.features().contains(Features::TIME_SYNC));
+        let mut time_sync_interval = interval(time_sync.unwrap_or(Duration::from_secs(1)));
+
+        let coalesce = config
+            .reliable_coalesce_window
+            .filter(|window| !window.is_zero());
+        let mut coalesce_interval = interval(coalesce.unwrap_or(Duration::from_secs(1)));
+
+        let keep_alive = config
+            .keep_alive_interval
+            .filter(|interval| !interval.is_zero());
+        let mut keep_alive_interval = interval(keep_alive.unwrap_or(Duration::from_secs(1)));
+        let mut last_datagram = Instant::now();
+        let mut simulator = config.network_simulation.map(Simulator::new);
+
+        // Pings waiting for a time response, by the client time of their request.
+        let mut pings: Vec<(u64, oneshot::Sender<Result<Duration, SendError>>)> = vec![];
+        // Whether the server waits for a receipt of the next message, see [`connection::RECEIPT`].
+        let mut receipt_requested = false;
+
+        let mut recv_buffer = [0u8; u16::MAX as usize];
         loop {
+            let simulator_due = simulator.as_ref().and_then(Simulator::next_due);
+
             tokio::select! {
-                result = Connection::read(&mut read_stream, config.max_reliable_size) => {
-                    match result {
-                        Ok(data) => {
-                            inbound_sender.try_send(ClientEvent::Received(data))?;
+                result = frames.next() => {
+                    match result.unwrap_or_else(|| Err(ErrorKind::UnexpectedEof.into())) {
+                        Ok(data) if data == connection::INVALID_TOKEN => {
+                            receiver::dispatch(inbound_sender, ClientEvent::Disconnected { reason: DisconnectReason::InvalidToken }, config.event_overflow).await?;
+                            return Err(ClientError::InvalidToken);
+                        },
+                        Ok(data) if data == connection::RECEIVED => {
+                            if !connection.receipt() {
+                                log::debug!("Error decoding frame (TCP): unexpected receipt.");
+                            }
+                        },
+                        Ok(data) if data == connection::RECEIPT => receipt_requested = true,
+                        Ok(mut data) => {
+                            let received_at = Instant::now();
+                            match Header::decode(&mut data) {
+                                Some(header) => {
+                                    hooks.receive(&mut data, Delivery::Reliable);
+                                    let receive = span::receive(&connection.span, data.len(), Delivery::Reliable);
+                                    receiver::dispatch(inbound_sender, ClientEvent::Received { header, data, received_at }, config.event_overflow).instrument(receive).await?
+                                },
+                                None => log::debug!("Error decoding frame (TCP): missing header.")
+                            }
+
+                            // Receipts are matched to messages by their order, so a malformed message is acknowledged as well.
+                            if std::mem::take(&mut receipt_requested) {
+                                if let Err(err) = connection.write(connection::RECEIVED).await {
+                                    log::debug!("Error writing receipt (TCP): {}", err);
+                                }
+                            }
                         },
                         Err(err) => {
                             log::debug!("Error reading frame (TCP): {:#?}", err);
-                            inbound_sender.try_send(ClientEvent::Disconnected)?;
                             return Err(err.into());
                         }
                     }
                 },
                 result = socket.recv(&mut recv_buffer) => {
+                    // Errors are transient for UDP, for example Windows reports an ICMP port unreachable as a reset on the next receive.
+                    let result = result.map_err(|err| log::debug!("Error receiving datagram (UDP): {}", err));
                     if let Ok(bytes_read) = result {
+                        let received_at = Instant::now();
+
                         // Must receive more than tag (u64) bytes
                         if bytes_read > 8 {
                             let tag = &recv_buffer[0..8];
                             let data = &recv_buffer[8..bytes_read];
 
-                            if connection.verify(data, tag) {
-                                inbound_sender.try_send(ClientEvent::Received(data.to_vec()))?;
+                            if connection.verify(data, tag) && connection.accept_counter(data) {
+                                state.set_packet_loss(connection.packet_loss());
+
+                                let data = &data[connection::COUNTER_SIZE..];
+                                let (sequence, payload) = datagram::decode_sequenced(data);
+                                let delivery = if sequence.is_some() { Delivery::UnreliableSequenced } else { Delivery::Unreliable };
+                                // Sequenced messages older than one already delivered are dropped.
+                                let is_fresh = || sequence.is_none_or(|sequence| connection.accept_sequence(sequence));
+
+                                match payload {
+                                    Ok(Payload::Fragment(_)) if !connection.features().contains(Features::FRAGMENTATION) => {
+                                        log::debug!("Error decoding datagram (UDP): fragmentation was not negotiated.");
+                                    },
+                                    Ok(_) if sequence.is_some() && !connection.features().contains(Features::SEQUENCING) => {
+                                        log::debug!("Error decoding datagram (UDP): sequencing was not negotiated.");
+                                    },
+                                    Ok(Payload::Messages(messages)) => {
+                                        let messages = if is_fresh() { messages } else { Vec::new() };
+                                        for message in messages {
+                                            let mut data = message.to_vec();
+                                            hooks.receive(&mut data, delivery);
+                                            let receive = span::receive(&connection.span, data.len(), delivery);
+                                            receiver::dispatch(inbound_sender, ClientEvent::Received { header: Header::default(), data, received_at }, config.event_overflow).instrument(receive).await?;
+                                        }
+                                    },
+                                    Ok(Payload::Fragment(fragment)) => {
+                                        let message = connection.reassembler.lock().unwrap().insert(fragment).filter(|_| is_fresh());
+                                        if let Some(mut data) = message {
+                                            hooks.receive(&mut data, delivery);
+                                            let receive = span::receive(&connection.span, data.len(), delivery);
+                                            receiver::dispatch(inbound_sender, ClientEvent::Received { header: Header::default(), data, received_at }, config.event_overflow).instrument(receive).await?;
+                                        }
+                                    },
+                                    Ok(Payload::TimeResponse { client_time, server_time }) => {
+                                        state.clock.update(client_time, server_time, config.time_sync_warm_up);
+
+                                        let rtt = Duration::from_micros(state.clock.client_time().saturating_sub(client_time));
+                                        while let Some(index) = pings.iter().position(|(sent_at, _)| *sent_at == client_time) {
+                                            let _ = pings.swap_remove(index).1.send(Ok(rtt));
+                                        }
+                                    },
+                                    Ok(Payload::TimeRequest { .. }) => log::debug!("Error decoding datagram (UDP): unexpected time request."),
+                                    Err(err) => log::debug!("Error decoding datagram (UDP): {}", err)
+                                }
                             }
                         }
                     }
                 },
-                result = outbound_receiver.next() => {
-                    if let Some((mut data, delivery)) = result {
-                        match delivery {
-                            Delivery::Reliable => match connection.write(&data).await {
-                                Ok(()) => {},
-                                Err(err) => log::debug!("Error writing message (TCP): {}", err)
-                            },
-                            Delivery::Unreliable => {
-                                let mut bytes = connection.sign(&data).to_vec(); // Add tag.
-                                bytes.extend(&id.to_be_bytes()); // Add id.
-                                bytes.append(&mut data); // Add data.
-
-                                match socket.send(&bytes).await {
-                                    Ok(_) => {},
-                                    Err(err) => log::debug!("Error writing message (UDP): {}", err)
+                _ = time_sync_interval.tick(), if time_sync.is_some() => {
+                    let payload = connection.sequence(datagram::encode_time_request(state.clock.client_time()));
+                    let mut bytes = connection.sign(&payload).to_vec(); // Add tag.
+                    bytes.extend(&id.to_be_bytes()); // Add id.
+                    bytes.extend(payload); // Add payload.
+
+                    match netsim::send(&socket, &mut simulator, bytes, None).await {
+                        Ok(_) => last_datagram = Instant::now(),
+                        Err(err) => log::debug!("Error writing time request (UDP): {}", err)
+                    }
+                },
+                _ = keep_alive_interval.tick(), if keep_alive.is_some() => {
+                    if keep_alive.is_some_and(|keep_alive| last_datagram.elapsed() >= keep_alive) {
+                        let payload = connection.sequence(datagram::encode_keep_alive());
+                        let mut bytes = connection.sign(&payload).to_vec(); // Add tag.
+                        bytes.extend(&id.to_be_bytes()); // Add id.
+                        bytes.extend(payload); // Add payload.
+
+                        match netsim::send(&socket, &mut simulator, bytes, None).await {
+                            Ok(_) => last_datagram = Instant::now(),
+                            Err(err) => log::debug!("Error writing keep-alive (UDP): {}", err)
+                        }
+                    }
+                },
+                _ = coalesce_interval.tick(), if coalesce.is_some() => {
+                    match connection.flush_coalesced().await {
+                        Ok(()) => {},
+                        Err(err) => log::debug!("Error writing message (TCP): {}", err)
+                    }
+                },
+                _ = sleep_until(simulator_due.unwrap_or_else(tokio::time::Instant::now)), if simulator_due.is_some() => {
+                    if let Some(simulator) = simulator.as_mut() {
+                        simulator.flush(&socket).await;
+                    }
+                },
+                Some(command) = next_command(&mut pending, outbound_receiver) => {
+                    match command {
+                        ClientCommand::Send { header, data, delivery } => {
+                            state.queued.pop();
+                            match delivery {
+                                Delivery::Reliable => match connection.write(&header.encode(&data)).instrument(span::send(&connection.span, data.len(), delivery)).await {
+                                    Ok(()) => {},
+                                    Err(err) => log::debug!("Error writing message (TCP): {}", err)
+                                },
+                                Delivery::Unreliable | Delivery::UnreliableSequenced => {
+                                    let sequence = connection.next_sequence(delivery);
+                                    match datagram::encode(&data, || connection.next_fragment_id(), connection.max_fragments(&config)) {
+                                        Some(payloads) => {
+                                            for payload in payloads {
+                                                let payload = match sequence {
+                                                    Some(sequence) => datagram::encode_sequenced(sequence, payload),
+                                                    None => payload,
+                                                };
+                                                let mut payload = connection.sequence(payload);
+                                                let mut bytes = connection.sign(&payload).to_vec(); // Add tag.
+                                                bytes.extend(&id.to_be_bytes()); // Add id.
+                                                bytes.append(&mut payload); // Add payload.
+
+                                                let send = span::send(&connection.span, data.len(), delivery);
+                                                match netsim::send(&socket, &mut simulator, bytes, None).instrument(send).await {
+                                                    Ok(_) => last_datagram = Instant::now(),
+                                                    Err(err) => log::debug!("Error writing message (UDP): {}", err)
+                                                }
+                                            }
+                                        },
+                                        None => log::debug!("Error writing message (UDP): {} bytes exceeds the maximum number of fragments.", data.len())
+                                    }
                                 }
                             }
+                        },
+                        ClientCommand::SendWithReceipt { header, data, reply } => {
+                            state.queued.pop();
+                            if !connection.features().contains(Features::RECEIPTS) {
+                                let _ = reply.send(Err(SendError::Unsupported));
+                                continue;
+                            }
+
+                            connection.expect_receipt(reply);
+                            let written = match connection.write(connection::RECEIPT).await {
+                                Ok(()) => connection.write(&header.encode(&data)).instrument(span::send(&connection.span, data.len(), Delivery::Reliable)).await,
+                                Err(err) => Err(err),
+                            };
+                            if let Err(err) = written {
+                                log::debug!("Error writing message (TCP): {}", err);
+                            }
+                        },
+                        ClientCommand::Ping { reply } => {
+                            if !connection.features().contains(Features::TIME_SYNC) {
+                                let _ = reply.send(Err(SendError::Unsupported));
+                                continue;
+                            }
+
+                            let client_time = state.clock.client_time();
+                            let payload = connection.sequence(datagram::encode_time_request(client_time));
+                            let mut bytes = connection.sign(&payload).to_vec(); // Add tag.
+                            bytes.extend(&id.to_be_bytes()); // Add id.
+                            bytes.extend(payload); // Add payload.
+
+                            match netsim::send(&socket, &mut simulator, bytes, None).await {
+                                Ok(_) => last_datagram = Instant::now(),
+                                Err(err) => log::debug!("Error writing time request (UDP): {}", err)
+                            }
+
+                            // Pings whose future was dropped, for instance by a timeout, will never be answered.
+                            pings.retain(|(_, reply)| !reply.is_canceled());
+                            pings.push((client_time, reply));
+                        },
+                        ClientCommand::Flush { reply } => {
+                            let flushed = connection.flush().await.map_err(|err| {
+                                log::debug!("Error flushing stream (TCP): {}", err);
+                                SendError::WriteFailed(err.kind())
+                            });
+                            let _ = reply.send(flushed);
+                        },
+                        ClientCommand::Disconnect => {
+                            match connection.close().await {
+                                Ok(()) => {},
+                                Err(err) => log::debug!("Error closing connection (TCP): {}", err)
+                            }
+
+                            receiver::dispatch(inbound_sender, ClientEvent::Disconnected { reason: DisconnectReason::ClosedLocally }, config.event_overflow).await?;
+                            return Ok(());
                         }
                     }
                 }
@@ -0,0 +1,146 @@
+//! Estimation of the server clock on the client.
+//!
+//! The client periodically sends its own (monotonic) time in an unreliable time request, and the server echoes it back along with its wall-clock time.
+//! Assuming the request and response take equally long, the server time was taken half a round trip before the response arrived.
+//! Each sample is therefore off by at most half the round trip time, and by less when the path is symmetric.
+//! Jitter makes individual round trips longer than the path itself: samples with a round trip time much larger than the recent minimum are rejected,
+//! and the remaining samples are smoothed with an exponential moving average. The first few are averaged with equal weight instead (a warm-up),
+//! so the estimates do not depend heavily on the first sample.
+//! The jitter itself is estimated from all samples as the smoothed deviation from the round trip time, like RTTVAR in RFC 6298.
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Weight of a new sample in the moving averages.
+const SMOOTHING: f64 = 0.125;
+/// Weight of a new sample in the jitter estimate, the value of beta in RFC 6298.
+const JITTER_SMOOTHING: f64 = 0.25;
+/// Number of recent round trip times used to find the minimum.
+const RTT_WINDOW: usize = 16;
+/// Samples with a round trip time above `RTT_TOLERANCE` times the recent minimum (plus 1ms) are rejected.
+const RTT_TOLERANCE: u32 = 2;
+
+#[derive(Debug)]
+struct Estimate {
+    /// Server time (microseconds since the Unix epoch) minus client time (microseconds since [`Clock::started`]).
+    offset: f64,
+    rtt: f64,
+}
+
+#[derive(Debug)]
+struct State {
+    estimate: Option<Estimate>,
+    /// Number of samples in the estimate.
+    samples: u32,
+    /// Smoothed mean deviation of the round trip time in microseconds.
+    jitter: Option<f64>,
+    rtts: VecDeque<Duration>,
+}
+
+/// Client-side estimate of the server clock.
+#[derive(Debug)]
+pub struct Clock {
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            state: Mutex::new(State {
+                estimate: None,
+                samples: 0,
+                jitter: None,
+                rtts: VecDeque::with_capacity(RTT_WINDOW),
+            }),
+        }
+    }
+}
+
+impl Clock {
+    /// Microseconds elapsed on the client clock, sent in time requests.
+    pub fn client_time(&self) -> u64 {
+        self.started.elapsed().as_micros() as u64
+    }
+
+    /// Microseconds since the Unix epoch on the server clock, sent in time responses.
+    pub fn server_time() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64
+    }
+
+    /// Adds a sample from a time response. The first `warm_up` accepted samples are averaged with equal weight.
+    pub fn update(&self, client_time: u64, server_time: u64, warm_up: u32) {
+        let now = self.client_time();
+        if client_time > now {
+            return;
+        }
+        let rtt = Duration::from_micros(now - client_time);
+
+        let mut state = self.state.lock().unwrap();
+        if state.rtts.len() == RTT_WINDOW {
+            state.rtts.pop_front();
+        }
+        state.rtts.push_back(rtt);
+
+        let sample = rtt.as_micros() as f64;
+        let srtt = state
+            .estimate
+            .as_ref()
+            .map_or(sample, |estimate| estimate.rtt);
+        state.jitter = Some(match state.jitter {
+            Some(jitter) => jitter + JITTER_SMOOTHING * ((srtt - sample).abs() - jitter),
+            None => sample / 2.0,
+        });
+
+        let min_rtt = state.rtts.iter().min().copied().unwrap_or(rtt);
+        if rtt > min_rtt * RTT_TOLERANCE + Duration::from_millis(1) {
+            return;
+        }
+
+        let rtt = rtt.as_micros() as f64;
+        let offset = server_time as f64 + rtt / 2.0 - now as f64;
+        // The cumulative average during the warm-up.
+        let weight = if state.samples < warm_up {
+            1.0 / (state.samples + 1) as f64
+        } else {
+            SMOOTHING
+        };
+        state.samples = state.samples.saturating_add(1);
+        state.estimate = Some(match state.estimate.take() {
+            Some(estimate) => Estimate {
+                offset: estimate.offset + weight * (offset - estimate.offset),
+                rtt: estimate.rtt + weight * (rtt - estimate.rtt),
+            },
+            None => Estimate { offset, rtt },
+        });
+    }
+
+    /// Estimated current wall-clock time of the server, or [`None`] before the first sample.
+    pub fn estimated_server_time(&self) -> Option<SystemTime> {
+        let offset = self.state.lock().unwrap().estimate.as_ref()?.offset;
+        let micros = (self.client_time() as f64 + offset).max(0.0) as u64;
+
+        Some(UNIX_EPOCH + Duration::from_micros(micros))
+    }
+
+    /// Smoothed round trip time of accepted samples, or [`None`] before the first sample.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        let rtt = self.state.lock().unwrap().estimate.as_ref()?.rtt;
+
+        Some(Duration::from_micros(rtt as u64))
+    }
+
+    /// Smoothed mean deviation of the round trip time, or [`None`] before the first sample.
+    pub fn jitter(&self) -> Option<Duration> {
+        let jitter = self.state.lock().unwrap().jitter?;
+
+        Some(Duration::from_micros(jitter as u64))
+    }
+}
@@ -0,0 +1,50 @@
+use std::convert::TryInto;
+use tokio::io;
+
+use crate::connection::FrameError;
+
+/// Frames smaller than this are never compressed, since they rarely shrink.
+const MIN_SIZE: usize = 64;
+
+/// Algorithm used to compress reliable frames, see [`Config::compression`](crate::Config::compression).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4 block compression, fast enough to compress every frame.
+    Lz4,
+}
+
+/// Compresses the data as `uncompressed size (u32) | block`, or returns [`None`] if it would not get smaller.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < MIN_SIZE {
+        return None;
+    }
+
+    let block = lz4_flex::block::compress(data);
+    if 4 + block.len() >= data.len() {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(4 + block.len());
+    bytes.extend(&(data.len() as u32).to_be_bytes());
+    bytes.extend(block);
+    Some(bytes)
+}
+
+/// Decompresses data produced by [`compress`], refusing to decompress to more than `max_size` bytes.
+pub fn decompress(bytes: &[u8], max_size: u32) -> io::Result<Vec<u8>> {
+    if bytes.len() < 4 {
+        return Err(FrameError::MalformedCompression.into());
+    }
+
+    let size = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+    if size > max_size {
+        return Err(FrameError::TooLarge {
+            size,
+            max: max_size,
+        }
+        .into());
+    }
+
+    lz4_flex::block::decompress(&bytes[4..], size as usize)
+        .map_err(|_| FrameError::MalformedCompression.into())
+}
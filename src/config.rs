@@ -1,24 +1,613 @@
+use socket2::SockRef;
+use std::{io, net::SocketAddr, time::Duration};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+#[cfg(feature = "lz4")]
+use crate::Compression;
+use crate::{connection::MAX_FRAME_SIZE, replay::MAX_REPLAY_WINDOW, Features, NetSim, Overflow};
+
+/// Identifies connections that belong to the same client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKey {
+    /// Connections presenting the same token. Clients connecting without a token are never duplicates.
+    Token,
+    /// Connections from the same IP address.
+    Ip,
+}
+
+/// How the client reconnects after losing its connection to the server, see [`Config::reconnect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Number of attempts before the client gives up.
+    pub max_attempts: u32,
+    /// Delay before the first attempt, doubled after every failed attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between attempts.
+    pub max_backoff: Duration,
+    /// Send the messages queued while reconnecting once reconnected, instead of dropping them.
+    /// Pings and flushes are kept either way, and a disconnect requested while reconnecting closes the new connection right away.
+    pub retain_messages: bool,
+}
+
+impl ReconnectPolicy {
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            retain_messages: true,
+        }
+    }
+}
+
+/// Rate at which the server accepts new connections from a single IP address, see [`Config::connection_rate`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionRate {
+    /// Sustained number of connections per second.
+    pub per_sec: f64,
+    /// Number of connections accepted in quick succession before the rate applies.
+    pub burst: u32,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("Max reliable size must be non-zero.")]
+    ZeroMaxReliableSize,
+    #[error("Max reliable size must be at most {0}.")]
+    MaxReliableSizeTooLarge(u32),
+    #[error("Event capacity must be non-zero.")]
+    ZeroEventCapacity,
+    #[error("Max event age must be non-zero.")]
+    ZeroMaxEventAge,
+    #[error("Max fragments must be non-zero.")]
+    ZeroMaxFragments,
+    #[error("Fragment timeout must be non-zero.")]
+    ZeroFragmentTimeout,
+    #[error("Time sync interval must be non-zero.")]
+    ZeroTimeSyncInterval,
+    #[error("Stats interval must be non-zero.")]
+    ZeroStatsInterval,
+    #[error("Reliable coalesce window must be non-zero.")]
+    ZeroReliableCoalesceWindow,
+    #[error("Replay window must be at most {0}.")]
+    ReplayWindowTooLarge(u32),
+    #[error("Keep-alive interval must be non-zero.")]
+    ZeroKeepAliveInterval,
+    #[error("Connection rate and burst must be positive.")]
+    InvalidConnectionRate,
+    #[error("Send queue capacity must be non-zero.")]
+    ZeroSendQueueCapacity,
+    #[error("Idle timeout and idle check interval must be non-zero.")]
+    ZeroIdleTimeout,
+    #[error("Connect timeout must be non-zero.")]
+    ZeroConnectTimeout,
+    #[error("Network simulation loss must be between 0 and 1.")]
+    InvalidNetworkSimulation,
+    #[error("Handshake timeout must be non-zero.")]
+    ZeroHandshakeTimeout,
+    #[error("UDP buffer sizes must be non-zero.")]
+    ZeroUdpBufferSize,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Config {
     /// Maximum accepted size of an incoming reliable message. The default is 1MB, meaning that the
+    /// connection is dropped if a larger frame is received. Larger messages are refused on the sending side with [`SendError::TooLarge`](crate::SendError::TooLarge).
     pub max_reliable_size: u32,
     /// Number of incoming events the socket can hold before it blocks incoming events.
     /// If the capacity is reached the underlying receive buffers may also reach its capacity resulting in packets being dropped.
     pub event_capacity: usize,
+    /// Drop [`ServerEvent::Received`](crate::ServerEvent::Received) and [`ClientEvent::Received`](crate::ClientEvent::Received) events older than this when they are taken from the event queue,
+    /// so an application recovering from a stall gets fresh messages instead of working through a backlog of stale ones. Other events are never dropped for age.
+    /// The age is measured from `received_at`, which every message carries already, so the only cost is reading the clock for each received event.
+    /// The default is [`None`], which keeps events regardless of age.
+    pub max_event_age: Option<Duration>,
+    /// What happens to reliable messages sent to a connection faster than its send rate allows, see [`ServerSender::set_send_rate`](crate::ServerSender::set_send_rate).
+    /// [`Overflow::Block`] queues them until the rate allows writing them in order, [`Overflow::DropNewest`] drops them like unreliable messages over the rate.
+    /// Messages sent with a receipt are always queued, so their receipt is not lost. The default is [`Overflow::Block`].
+    pub send_rate_overflow: Overflow,
+    /// Detect connections that belong to an already connected client and report them with [`ServerEvent::DuplicateConnection`](crate::ServerEvent::DuplicateConnection).
+    /// The default is [`None`], which disables detection.
+    pub duplicate_key: Option<DuplicateKey>,
+    /// Number of recent protocol errors kept per connection on the server, see [`ServerSender::recent_errors`](crate::ServerSender::recent_errors).
+    pub recent_errors_capacity: usize,
+    /// Maximum number of fragments an unreliable message can be split into, and the maximum number of fragments buffered for reassembly per connection.
+    /// Unreliable messages larger than 1KB are fragmented, so the default of 64 allows messages up to 64KB.
+    pub max_fragments: u8,
+    /// Time after which a partially received unreliable message is discarded.
+    pub fragment_timeout: Duration,
+    /// How often the client samples the server clock, see [`ClientSender::estimated_server_time`](crate::ClientSender::estimated_server_time).
+    /// The default is every second, [`None`] disables time synchronization.
+    pub time_sync_interval: Option<Duration>,
+    /// Number of accepted time samples that are averaged with equal weight before the estimates switch to the exponential moving average.
+    /// This keeps a single slow sample right after connecting, for instance one delayed by the handshake, from biasing the estimates for long.
+    /// The default of 8 hands over smoothly to the weight of new samples in the moving average, 0 uses the moving average from the first sample.
+    pub time_sync_warm_up: u32,
+    /// Optional features advertised during the handshake. A connection uses the features supported by both sides.
+    /// The default is [`Features::all`].
+    pub features: Features,
+    /// Maximum number of connections, including connections that are still performing the handshake.
+    /// Clients connecting past the limit are rejected with [`ClientError::Rejected`](crate::ClientError::Rejected). The default is [`None`], which means no limit.
+    pub max_connections: Option<usize>,
+    /// Time after a connection is closed during which a client presenting the same token resumes its session, see [`Hooks::on_resume`](crate::Hooks::on_resume).
+    pub resume_window: Duration,
+    /// Maximum size of the token a client presents during the handshake. Connections presenting a larger token are dropped before validation.
+    pub max_token_size: u32,
+    /// How often the server dispatches [`ServerEvent::Stats`](crate::ServerEvent::Stats) for every established connection.
+    /// The default is [`None`], which disables the events.
+    pub stats_interval: Option<Duration>,
+    /// Number of unreliable datagrams that can arrive out of order before they are rejected as possible replays.
+    /// Every unreliable datagram carries a signed counter, and a datagram is only accepted if its counter was not accepted before
+    /// and is within this many counters of the highest accepted one. The default (and maximum) is 64.
+    pub replay_window: u32,
+    /// Time reliable messages are held back so that messages sent in quick succession are written to the stream together,
+    /// reducing the number of TCP segments (and TLS records) without relying on Nagle's algorithm, which is disabled.
+    /// A message is delayed by at most this long, and [`ClientSender::flush`](crate::ClientSender::flush) or [`ServerSender::flush`](crate::ServerSender::flush) writes the held back messages early.
+    /// The default is [`None`], which writes every message right away.
+    pub reliable_coalesce_window: Option<Duration>,
+    /// Time without sending an unreliable datagram after which the client sends an empty one,
+    /// keeping the UDP mapping of NATs and firewalls between the client and the server open. Keep-alives are not dispatched as events.
+    /// The default is every 10 seconds, [`None`] disables keep-alives. Time synchronization also keeps the mapping open, see [`Config::time_sync_interval`].
+    pub keep_alive_interval: Option<Duration>,
+    /// Reconnect with the same token when the connection to the server is lost, dispatching [`ClientEvent::Reconnected`](crate::ClientEvent::Reconnected) instead of
+    /// [`ClientEvent::Disconnected`](crate::ClientEvent::Disconnected) once reconnected. The server sees a new connection, see [`Hooks::on_resume`](crate::Hooks::on_resume).
+    /// The default is [`None`], which ends the client when the connection is lost.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Local address the client binds both its UDP socket and its TCP stream to, choosing the interface and port traffic originates from.
+    /// The default is [`None`], which binds the UDP socket to an ephemeral port on all interfaces of the same family (IPv4 or IPv6) as the server address,
+    /// and lets the operating system choose for the TCP stream.
+    pub local_address: Option<SocketAddr>,
+    /// Bind the server to IPv6 addresses with `IPV6_V6ONLY` disabled, so listening on `[::]` accepts both IPv6 and IPv4 clients.
+    /// IPv4 clients then appear with IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), which the block list treats as the IPv4 address, see [`Disconnector::block`](crate::Disconnector::block).
+    /// The default is `false`, which leaves the operating system default: dual-stack on Linux, IPv6-only on Windows and most BSDs.
+    pub dual_stack: bool,
+    /// What happens to events when the event queue (see [`Config::event_capacity`]) is full because the application falls behind.
+    /// The default is [`Overflow::Block`]. Periodic [`ServerEvent::Stats`](crate::ServerEvent::Stats) are always dropped instead.
+    pub event_overflow: Overflow,
+    /// Compress reliable frames that shrink when compressed, which pays off for messages like state snapshots.
+    /// Compression is advertised during the handshake as [`Features::COMPRESSION`], so frames are only compressed when both sides set it.
+    /// The default is [`None`], which neither compresses frames nor accepts compressed frames.
+    #[cfg(feature = "lz4")]
+    pub compression: Option<Compression>,
+    /// Limit how fast the server accepts new connections from a single IP address, to resist handshake floods.
+    /// Connections exceeding the rate are closed right after they are accepted, before the TLS handshake. The default is [`None`], which means no limit.
+    pub connection_rate: Option<ConnectionRate>,
+    /// Maximum size of an unreliable message, larger messages are refused with [`SendError::TooLarge`](crate::SendError::TooLarge),
+    /// and fragments of larger received messages are discarded without being buffered.
+    /// Messages up to 1KB are sent in a single datagram, which stays below the MTU of common paths, so setting it to 1024 keeps messages from being fragmented.
+    /// The default is [`None`], which allows messages up to [`Config::max_fragments`] KB (1KB without [`Features::FRAGMENTATION`]).
+    pub max_unreliable_size: Option<usize>,
+    /// Maximum number of messages sent to a connection that can wait for the client or server task to write them.
+    /// Sending past the capacity fails with [`SendError::Full`](crate::SendError::Full), so the application can shed load instead of growing memory without limit.
+    /// On the server the capacity applies to every connection separately, so a slow connection does not hold back the others.
+    /// The default is [`None`], which means no limit. See [`ClientSender::queued`](crate::ClientSender::queued) and [`ConnectionStats::queued`](crate::ConnectionStats::queued) for the current depth.
+    pub send_queue_capacity: Option<usize>,
+    /// Time without receiving anything from a connection after which the server closes it, dispatching [`ServerEvent::Disconnected`](crate::ServerEvent::Disconnected) if it was established.
+    /// Reliable frames and authenticated datagrams, including keep-alives and time requests, count as activity, so the timeout should exceed the [`Config::keep_alive_interval`] of the clients.
+    /// Connections that do not complete the handshake within the timeout are closed as well. The default is [`None`], which leaves detecting lost clients to TCP.
+    pub idle_timeout: Option<Duration>,
+    /// How often the server looks for connections exceeding [`Config::idle_timeout`] or [`Config::handshake_timeout`], so a connection is closed at most this long after the timeout.
+    /// The default is every second.
+    pub idle_check_interval: Duration,
+    /// Dispatch [`ServerEvent::Error`](crate::ServerEvent::Error) for every protocol error recorded by the server, see [`ServerSender::recent_errors`](crate::ServerSender::recent_errors).
+    /// The default is `false`, as a misbehaving client can cause an error for every datagram it sends.
+    pub error_events: bool,
+    /// Time the client may take to connect to the server and complete the handshake, including the TLS handshake, before giving up with [`ClientError::Timeout`](crate::ClientError::Timeout).
+    /// The sockets opened so far are closed. It applies to every reconnect attempt as well, see [`Config::reconnect`].
+    /// The default is [`None`], which waits until TCP gives up, potentially forever on a server that accepted the stream but never responds.
+    pub connect_timeout: Option<Duration>,
+    /// Delay and drop the unreliable datagrams this client or server sends, including time synchronization and keep-alives, to test an application against a poor network without external tools.
+    /// Only outgoing datagrams are affected, so set it on both sides to simulate both directions. Reliable messages are sent as usual.
+    /// The default is [`None`], which sends every datagram right away.
+    pub network_simulation: Option<NetSim>,
+    /// Time a client may take from the server accepting its TCP stream to completing the handshake, including the TLS handshake and the token.
+    /// Clients that stall are dropped without ever becoming a connection, so they cannot hold on to server resources. Independent of [`Config::idle_timeout`].
+    /// The default is 10 seconds, [`None`] lets clients take as long as they like.
+    pub handshake_timeout: Option<Duration>,
+    /// Size of the receive buffer of the UDP sockets (`SO_RCVBUF`), which holds datagrams until the client or server task reads them.
+    /// Raise it on busy servers, where bursts of datagrams overflow the buffer and are dropped by the kernel before they are read.
+    /// The operating system may clamp the size (on Linux to `net.core.rmem_max`), so the size that took effect is logged at debug level.
+    /// Linux reports twice the size that took effect, as it counts its bookkeeping overhead.
+    /// The default is [`None`], which keeps the default of the operating system.
+    pub udp_recv_buffer_size: Option<usize>,
+    /// Size of the send buffer of the UDP sockets (`SO_SNDBUF`), see [`Config::udp_recv_buffer_size`].
+    /// The default is [`None`], which keeps the default of the operating system.
+    pub udp_send_buffer_size: Option<usize>,
+    /// Type of service byte of the datagrams sent on the UDP sockets (`IP_TOS`, or `IPV6_TCLASS` for IPv6), which carries the DSCP in its upper 6 bits.
+    /// For example `Some(0xB8)` marks unreliable messages for expedited forwarding (DSCP 46), which suits latency-sensitive game traffic.
+    /// Marking is best-effort: many networks ignore or strip it, and some operating systems do not support it for IPv6 sockets, which is logged at debug level.
+    /// The default is [`None`], which leaves the datagrams unmarked.
+    pub udp_tos: Option<u8>,
 }
 
-impl Config {
-    pub fn default() -> Self {
+impl Default for Config {
+    fn default() -> Self {
         Self {
             max_reliable_size: 1000000,
             event_capacity: 65536,
+            max_event_age: None,
+            send_rate_overflow: Overflow::Block,
+            duplicate_key: None,
+            recent_errors_capacity: 16,
+            max_fragments: 64,
+            fragment_timeout: Duration::from_secs(1),
+            time_sync_interval: Some(Duration::from_secs(1)),
+            time_sync_warm_up: 8,
+            features: Features::all(),
+            max_connections: None,
+            resume_window: Duration::from_secs(10),
+            max_token_size: 1024,
+            stats_interval: None,
+            replay_window: MAX_REPLAY_WINDOW,
+            reliable_coalesce_window: None,
+            keep_alive_interval: Some(Duration::from_secs(10)),
+            reconnect: None,
+            local_address: None,
+            dual_stack: false,
+            event_overflow: Overflow::Block,
+            #[cfg(feature = "lz4")]
+            compression: None,
+            connection_rate: None,
+            max_unreliable_size: None,
+            send_queue_capacity: None,
+            idle_timeout: None,
+            idle_check_interval: Duration::from_secs(1),
+            error_events: false,
+            connect_timeout: None,
+            network_simulation: None,
+            handshake_timeout: Some(Duration::from_secs(10)),
+            udp_recv_buffer_size: None,
+            udp_send_buffer_size: None,
+            udp_tos: None,
         }
     }
+}
+
+impl Config {
+    /// Returns a [`ConfigBuilder`] starting from the default configuration.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Checks the invariants of the configuration.
+    /// Called by [`ConfigBuilder::build`], and again by [`Server::listen`](crate::Server::listen) and [`Client::connect`](crate::Client::connect) for a config built by hand,
+    /// whose tasks then fail with [`ServerError::Config`](crate::ServerError::Config) or [`ClientError::Config`](crate::ClientError::Config).
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_reliable_size == 0 {
+            return Err(ConfigError::ZeroMaxReliableSize);
+        }
+        if self.max_reliable_size > MAX_FRAME_SIZE {
+            return Err(ConfigError::MaxReliableSizeTooLarge(MAX_FRAME_SIZE));
+        }
+        if self.event_capacity == 0 {
+            return Err(ConfigError::ZeroEventCapacity);
+        }
+        if self.max_event_age == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroMaxEventAge);
+        }
+        if self.max_fragments == 0 {
+            return Err(ConfigError::ZeroMaxFragments);
+        }
+        if self.fragment_timeout.is_zero() {
+            return Err(ConfigError::ZeroFragmentTimeout);
+        }
+        if self.time_sync_interval == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroTimeSyncInterval);
+        }
+        if self.stats_interval == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroStatsInterval);
+        }
+        if self.reliable_coalesce_window == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroReliableCoalesceWindow);
+        }
+        if self.replay_window > MAX_REPLAY_WINDOW {
+            return Err(ConfigError::ReplayWindowTooLarge(MAX_REPLAY_WINDOW));
+        }
+        if self.keep_alive_interval == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroKeepAliveInterval);
+        }
+        if let Some(rate) = self.connection_rate {
+            if rate.per_sec.is_nan() || rate.per_sec <= 0.0 || rate.burst == 0 {
+                return Err(ConfigError::InvalidConnectionRate);
+            }
+        }
+        if self.send_queue_capacity == Some(0) {
+            return Err(ConfigError::ZeroSendQueueCapacity);
+        }
+        if self.idle_timeout == Some(Duration::ZERO) || self.idle_check_interval.is_zero() {
+            return Err(ConfigError::ZeroIdleTimeout);
+        }
+        if self.connect_timeout == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroConnectTimeout);
+        }
+        if self
+            .network_simulation
+            .is_some_and(|netsim| !netsim.is_valid())
+        {
+            return Err(ConfigError::InvalidNetworkSimulation);
+        }
+        if self.handshake_timeout == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroHandshakeTimeout);
+        }
+        if self.udp_recv_buffer_size == Some(0) || self.udp_send_buffer_size == Some(0) {
+            return Err(ConfigError::ZeroUdpBufferSize);
+        }
+
+        Ok(())
+    }
 
     pub fn new(max_reliable_size: u32, event_capacity: usize) -> Self {
         Self {
             max_reliable_size,
             event_capacity,
+            ..Self::default()
+        }
+    }
+
+    /// Features advertised during the handshake, [`Features::COMPRESSION`] is only advertised if compression is configured.
+    pub(crate) fn advertised_features(&self) -> Features {
+        #[cfg(feature = "lz4")]
+        if self.compression.is_some() {
+            return self.features | Features::COMPRESSION;
+        }
+
+        self.features & !Features::COMPRESSION
+    }
+
+    /// Sets the options of a UDP socket, see [`Config::udp_recv_buffer_size`], [`Config::udp_send_buffer_size`] and [`Config::udp_tos`].
+    pub(crate) fn apply_udp_options(&self, socket: &UdpSocket) -> io::Result<()> {
+        if let Some(tos) = self.udp_tos {
+            match socket.local_addr()? {
+                SocketAddr::V4(_) => socket.set_tos_v4(tos.into())?,
+                SocketAddr::V6(_) => set_tclass_v6(socket, tos)?,
+            }
         }
+
+        let socket = SockRef::from(socket);
+        if let Some(size) = self.udp_recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+            log::debug!(
+                "UDP receive buffer size of {} bytes requested, {} bytes applied.",
+                size,
+                socket.recv_buffer_size()?
+            );
+        }
+        if let Some(size) = self.udp_send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+            log::debug!(
+                "UDP send buffer size of {} bytes requested, {} bytes applied.",
+                size,
+                socket.send_buffer_size()?
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_tclass_v6(socket: &UdpSocket, tclass: u8) -> io::Result<()> {
+    socket.set_tclass_v6(tclass.into())
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_tclass_v6(_: &UdpSocket, _: u8) -> io::Result<()> {
+    log::debug!("Marking IPv6 datagrams is not supported on this platform.");
+    Ok(())
+}
+
+/// Builds a [`Config`], overriding only the fields that are set and validating the result.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn max_reliable_size(mut self, max_reliable_size: u32) -> Self {
+        self.config.max_reliable_size = max_reliable_size;
+        self
+    }
+
+    pub fn event_capacity(mut self, event_capacity: usize) -> Self {
+        self.config.event_capacity = event_capacity;
+        self
+    }
+
+    pub fn max_event_age(mut self, max_event_age: Option<Duration>) -> Self {
+        self.config.max_event_age = max_event_age;
+        self
+    }
+
+    pub fn send_rate_overflow(mut self, send_rate_overflow: Overflow) -> Self {
+        self.config.send_rate_overflow = send_rate_overflow;
+        self
+    }
+
+    pub fn duplicate_key(mut self, duplicate_key: Option<DuplicateKey>) -> Self {
+        self.config.duplicate_key = duplicate_key;
+        self
+    }
+
+    pub fn recent_errors_capacity(mut self, recent_errors_capacity: usize) -> Self {
+        self.config.recent_errors_capacity = recent_errors_capacity;
+        self
+    }
+
+    pub fn max_fragments(mut self, max_fragments: u8) -> Self {
+        self.config.max_fragments = max_fragments;
+        self
+    }
+
+    pub fn fragment_timeout(mut self, fragment_timeout: Duration) -> Self {
+        self.config.fragment_timeout = fragment_timeout;
+        self
+    }
+
+    pub fn time_sync_interval(mut self, time_sync_interval: Option<Duration>) -> Self {
+        self.config.time_sync_interval = time_sync_interval;
+        self
+    }
+
+    pub fn time_sync_warm_up(mut self, time_sync_warm_up: u32) -> Self {
+        self.config.time_sync_warm_up = time_sync_warm_up;
+        self
+    }
+
+    pub fn features(mut self, features: Features) -> Self {
+        self.config.features = features;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.config.max_connections = max_connections;
+        self
+    }
+
+    pub fn resume_window(mut self, resume_window: Duration) -> Self {
+        self.config.resume_window = resume_window;
+        self
+    }
+
+    pub fn max_token_size(mut self, max_token_size: u32) -> Self {
+        self.config.max_token_size = max_token_size;
+        self
+    }
+
+    pub fn stats_interval(mut self, stats_interval: Option<Duration>) -> Self {
+        self.config.stats_interval = stats_interval;
+        self
+    }
+
+    pub fn replay_window(mut self, replay_window: u32) -> Self {
+        self.config.replay_window = replay_window;
+        self
+    }
+
+    pub fn reliable_coalesce_window(mut self, reliable_coalesce_window: Option<Duration>) -> Self {
+        self.config.reliable_coalesce_window = reliable_coalesce_window;
+        self
+    }
+
+    pub fn keep_alive_interval(mut self, keep_alive_interval: Option<Duration>) -> Self {
+        self.config.keep_alive_interval = keep_alive_interval;
+        self
+    }
+
+    pub fn reconnect(mut self, reconnect: Option<ReconnectPolicy>) -> Self {
+        self.config.reconnect = reconnect;
+        self
+    }
+
+    pub fn local_address(mut self, local_address: Option<SocketAddr>) -> Self {
+        self.config.local_address = local_address;
+        self
+    }
+
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.config.dual_stack = dual_stack;
+        self
+    }
+
+    pub fn event_overflow(mut self, event_overflow: Overflow) -> Self {
+        self.config.event_overflow = event_overflow;
+        self
+    }
+
+    #[cfg(feature = "lz4")]
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.config.compression = compression;
+        self
+    }
+
+    pub fn connection_rate(mut self, connection_rate: Option<ConnectionRate>) -> Self {
+        self.config.connection_rate = connection_rate;
+        self
+    }
+
+    pub fn max_unreliable_size(mut self, max_unreliable_size: Option<usize>) -> Self {
+        self.config.max_unreliable_size = max_unreliable_size;
+        self
+    }
+
+    pub fn send_queue_capacity(mut self, send_queue_capacity: Option<usize>) -> Self {
+        self.config.send_queue_capacity = send_queue_capacity;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn idle_check_interval(mut self, idle_check_interval: Duration) -> Self {
+        self.config.idle_check_interval = idle_check_interval;
+        self
+    }
+
+    pub fn error_events(mut self, error_events: bool) -> Self {
+        self.config.error_events = error_events;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Option<Duration>) -> Self {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn network_simulation(mut self, network_simulation: Option<NetSim>) -> Self {
+        self.config.network_simulation = network_simulation;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, handshake_timeout: Option<Duration>) -> Self {
+        self.config.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn udp_recv_buffer_size(mut self, udp_recv_buffer_size: Option<usize>) -> Self {
+        self.config.udp_recv_buffer_size = udp_recv_buffer_size;
+        self
+    }
+
+    pub fn udp_send_buffer_size(mut self, udp_send_buffer_size: Option<usize>) -> Self {
+        self.config.udp_send_buffer_size = udp_send_buffer_size;
+        self
+    }
+
+    pub fn udp_tos(mut self, udp_tos: Option<u8>) -> Self {
+        self.config.udp_tos = udp_tos;
+        self
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        self.config.validate()?;
+        Ok(self.config)
     }
 }
@@ -1,16 +1,34 @@
 use aes::Aes128;
 use cmac::{Cmac, Mac, NewMac};
+use futures::channel::oneshot;
+#[cfg(not(feature = "rustls"))]
 use rand::RngCore;
-use std::{convert::TryInto, net::SocketAddr};
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    net::SocketAddr,
+    sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
 
 use tokio::{
     io,
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
     net::UdpSocket,
     sync::Mutex,
+    task::JoinHandle,
     time::{sleep, Duration},
 };
 
+#[cfg(feature = "lz4")]
+use crate::compression;
+use crate::{
+    datagram::{self, Reassembler},
+    limiter::Pacer,
+    replay::ReplayWindow,
+    span::{self, Span},
+    Config, ConnectionId, Delivery, DisconnectReason, Features, SendError,
+};
+
 use thiserror::Error;
 #[derive(Debug, Error)]
 pub enum ConnectionError {
@@ -20,15 +38,116 @@ pub enum ConnectionError {
     FailedToCreateMac(String),
     #[error("Client received invalid handshake message: {0}")]
     InvalidHandshake(&'static str),
+    #[error("Server rejected the connection.")]
+    Rejected,
+}
+
+/// A reliable frame refused by [`Connection::read`], carried as the source of an [`io::Error`] of kind [`io::ErrorKind::InvalidData`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FrameError {
+    #[error("Frame of {size} bytes exceeds the maximum of {max} bytes.")]
+    TooLarge { size: u32, max: u32 },
+    #[error("Compressed frames are not supported.")]
+    CompressionUnsupported,
+    #[error("Compressed frame is malformed.")]
+    MalformedCompression,
+}
+
+impl From<FrameError> for io::Error {
+    fn from(err: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// The reason a frame was refused, if the error was caused by the peer sending an invalid frame rather than by the stream.
+pub fn frame_error(err: &io::Error) -> Option<&FrameError> {
+    err.get_ref()
+        .and_then(|err| err.downcast_ref::<FrameError>())
 }
 
+/// Why the stream of a connection was lost, given the error reading from it. The end of the stream is the peer closing the connection.
+pub fn disconnect_reason(err: &io::Error) -> DisconnectReason {
+    #[cfg(feature = "rustls")]
+    if err
+        .get_ref()
+        .is_some_and(|err| err.is::<tokio_rustls::rustls::TLSError>())
+    {
+        return DisconnectReason::Tls;
+    }
+
+    if frame_error(err).is_some() {
+        return DisconnectReason::MalformedFrame;
+    }
+
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => DisconnectReason::ClosedByPeer,
+        io::ErrorKind::TimedOut => DisconnectReason::TimedOut,
+        kind => DisconnectReason::Io(kind),
+    }
+}
+
+/// Frame sent by the server instead of initiating the handshake when it refuses a connection.
+pub const REJECTED: &[u8] = b"REJ";
+/// Frame sent by the server in response to the final handshake ACK when the token is invalid.
+pub const INVALID_TOKEN: &[u8] = b"TOK";
+/// Frame written in front of a reliable message whose sender waits for a receipt, see [`Features::RECEIPTS`].
+pub const RECEIPT: &[u8] = b"RCP";
+/// Frame acknowledging a message that followed a [`RECEIPT`] frame, once it has been read.
+/// Regular frames always carry a 4 byte header, so none of these frames can be mistaken for a message.
+pub const RECEIVED: &[u8] = b"RCV";
+
+/// Key authenticating the unreliable datagrams of a connection.
+/// With the `rustls` feature it is exported from the TLS session by both sides, otherwise the server generates it and sends it during the handshake.
+pub type Key = [u8; 16];
+
+/// Offset of the features in the frame initiating the handshake, `id (u32) | key (without rustls) | features (u32)`.
+#[cfg(feature = "rustls")]
+const FEATURES_OFFSET: usize = 4;
+#[cfg(not(feature = "rustls"))]
+const FEATURES_OFFSET: usize = 4 + 16;
+
+/// Size of the counter in front of the payload of unreliable datagrams.
+pub const COUNTER_SIZE: usize = 8;
+
+/// Bit of the frame length marking a compressed frame, see [`Config::compression`].
+const COMPRESSED: u32 = 1 << 31;
+/// Largest frame length that leaves the [`COMPRESSED`] bit clear.
+pub const MAX_FRAME_SIZE: u32 = COMPRESSED - 1;
+
+/// Size at which held back reliable frames are written even though the connection is corked.
+const CORK_CAPACITY: usize = 65536;
+
 #[derive(Debug)]
 pub struct Connection<T: AsyncRead + AsyncWrite> {
-    pub key: [u8; 16],
     pub sign_mac: std::sync::Mutex<Cmac<Aes128>>,
     pub verify_mac: std::sync::Mutex<Cmac<Aes128>>,
     pub write_stream: Mutex<WriteHalf<T>>,
     pub address: Mutex<Option<SocketAddr>>,
+    /// Index of the server socket the datagrams of the connection are exchanged on, see [`Server::listen_multi`](crate::Server::listen_multi).
+    endpoint: AtomicUsize,
+    pub peer_address: SocketAddr,
+    pub token: std::sync::Mutex<Vec<u8>>,
+    pub pacer: std::sync::Mutex<Pacer>,
+    pub cork: std::sync::Mutex<Option<Vec<u8>>>,
+    /// Reliable frames waiting to be written together, [`None`] unless [`Config::reliable_coalesce_window`] is set.
+    coalesced: Option<std::sync::Mutex<Vec<u8>>>,
+    pub reassembler: std::sync::Mutex<Reassembler>,
+    pub fragment_id: AtomicU16,
+    /// Task reading reliable frames from the connection on the server, aborted when the server closes the connection.
+    pub reader: std::sync::Mutex<Option<JoinHandle<()>>>,
+    /// Features supported by both sides, see [`Features`].
+    features: AtomicU32,
+    /// Counter of the next unreliable datagram sent on the connection.
+    send_counter: AtomicU64,
+    replay_window: std::sync::Mutex<ReplayWindow>,
+    /// Sequence of the next sequenced unreliable message sent on the connection, see [`Delivery::UnreliableSequenced`].
+    send_sequence: AtomicU32,
+    /// Highest sequence of the sequenced unreliable messages delivered from the other side, 0 before the first.
+    received_sequence: AtomicU32,
+    /// Replies waiting for a [`RECEIVED`] frame, in the order the messages were written. Dropping the connection fails them.
+    receipts: std::sync::Mutex<VecDeque<oneshot::Sender<Result<(), SendError>>>>,
+    /// Span the messages of the connection are recorded under, see [`span`].
+    pub span: Span,
 }
 
 impl<T> Connection<T>
@@ -39,18 +158,32 @@ where
         socket: &UdpSocket,
         read_stream: &mut ReadHalf<T>,
         mut write_stream: WriteHalf<T>,
+        peer_address: SocketAddr,
         token: Vec<u8>,
+        #[cfg(feature = "rustls")] key: Key,
+        config: Config,
     ) -> Result<(u32, Self), ConnectionError> {
         let data = Self::read(read_stream, 2500).await?;
+        if data == REJECTED {
+            return Err(ConnectionError::Rejected);
+        }
 
-        let id = u32::from_be_bytes(
-            data[0..4]
-                .try_into()
-                .map_err(|_| ConnectionError::InvalidHandshake("Missing id."))?,
-        );
-        let key: [u8; 16] = data[4..20]
-            .try_into()
-            .map_err(|_| ConnectionError::InvalidHandshake("Missing key."))?;
+        // A short frame from the server is an invalid handshake rather than out of bounds.
+        let id = data
+            .get(0..4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u32::from_be_bytes)
+            .ok_or(ConnectionError::InvalidHandshake("Missing id."))?;
+        #[cfg(not(feature = "rustls"))]
+        let key: Key = data
+            .get(4..20)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ConnectionError::InvalidHandshake("Missing key."))?;
+        let features = data
+            .get(FEATURES_OFFSET..FEATURES_OFFSET + 4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(|bits| Features::from_bits(u32::from_be_bytes(bits)))
+            .ok_or(ConnectionError::InvalidHandshake("Missing features."))?;
 
         let mut sign_mac = Cmac::<Aes128>::new_varkey(&key)
             .map_err(|err| ConnectionError::FailedToCreateMac(err.to_string()))?;
@@ -60,24 +193,28 @@ where
         let tag: [u8; 8] = {
             sign_mac.update(b"ACK");
 
-            sign_mac.finalize_reset().into_bytes().as_slice()[0..8]
+            sign_mac.finalize_reset().into_bytes()[0..8]
                 .try_into()
                 .unwrap()
         };
 
         let mut ack = tag.to_vec(); // Add tag.
-        ack.extend(&data[0..4]); // Add id (using raw received bytes).
+        ack.extend(&id.to_be_bytes()); // Add id.
         ack.extend(b"ACK"); // Add data.
 
         // Handshake - Send unreliable ACK (2):
         socket.send(&ack).await?;
+        // Kept across retries, since dropping a read in the middle of a frame would lose the part already read.
+        let acknowledged = async {
+            while Self::read(read_stream, 80).await? != b"ACK" {}
+            io::Result::Ok(())
+        };
+        tokio::pin!(acknowledged);
         loop {
             tokio::select! {
-                result = Self::read(read_stream, 80) => {
-                    let data = result?;
-                    if data == b"ACK" {
-                        break;
-                    }
+                result = &mut acknowledged => {
+                    result?;
+                    break;
                 },
                 _ = sleep(Duration::from_millis(128)) => {
                     // Retry. Packet was probably dropped or the latency is high.
@@ -86,28 +223,62 @@ where
             }
         }
 
-        // Handshake - Send final reliable ACK and token (3):
+        // Handshake - Send final reliable ACK, features and token (3):
+        write_stream
+            .write_u32((b"ACK".len() + 4 + token.len()) as u32)
+            .await?;
+        write_stream.write_all(b"ACK").await?;
         write_stream
-            .write_u32((b"ACK".len() + token.len()) as u32)
+            .write_u32(config.advertised_features().bits())
             .await?;
-        write_stream.write(b"ACK").await?;
-        write_stream.write(&token).await?;
+        write_stream.write_all(&token).await?;
 
         Ok((
             id,
             Self {
-                key,
                 sign_mac: std::sync::Mutex::new(sign_mac),
                 verify_mac: std::sync::Mutex::new(verify_mac),
                 write_stream: Mutex::new(write_stream),
                 address: Mutex::new(None),
+                endpoint: AtomicUsize::new(0),
+                peer_address,
+                token: std::sync::Mutex::new(token),
+                pacer: std::sync::Mutex::new(Pacer::default()),
+                cork: std::sync::Mutex::new(None),
+                coalesced: config
+                    .reliable_coalesce_window
+                    .map(|_| std::sync::Mutex::new(vec![])),
+                reassembler: std::sync::Mutex::new(Reassembler::new(
+                    config.max_fragments,
+                    datagram::max_message_size(&config),
+                    config.fragment_timeout,
+                )),
+                fragment_id: AtomicU16::new(0),
+                reader: std::sync::Mutex::new(None),
+                features: AtomicU32::new((features & config.advertised_features()).bits()),
+                send_counter: AtomicU64::new(1),
+                replay_window: std::sync::Mutex::new(ReplayWindow::new(config.replay_window)),
+                send_sequence: AtomicU32::new(1),
+                received_sequence: AtomicU32::new(0),
+                receipts: std::sync::Mutex::new(VecDeque::new()),
+                span: span::connection(id, peer_address),
             },
         ))
     }
 
-    pub async fn accept(id: u32, mut write_stream: WriteHalf<T>) -> Result<Self, ConnectionError> {
-        let mut key = [0u8; 16];
-        rand::thread_rng().fill_bytes(&mut key);
+    pub async fn accept(
+        id: ConnectionId,
+        mut write_stream: WriteHalf<T>,
+        peer_address: SocketAddr,
+        #[cfg(feature = "rustls")] key: Key,
+        config: Config,
+    ) -> Result<Self, ConnectionError> {
+        #[cfg(not(feature = "rustls"))]
+        let key = {
+            let mut key = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        };
 
         let sign_mac = Cmac::<Aes128>::new_varkey(&key)
             .map_err(|err| ConnectionError::FailedToCreateMac(format!("{}", err)))?;
@@ -115,37 +286,262 @@ where
             .map_err(|err| ConnectionError::FailedToCreateMac(format!("{}", err)))?;
 
         // Handshake - Initiate (1):
-        write_stream.write_u32(4 + key.len() as u32).await?; // Connection id (u32) size + Key size
-        write_stream.write_u32(id as u32).await?; // Connection id.
-        write_stream.write(&key).await?; // Key.
+        #[cfg(feature = "rustls")]
+        write_stream.write_u32(4 + 4).await?; // Connection id (u32) size + Features (u32) size
+        #[cfg(not(feature = "rustls"))]
+        write_stream.write_u32(4 + key.len() as u32 + 4).await?; // Connection id (u32) size + Key size + Features (u32) size
+        write_stream.write_u32(id.index()).await?; // Connection id.
+        #[cfg(not(feature = "rustls"))]
+        write_stream.write_all(&key).await?; // Key.
+        write_stream
+            .write_u32(config.advertised_features().bits())
+            .await?; // Features.
 
         Ok(Self {
-            key,
             sign_mac: std::sync::Mutex::new(sign_mac),
             verify_mac: std::sync::Mutex::new(verify_mac),
             write_stream: Mutex::new(write_stream),
             address: Mutex::new(None),
+            endpoint: AtomicUsize::new(0),
+            peer_address,
+            token: std::sync::Mutex::new(vec![]),
+            pacer: std::sync::Mutex::new(Pacer::default()),
+            cork: std::sync::Mutex::new(None),
+            coalesced: config
+                .reliable_coalesce_window
+                .map(|_| std::sync::Mutex::new(vec![])),
+            reassembler: std::sync::Mutex::new(Reassembler::new(
+                config.max_fragments,
+                datagram::max_message_size(&config),
+                config.fragment_timeout,
+            )),
+            fragment_id: AtomicU16::new(0),
+            reader: std::sync::Mutex::new(None),
+            features: AtomicU32::new(Features::empty().bits()),
+            send_counter: AtomicU64::new(1),
+            replay_window: std::sync::Mutex::new(ReplayWindow::new(config.replay_window)),
+            send_sequence: AtomicU32::new(1),
+            received_sequence: AtomicU32::new(0),
+            receipts: std::sync::Mutex::new(VecDeque::new()),
+            span: span::connection(id, peer_address),
         })
     }
 
+    /// Refuse a connection by sending a rejection frame ([`REJECTED`] or [`INVALID_TOKEN`]) and closing the stream.
+    pub async fn reject(mut write_stream: WriteHalf<T>, reason: &[u8]) -> io::Result<()> {
+        write_stream.write_u32(reason.len() as u32).await?;
+        write_stream.write_all(reason).await?;
+        write_stream.shutdown().await
+    }
+
     pub async fn write(&self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(&self.frame(data)).await
+    }
+
+    /// Writes a frame built by [`Connection::frame`], which lets a frame built once be written to several connections.
+    pub async fn write_frame(&self, frame: &[u8]) -> io::Result<()> {
+        let held = {
+            let mut cork = self.cork.lock().unwrap();
+            let mut coalesced = self.coalesced.as_ref().map(|buffer| buffer.lock().unwrap());
+            match cork.as_mut().or(coalesced.as_deref_mut()) {
+                Some(buffer) => {
+                    buffer.extend_from_slice(frame);
+                    if buffer.len() < CORK_CAPACITY {
+                        return Ok(());
+                    }
+                    Some(std::mem::take(buffer))
+                }
+                None => None,
+            }
+        };
+
+        match held {
+            Some(bytes) => self.write_frames(&bytes).await,
+            None => self.write_frames(frame).await,
+        }
+    }
+
+    /// Prefixes the data with its length, compressing it if compression was negotiated and the data shrinks.
+    /// Connections that agree on [`Connection::compresses`] build the same frame from the same data.
+    pub fn frame(&self, data: &[u8]) -> Vec<u8> {
+        #[cfg(feature = "lz4")]
+        if self.compresses() {
+            if let Some(compressed) = compression::compress(data) {
+                let mut frame = Vec::with_capacity(4 + compressed.len());
+                frame.extend(&(compressed.len() as u32 | COMPRESSED).to_be_bytes());
+                frame.extend(compressed);
+                return frame;
+            }
+        }
+
+        let mut frame = Vec::with_capacity(4 + data.len());
+        frame.extend(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// Hold back reliable frames until [`Connection::uncork`] is called or the buffer fills up.
+    pub fn cork(&self) {
+        let mut cork = self.cork.lock().unwrap();
+        if cork.is_none() {
+            // Frames waiting to be coalesced must be written before any frame written while corked.
+            let coalesced = self
+                .coalesced
+                .as_ref()
+                .map(|buffer| std::mem::take(&mut *buffer.lock().unwrap()));
+            *cork = Some(coalesced.unwrap_or_default());
+        }
+    }
+
+    /// Write the frames held back by [`Config::reliable_coalesce_window`], should be called at least once per window.
+    pub async fn flush_coalesced(&self) -> io::Result<()> {
+        let bytes = match self.coalesced.as_ref() {
+            Some(buffer) => std::mem::take(&mut *buffer.lock().unwrap()),
+            None => return Ok(()),
+        };
+
+        if bytes.is_empty() {
+            Ok(())
+        } else {
+            self.write_frames(&bytes).await
+        }
+    }
+
+    /// Write the frames held back by [`Config::reliable_coalesce_window`] and flush the stream, so that every frame written before has been handed to the operating system.
+    /// Frames held back by [`Connection::cork`] stay held back.
+    pub async fn flush(&self) -> io::Result<()> {
+        self.flush_coalesced().await?;
+        self.write_stream.lock().await.flush().await
+    }
+
+    /// Write all frames held back since [`Connection::cork`] was called, and stop holding back frames.
+    pub async fn uncork(&self) -> io::Result<()> {
+        let bytes = self.cork.lock().unwrap().take();
+        match bytes {
+            Some(bytes) if !bytes.is_empty() => self.write_frames(&bytes).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the coalesced messages, if any, and closes the stream, which the peer reads as the end of the stream.
+    pub async fn close(&self) -> io::Result<()> {
+        self.flush_coalesced().await?;
+        self.write_stream.lock().await.shutdown().await
+    }
+
+    async fn write_frames(&self, bytes: &[u8]) -> io::Result<()> {
         let mut write_stream = self.write_stream.lock().await;
-        write_stream.write_u32(data.len() as u32).await?;
-        write_stream.write(data).await?;
+        write_stream.write_all(bytes).await?;
 
         write_stream.flush().await?;
 
         Ok(())
     }
 
+    pub fn features(&self) -> Features {
+        Features::from_bits(self.features.load(Ordering::Relaxed))
+    }
+
+    /// Whether reliable frames written to the connection are compressed, see [`Features::COMPRESSION`].
+    pub fn compresses(&self) -> bool {
+        cfg!(feature = "lz4") && self.features().contains(Features::COMPRESSION)
+    }
+
+    /// Sets the features supported by both sides, once the other side has advertised its features.
+    pub fn set_features(&self, features: Features) {
+        self.features.store(features.bits(), Ordering::Relaxed);
+    }
+
+    /// Waits for a receipt of the next message written after a [`RECEIPT`] frame.
+    pub fn expect_receipt(&self, reply: oneshot::Sender<Result<(), SendError>>) {
+        self.receipts.lock().unwrap().push_back(reply);
+    }
+
+    /// Replies to the oldest message waiting for a receipt, returns false if none was waiting.
+    pub fn receipt(&self) -> bool {
+        match self.receipts.lock().unwrap().pop_front() {
+            Some(reply) => {
+                let _ = reply.send(Ok(()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn endpoint(&self) -> usize {
+        self.endpoint.load(Ordering::Relaxed)
+    }
+
+    pub fn set_endpoint(&self, endpoint: usize) {
+        self.endpoint.store(endpoint, Ordering::Relaxed);
+    }
+
+    /// Maximum number of fragments an unreliable message can be sent as.
+    pub fn max_fragments(&self, config: &Config) -> u8 {
+        if self.features().contains(Features::FRAGMENTATION) {
+            config.max_fragments
+        } else {
+            1
+        }
+    }
+
+    pub fn next_fragment_id(&self) -> u16 {
+        self.fragment_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Sequence to send an unreliable message with, [`None`] unless it is sequenced and the other side supports [`Features::SEQUENCING`].
+    pub fn next_sequence(&self, delivery: Delivery) -> Option<u32> {
+        match delivery {
+            Delivery::UnreliableSequenced if self.features().contains(Features::SEQUENCING) => {
+                Some(self.send_sequence.fetch_add(1, Ordering::Relaxed))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true if a sequenced message is newer than every sequenced message accepted before, which it then replaces.
+    /// Sequences are compared with wrap-around, so a sequence is newer if it is ahead by less than half the range.
+    pub fn accept_sequence(&self, sequence: u32) -> bool {
+        self.received_sequence
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |highest| {
+                ((sequence.wrapping_sub(highest) as i32) > 0).then_some(sequence)
+            })
+            .is_ok()
+    }
+
+    /// Prefixes the payload of an unreliable datagram with the next counter, before it is signed.
+    pub fn sequence(&self, payload: Vec<u8>) -> Vec<u8> {
+        let counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut data = Vec::with_capacity(COUNTER_SIZE + payload.len());
+        data.extend(&counter.to_be_bytes());
+        data.extend(payload);
+        data
+    }
+
+    /// Checks the counter in front of a verified unreliable datagram against the replay window.
+    /// Returns false if the datagram is a replay (or duplicate) of an accepted datagram.
+    pub fn accept_counter(&self, data: &[u8]) -> bool {
+        match data.get(0..COUNTER_SIZE) {
+            Some(counter) => {
+                let counter = u64::from_be_bytes(counter.try_into().unwrap());
+                self.replay_window.lock().unwrap().accept(counter)
+            }
+            None => false,
+        }
+    }
+
+    /// Estimated fraction of unreliable datagrams from the other side that were lost, see [`ReplayWindow`].
+    pub fn packet_loss(&self) -> Option<f32> {
+        self.replay_window.lock().unwrap().loss()
+    }
+
     pub fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
         let mut mac = self.verify_mac.lock().unwrap();
 
         mac.update(data);
 
-        let verify_tag: [u8; 8] = mac.finalize_reset().into_bytes().as_slice()[0..8]
-            .try_into()
-            .unwrap();
+        let verify_tag: [u8; 8] = mac.finalize_reset().into_bytes()[0..8].try_into().unwrap();
 
         verify_tag == tag
     }
@@ -155,9 +551,7 @@ where
 
         mac.update(data);
 
-        mac.finalize_reset().into_bytes().as_slice()[0..8]
-            .try_into()
-            .unwrap()
+        mac.finalize_reset().into_bytes()[0..8].try_into().unwrap()
     }
 
     pub async fn read(read_stream: &mut ReadHalf<T>, max_size: u32) -> io::Result<Vec<u8>> {
@@ -167,11 +561,16 @@ where
 
             u32::from_be_bytes(bytes)
         };
+        let compressed = frame_size & COMPRESSED != 0;
+        let frame_size = frame_size & !COMPRESSED;
+        // Checked before anything is allocated, and the buffer only grows with the bytes that actually arrive,
+        // so a peer declaring a huge frame cannot make the reader allocate more than it sends.
         if frame_size > max_size {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Max frame size exceeded.",
-            ));
+            return Err(FrameError::TooLarge {
+                size: frame_size,
+                max: max_size,
+            }
+            .into());
         }
 
         let mut buffer = vec![];
@@ -179,7 +578,176 @@ where
             .take(frame_size as u64)
             .read_to_end(&mut buffer)
             .await?;
+        // Like `read_exact`, a stream ending within the frame is an error rather than a shorter frame.
+        if buffer.len() < frame_size as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        if compressed {
+            #[cfg(feature = "lz4")]
+            return compression::decompress(&buffer, max_size);
+
+            #[cfg(not(feature = "lz4"))]
+            return Err(FrameError::CompressionUnsupported.into());
+        }
 
         Ok(buffer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        pin::Pin,
+        sync::{atomic::AtomicUsize, Arc},
+        task::{Context, Poll},
+    };
+    use tokio::{
+        io::{duplex, split, DuplexStream, ReadBuf},
+        time::timeout,
+    };
+
+    /// Counts the writes handed to the stream, each of which becomes at least one segment with `TCP_NODELAY`.
+    struct CountingStream {
+        inner: DuplexStream,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountingStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CountingStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if poll.is_ready() {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+            }
+            poll
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Number of writes to the stream for a burst of 100 small messages.
+    async fn writes_for_burst(config: Config) -> usize {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let (inner, _peer) = duplex(1 << 20);
+        let stream = CountingStream {
+            inner,
+            writes: writes.clone(),
+        };
+        let (_read_stream, write_stream) = split(stream);
+        let connection = Connection::accept(
+            ConnectionId::new(0, 0),
+            write_stream,
+            "127.0.0.1:0".parse().unwrap(),
+            #[cfg(feature = "rustls")]
+            [0; 16],
+            config,
+        )
+        .await
+        .unwrap();
+
+        writes.store(0, Ordering::Relaxed);
+        for i in 0..100u8 {
+            connection.write(&[i; 8]).await.unwrap();
+        }
+        connection.flush_coalesced().await.unwrap();
+        writes.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn coalescing_reduces_writes() {
+        let coalesced = Config::builder()
+            .reliable_coalesce_window(Some(Duration::from_millis(1)))
+            .build()
+            .unwrap();
+        assert_eq!(writes_for_burst(Config::default()).await, 100);
+        assert_eq!(writes_for_burst(coalesced).await, 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected_before_reading_it() {
+        let (client, mut server) = duplex(64);
+        let (mut read_stream, _write_stream) = split(client);
+
+        // Only the length prefix is written and the stream stays open, so reading the frame would wait forever.
+        server
+            .write_all(&0x7fff_ffffu32.to_be_bytes())
+            .await
+            .unwrap();
+        let err = timeout(
+            Duration::from_secs(1),
+            Connection::read(&mut read_stream, 1024),
+        )
+        .await
+        .expect("the frame was awaited")
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            frame_error(&err),
+            Some(&FrameError::TooLarge {
+                size: 0x7fff_ffff,
+                max: 1024
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_an_unexpected_eof() {
+        let (client, mut server) = duplex(64);
+        let (mut read_stream, _write_stream) = split(client);
+
+        // Declares a frame of 1GB within the maximum, but sends 3 bytes of it.
+        server.write_all(&(1u32 << 30).to_be_bytes()).await.unwrap();
+        server.write_all(b"ACK").await.unwrap();
+        drop(server);
+        let err = Connection::read(&mut read_stream, 1 << 30)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn short_handshake_is_invalid() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (client, mut server) = duplex(64);
+        let (mut read_stream, write_stream) = split(client);
+
+        // A frame of 2 bytes, too short for the id.
+        server.write_all(&[0, 0, 0, 2, 0, 0]).await.unwrap();
+        let result = Connection::connect(
+            &socket,
+            &mut read_stream,
+            write_stream,
+            socket.local_addr().unwrap(),
+            vec![],
+            #[cfg(feature = "rustls")]
+            [0; 16],
+            Config::default(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(ConnectionError::InvalidHandshake("Missing id."))
+        ));
+    }
+}
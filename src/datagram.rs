@@ -0,0 +1,453 @@
+//! Payload region of unreliable datagrams.
+//!
+//! The first byte of the payload region identifies its kind:
+//!
+//! * `0`: one or more complete messages, length-delimited as described in [`framing`](crate::framing).
+//! * `1`: a fragment of a single message too large for one datagram, laid out as
+//!   `fragment id (u16) | index (u8) | count (u8) | chunk`. The message is the concatenation of the chunks of all `count` fragments in index order.
+//! * `2`: a time request from the client, `client time (u64)`.
+//! * `3`: a time response from the server, `client time (u64) | server time (u64)`, see [`clock`](crate::clock).
+//! * `4`: a sequenced message or fragment, `sequence (u32) | payload region of kind 0 or 1`, see [`Delivery::UnreliableSequenced`](crate::Delivery::UnreliableSequenced).
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+use crate::{
+    framing::{self, FramingError},
+    Config, Features,
+};
+
+const KIND_MESSAGES: u8 = 0;
+const KIND_FRAGMENT: u8 = 1;
+const KIND_TIME_REQUEST: u8 = 2;
+const KIND_TIME_RESPONSE: u8 = 3;
+const KIND_SEQUENCED: u8 = 4;
+
+/// Maximum number of message bytes carried by a single datagram. Larger messages are fragmented.
+pub const FRAGMENT_SIZE: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum DatagramError {
+    #[error("Datagram is empty.")]
+    Empty,
+    #[error("Datagram has unknown kind: {0}.")]
+    UnknownKind(u8),
+    #[error("Datagram has malformed messages: {0}")]
+    Framing(#[from] FramingError),
+    #[error("Datagram has a malformed fragment.")]
+    MalformedFragment,
+    #[error("Datagram has a malformed time request or response.")]
+    MalformedTime,
+    #[error("Datagram has a malformed sequenced payload.")]
+    MalformedSequence,
+}
+
+#[derive(Debug)]
+pub enum Payload<'a> {
+    Messages(Vec<&'a [u8]>),
+    Fragment(Fragment<'a>),
+    TimeRequest { client_time: u64 },
+    TimeResponse { client_time: u64, server_time: u64 },
+}
+
+#[derive(Debug)]
+pub struct Fragment<'a> {
+    id: u16,
+    index: u8,
+    count: u8,
+    chunk: &'a [u8],
+}
+
+/// Size of the largest unreliable message that can be sent, given the configured limits.
+pub fn max_message_size(config: &Config) -> usize {
+    let max = if config.features.contains(Features::FRAGMENTATION) {
+        FRAGMENT_SIZE * config.max_fragments as usize
+    } else {
+        FRAGMENT_SIZE
+    };
+
+    config.max_unreliable_size.map_or(max, |size| size.min(max))
+}
+
+/// Encodes a message into one or more payload regions, fragmenting it if it is larger than [`FRAGMENT_SIZE`].
+/// Returns [`None`] if the message would need more than `max_fragments` fragments.
+pub fn encode<F: FnOnce() -> u16>(
+    data: &[u8],
+    fragment_id: F,
+    max_fragments: u8,
+) -> Option<Vec<Vec<u8>>> {
+    if data.len() <= FRAGMENT_SIZE {
+        let mut payload = vec![KIND_MESSAGES];
+        framing::encode(data, &mut payload);
+        return Some(vec![payload]);
+    }
+
+    let count = data.len().div_ceil(FRAGMENT_SIZE);
+    if count > max_fragments as usize {
+        return None;
+    }
+
+    let id = fragment_id();
+    let payloads = data
+        .chunks(FRAGMENT_SIZE)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut payload = Vec::with_capacity(5 + chunk.len());
+            payload.push(KIND_FRAGMENT);
+            payload.extend(&id.to_be_bytes());
+            payload.push(index as u8);
+            payload.push(count as u8);
+            payload.extend_from_slice(chunk);
+            payload
+        })
+        .collect();
+
+    Some(payloads)
+}
+
+/// An empty batch of messages, sent to keep the UDP mapping of NATs open.
+pub fn encode_keep_alive() -> Vec<u8> {
+    vec![KIND_MESSAGES]
+}
+
+pub fn encode_time_request(client_time: u64) -> Vec<u8> {
+    let mut payload = vec![KIND_TIME_REQUEST];
+    payload.extend(&client_time.to_be_bytes());
+    payload
+}
+
+pub fn encode_time_response(client_time: u64, server_time: u64) -> Vec<u8> {
+    let mut payload = vec![KIND_TIME_RESPONSE];
+    payload.extend(&client_time.to_be_bytes());
+    payload.extend(&server_time.to_be_bytes());
+    payload
+}
+
+/// Prefixes a payload region of messages or a fragment with a sequence number.
+pub fn encode_sequenced(sequence: u32, payload: Vec<u8>) -> Vec<u8> {
+    let mut sequenced = Vec::with_capacity(5 + payload.len());
+    sequenced.push(KIND_SEQUENCED);
+    sequenced.extend(&sequence.to_be_bytes());
+    sequenced.extend(payload);
+    sequenced
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Payload<'_>, DatagramError> {
+    let (kind, rest) = bytes.split_first().ok_or(DatagramError::Empty)?;
+    match *kind {
+        KIND_MESSAGES => Ok(Payload::Messages(framing::decode(rest)?)),
+        KIND_FRAGMENT => {
+            if rest.len() < 4 {
+                return Err(DatagramError::MalformedFragment);
+            }
+
+            let fragment = Fragment {
+                id: u16::from_be_bytes(rest[0..2].try_into().unwrap()),
+                index: rest[2],
+                count: rest[3],
+                chunk: &rest[4..],
+            };
+
+            if fragment.index >= fragment.count {
+                return Err(DatagramError::MalformedFragment);
+            }
+
+            Ok(Payload::Fragment(fragment))
+        }
+        KIND_TIME_REQUEST => {
+            let client_time = rest.try_into().map_err(|_| DatagramError::MalformedTime)?;
+
+            Ok(Payload::TimeRequest {
+                client_time: u64::from_be_bytes(client_time),
+            })
+        }
+        KIND_TIME_RESPONSE => {
+            if rest.len() != 16 {
+                return Err(DatagramError::MalformedTime);
+            }
+
+            Ok(Payload::TimeResponse {
+                client_time: u64::from_be_bytes(rest[0..8].try_into().unwrap()),
+                server_time: u64::from_be_bytes(rest[8..16].try_into().unwrap()),
+            })
+        }
+        // Sequenced payloads are unwrapped by `decode_sequenced`, so this one is truncated or nested.
+        KIND_SEQUENCED => Err(DatagramError::MalformedSequence),
+        kind => Err(DatagramError::UnknownKind(kind)),
+    }
+}
+
+/// Decodes a payload region like [`decode`], unwrapping the sequence of a sequenced message or fragment.
+pub fn decode_sequenced(bytes: &[u8]) -> (Option<u32>, Result<Payload<'_>, DatagramError>) {
+    match bytes.split_first() {
+        Some((&KIND_SEQUENCED, rest)) if rest.len() >= 4 => {
+            let sequence = u32::from_be_bytes(rest[0..4].try_into().unwrap());
+            let payload = match decode(&rest[4..]) {
+                Ok(payload @ (Payload::Messages(_) | Payload::Fragment(_))) => Ok(payload),
+                Ok(_) => Err(DatagramError::MalformedSequence),
+                Err(err) => Err(err),
+            };
+
+            (Some(sequence), payload)
+        }
+        _ => (None, decode(bytes)),
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    started: Instant,
+    received: usize,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+/// Reassembles fragmented messages received on a connection.
+#[derive(Debug)]
+pub struct Reassembler {
+    partials: HashMap<u16, Partial>,
+    buffered: usize,
+    max_fragments: usize,
+    max_size: usize,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    /// Creates a reassembler holding at most `max_fragments` fragments at a time, of messages up to `max_size` bytes,
+    /// discarding incomplete messages once they are older than `timeout`. See [`max_message_size`] for the size.
+    pub fn new(max_fragments: u8, max_size: usize, timeout: Duration) -> Self {
+        Self {
+            partials: HashMap::new(),
+            buffered: 0,
+            max_fragments: max_fragments as usize,
+            max_size,
+            timeout,
+        }
+    }
+
+    /// Adds a fragment, returning the complete message once all of its fragments have been received.
+    pub fn insert(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        let count = fragment.count as usize;
+        if count > self.max_fragments {
+            return None;
+        }
+
+        // Every chunk but the last is full, so the count declares a message of at least `(count - 1) * FRAGMENT_SIZE + 1` bytes.
+        let is_sized = if fragment.index as usize + 1 == count {
+            !fragment.chunk.is_empty() && fragment.chunk.len() <= FRAGMENT_SIZE
+        } else {
+            fragment.chunk.len() == FRAGMENT_SIZE
+        };
+        if !is_sized || (count - 1) * FRAGMENT_SIZE + 1 > self.max_size {
+            return None;
+        }
+
+        self.expire();
+
+        // A fragment id that is reused with a different count belongs to a new message.
+        if let Some(partial) = self.partials.get(&fragment.id) {
+            if partial.chunks.len() != count {
+                self.discard(fragment.id);
+            }
+        }
+
+        let is_new = self
+            .partials
+            .get(&fragment.id)
+            .map(|partial| partial.chunks[fragment.index as usize].is_none())
+            .unwrap_or(true);
+        if !is_new {
+            return None;
+        }
+
+        // Make room by discarding the oldest incomplete messages.
+        while self.buffered + 1 > self.max_fragments {
+            let oldest = self
+                .partials
+                .iter()
+                .filter(|(id, _)| **id != fragment.id)
+                .min_by_key(|(_, partial)| partial.started)
+                .map(|(id, _)| *id)?;
+            self.discard(oldest);
+        }
+
+        let partial = self.partials.entry(fragment.id).or_insert_with(|| Partial {
+            started: Instant::now(),
+            received: 0,
+            chunks: vec![None; count],
+        });
+        partial.chunks[fragment.index as usize] = Some(fragment.chunk.to_vec());
+        partial.received += 1;
+        self.buffered += 1;
+
+        if partial.received == count {
+            let partial = self.partials.remove(&fragment.id)?;
+            self.buffered -= partial.received;
+
+            Some(partial.chunks.into_iter().flatten().flatten().collect())
+                .filter(|message: &Vec<u8>| message.len() <= self.max_size)
+        } else {
+            None
+        }
+    }
+
+    fn discard(&mut self, id: u16) {
+        if let Some(partial) = self.partials.remove(&id) {
+            self.buffered -= partial.received;
+        }
+    }
+
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        let expired: Vec<u16> = self
+            .partials
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            self.discard(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a message into fragments with the given id.
+    fn fragments(data: &[u8], id: u16) -> Vec<Vec<u8>> {
+        encode(data, || id, u8::MAX).unwrap()
+    }
+
+    fn fragment(payload: &[u8]) -> Fragment<'_> {
+        match decode(payload).unwrap() {
+            Payload::Fragment(fragment) => fragment,
+            payload => panic!("expected a fragment, got {:?}", payload),
+        }
+    }
+
+    fn message(size: usize) -> Vec<u8> {
+        (0..size).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn reassembles_fragments_out_of_order() {
+        let data = message(3 * FRAGMENT_SIZE - 10);
+        let payloads = fragments(&data, 1);
+        assert_eq!(payloads.len(), 3);
+
+        let mut reassembler = Reassembler::new(8, 8 * FRAGMENT_SIZE, Duration::from_secs(1));
+        assert_eq!(reassembler.insert(fragment(&payloads[2])), None);
+        assert_eq!(reassembler.insert(fragment(&payloads[0])), None);
+        assert_eq!(reassembler.insert(fragment(&payloads[1])), Some(data));
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn ignores_duplicate_fragments() {
+        let data = message(2 * FRAGMENT_SIZE);
+        let payloads = fragments(&data, 1);
+
+        let mut reassembler = Reassembler::new(8, 8 * FRAGMENT_SIZE, Duration::from_secs(1));
+        assert_eq!(reassembler.insert(fragment(&payloads[0])), None);
+        assert_eq!(reassembler.insert(fragment(&payloads[0])), None);
+        assert_eq!(reassembler.buffered, 1);
+        assert_eq!(reassembler.insert(fragment(&payloads[1])), Some(data));
+
+        // A fragment arriving after its message is complete starts a new message, which never completes.
+        assert_eq!(reassembler.insert(fragment(&payloads[1])), None);
+    }
+
+    #[test]
+    fn rejects_messages_with_more_fragments_than_the_maximum() {
+        let payloads = fragments(&message(3 * FRAGMENT_SIZE), 1);
+
+        let mut reassembler = Reassembler::new(2, 8 * FRAGMENT_SIZE, Duration::from_secs(1));
+        for payload in &payloads {
+            assert_eq!(reassembler.insert(fragment(payload)), None);
+        }
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn rejects_messages_larger_than_the_maximum_size() {
+        let payloads = fragments(&message(3 * FRAGMENT_SIZE), 1);
+
+        // The count of three declares more than two full chunks, whichever fragment arrives first.
+        let mut reassembler = Reassembler::new(8, 2 * FRAGMENT_SIZE, Duration::from_secs(1));
+        for payload in &payloads {
+            assert_eq!(reassembler.insert(fragment(payload)), None);
+        }
+        assert_eq!(reassembler.buffered, 0);
+
+        // A last chunk that takes the message past the maximum discards it once complete.
+        let payloads = fragments(&message(2 * FRAGMENT_SIZE), 1);
+        let mut reassembler = Reassembler::new(8, FRAGMENT_SIZE + 1, Duration::from_secs(1));
+        assert_eq!(reassembler.insert(fragment(&payloads[0])), None);
+        assert_eq!(reassembler.insert(fragment(&payloads[1])), None);
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn rejects_chunks_of_the_wrong_size() {
+        let mut reassembler = Reassembler::new(8, 8 * FRAGMENT_SIZE, Duration::from_secs(1));
+        let chunk = |index, size| Fragment {
+            id: 1,
+            index,
+            count: 2,
+            chunk: &[0; 2 * FRAGMENT_SIZE][..size],
+        };
+
+        assert_eq!(reassembler.insert(chunk(0, FRAGMENT_SIZE - 1)), None);
+        assert_eq!(reassembler.insert(chunk(1, FRAGMENT_SIZE + 1)), None);
+        assert_eq!(reassembler.insert(chunk(1, 0)), None);
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn discards_the_oldest_message_when_full() {
+        let first = fragments(&message(2 * FRAGMENT_SIZE), 1);
+        let second = fragments(&message(3 * FRAGMENT_SIZE), 2);
+
+        let mut reassembler = Reassembler::new(3, 8 * FRAGMENT_SIZE, Duration::from_secs(1));
+        assert_eq!(reassembler.insert(fragment(&first[0])), None);
+        assert_eq!(reassembler.insert(fragment(&second[0])), None);
+        assert_eq!(reassembler.insert(fragment(&second[1])), None);
+        // Holding a third fragment of the second message discards the first message.
+        assert_eq!(
+            reassembler.insert(fragment(&second[2])),
+            Some(message(3 * FRAGMENT_SIZE))
+        );
+        assert_eq!(reassembler.insert(fragment(&first[1])), None);
+        assert_eq!(reassembler.buffered, 1);
+    }
+
+    #[test]
+    fn discards_expired_messages() {
+        let payloads = fragments(&message(2 * FRAGMENT_SIZE), 1);
+
+        let mut reassembler = Reassembler::new(8, 8 * FRAGMENT_SIZE, Duration::from_millis(1));
+        assert_eq!(reassembler.insert(fragment(&payloads[0])), None);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(reassembler.insert(fragment(&payloads[1])), None);
+        assert_eq!(reassembler.buffered, 1);
+    }
+
+    #[test]
+    fn rejects_malformed_fragments() {
+        assert!(matches!(
+            decode(&[KIND_FRAGMENT, 0, 1, 0]),
+            Err(DatagramError::MalformedFragment)
+        ));
+        // The index must be below the count.
+        assert!(matches!(
+            decode(&[KIND_FRAGMENT, 0, 1, 2, 2, 0]),
+            Err(DatagramError::MalformedFragment)
+        ));
+    }
+}
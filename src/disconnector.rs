@@ -1,8 +1,14 @@
 use futures::channel::mpsc::UnboundedSender;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use thiserror::Error;
 
-use crate::ConnectionId;
+use crate::{registry::Registry, ConnectionId};
 
 #[derive(Debug, Error)]
 pub enum DisconnectError {
@@ -12,15 +18,79 @@ pub enum DisconnectError {
     Disconnected,
 }
 
+/// Addresses that are temporarily blocked from interacting with the server.
+/// IPv4-mapped IPv6 addresses are stored as the IPv4 address, so blocks apply to IPv4 clients of a dual-stack server, see [`Config::dual_stack`](crate::Config::dual_stack).
+#[derive(Debug, Default)]
+pub struct BlockList {
+    entries: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl BlockList {
+    pub fn insert(&self, address: IpAddr, duration: Duration) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(address.to_canonical(), Instant::now() + duration);
+    }
+
+    pub fn remove(&self, address: IpAddr) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&address.to_canonical())
+            .is_some()
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Checks whether the address is currently blocked, removing the entry if it has expired.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&address) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                entries.remove(&address);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn entries(&self) -> Vec<(IpAddr, Instant)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, until| *until > now);
+        entries
+            .iter()
+            .map(|(address, until)| (*address, *until))
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct Disconnector {
+pub struct Disconnector<U = ()> {
     sender: UnboundedSender<ConnectionId>,
+    block_list: Arc<BlockList>,
+    registry: Arc<Registry<U>>,
 }
 
-impl Disconnector {
-    pub fn new(sender: UnboundedSender<ConnectionId>) -> Self {
-        Self { sender }
+impl<U> Disconnector<U> {
+    pub fn new(
+        sender: UnboundedSender<ConnectionId>,
+        block_list: Arc<BlockList>,
+        registry: Arc<Registry<U>>,
+    ) -> Self {
+        Self {
+            sender,
+            block_list,
+            registry,
+        }
     }
+
+    /// Close a connection from the server side, see [`ServerSender::disconnect`](crate::ServerSender::disconnect).
     pub fn disconnect(&self, id: ConnectionId) -> Result<(), DisconnectError> {
         self.sender.unbounded_send(id).map_err(|err| {
             if err.is_full() {
@@ -30,4 +100,39 @@ impl Disconnector {
             }
         })
     }
+
+    /// Block an IP address for the specified duration.
+    /// While blocked, new connections from the address are refused and its datagrams are silently dropped, without producing any events.
+    /// Blocking does not disconnect existing connections, use [`Disconnector::ban`] or [`Disconnector::disconnect`] for that.
+    pub fn block(&self, address: IpAddr, duration: Duration) {
+        self.block_list.insert(address, duration);
+    }
+
+    /// Block an IP address like [`Disconnector::block`], and also disconnect the connections from it, including those still performing the handshake.
+    /// Returns the number of connections that were disconnected. Lift the ban with [`Disconnector::unblock`].
+    pub fn ban(&self, address: IpAddr, duration: Duration) -> Result<usize, DisconnectError> {
+        self.block(address, duration);
+
+        let ids = self.registry.with_ip(address);
+        for &id in &ids {
+            self.disconnect(id)?;
+        }
+
+        Ok(ids.len())
+    }
+
+    /// Remove an IP address from the block list, returns true if it was blocked.
+    pub fn unblock(&self, address: IpAddr) -> bool {
+        self.block_list.remove(address)
+    }
+
+    /// Remove all IP addresses from the block list.
+    pub fn clear_blocked(&self) {
+        self.block_list.clear();
+    }
+
+    /// Returns the currently blocked IP addresses along with the time their block expires.
+    pub fn blocked(&self) -> Vec<(IpAddr, Instant)> {
+        self.block_list.entries()
+    }
 }
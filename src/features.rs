@@ -0,0 +1,76 @@
+use std::ops::{BitAnd, BitOr, Not};
+
+/// Optional protocol features, advertised by both sides during the handshake.
+/// A connection only uses the features supported by both the client and the server, see [`Config::features`](crate::Config::features).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Features(u32);
+
+impl Features {
+    /// Unreliable messages larger than 1KB are split into fragments. Without it such messages are dropped.
+    pub const FRAGMENTATION: Self = Self(1);
+    /// The client estimates the server clock, see [`ClientSender::estimated_server_time`](crate::ClientSender::estimated_server_time).
+    pub const TIME_SYNC: Self = Self(1 << 1);
+    /// Reliable frames may be compressed. Only advertised if `Config::compression` is set, which requires the `lz4` feature.
+    pub const COMPRESSION: Self = Self(1 << 2);
+    /// Reliable messages can be acknowledged once the peer has read them, see [`ClientSender::reliable_with_ack`](crate::ClientSender::reliable_with_ack).
+    pub const RECEIPTS: Self = Self(1 << 3);
+    /// Unreliable messages can be sequenced, dropping those older than a message already delivered, see [`Delivery::UnreliableSequenced`](crate::Delivery::UnreliableSequenced).
+    pub const SEQUENCING: Self = Self(1 << 4);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn all() -> Self {
+        Self(
+            Self::FRAGMENTATION.0
+                | Self::TIME_SYNC.0
+                | Self::COMPRESSION.0
+                | Self::RECEIPTS.0
+                | Self::SEQUENCING.0,
+        )
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Creates a set from its bitmask, keeping unknown bits so that they are dropped when intersected with a known set.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl Not for Features {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self(!self.0)
+    }
+}
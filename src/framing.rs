@@ -0,0 +1,144 @@
+//! Length-delimited framing of messages in the payload region of unreliable datagrams.
+//!
+//! A payload region of the messages kind (see [`datagram`](crate::datagram)) holds one or more messages.
+//! Each message is prefixed with its length encoded as an unsigned LEB128 varint:
+//! seven bits per byte, least significant group first, with the high bit set on every byte except the last.
+//!
+//! ```text
+//! +----------------+-----------+----------------+-----------+-----
+//! | varint length  | message   | varint length  | message   | ...
+//! +----------------+-----------+----------------+-----------+-----
+//! ```
+//!
+//! A datagram is only accepted if it splits exactly into whole messages, otherwise the entire datagram is dropped.
+
+use thiserror::Error;
+
+/// Maximum number of bytes in an encoded length. Lengths are limited to [`u32::MAX`].
+const MAX_VARINT_SIZE: usize = 5;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FramingError {
+    #[error("Message length is truncated.")]
+    TruncatedLength,
+    #[error("Message length is too large.")]
+    InvalidLength,
+    #[error("Message is shorter than its declared length.")]
+    TruncatedMessage,
+}
+
+/// Appends a length-prefixed message to the buffer.
+pub fn encode(data: &[u8], buffer: &mut Vec<u8>) {
+    let mut length = data.len() as u32;
+    loop {
+        let byte = (length & 0x7f) as u8;
+        length >>= 7;
+        if length == 0 {
+            buffer.push(byte);
+            break;
+        }
+        buffer.push(byte | 0x80);
+    }
+    buffer.extend_from_slice(data);
+}
+
+/// Splits a payload region into the messages it contains.
+pub fn decode(mut bytes: &[u8]) -> Result<Vec<&[u8]>, FramingError> {
+    let mut messages = vec![];
+    while !bytes.is_empty() {
+        let mut length: u64 = 0;
+        let mut consumed = 0;
+        loop {
+            let byte = *bytes.get(consumed).ok_or(FramingError::TruncatedLength)?;
+            length |= ((byte & 0x7f) as u64) << (7 * consumed);
+            consumed += 1;
+
+            if byte & 0x80 == 0 {
+                break;
+            } else if consumed == MAX_VARINT_SIZE {
+                return Err(FramingError::InvalidLength);
+            }
+        }
+
+        if length > u32::MAX as u64 {
+            return Err(FramingError::InvalidLength);
+        }
+
+        let end = consumed + length as usize;
+        if end > bytes.len() {
+            return Err(FramingError::TruncatedMessage);
+        }
+
+        messages.push(&bytes[consumed..end]);
+        bytes = &bytes[end..];
+    }
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_lengths_across_varint_sizes() {
+        // One, two and three byte lengths.
+        for &size in &[0, 1, 127, 128, 16_383, 16_384, 70_000] {
+            let data = vec![7; size];
+            let mut buffer = vec![];
+            encode(&data, &mut buffer);
+            assert_eq!(decode(&buffer), Ok(vec![&data[..]]), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn round_trips_several_messages() {
+        let mut buffer = vec![];
+        encode(b"first", &mut buffer);
+        encode(b"", &mut buffer);
+        encode(&[1; 200], &mut buffer);
+        assert_eq!(
+            decode(&buffer),
+            Ok(vec![&b"first"[..], &b""[..], &[1; 200][..]])
+        );
+    }
+
+    #[test]
+    fn encodes_lengths_least_significant_group_first() {
+        let mut buffer = vec![];
+        encode(&[0; 300], &mut buffer);
+        assert_eq!(buffer[..2], [0xac, 0x02]);
+    }
+
+    #[test]
+    fn rejects_truncated_length() {
+        assert_eq!(decode(&[0x80]), Err(FramingError::TruncatedLength));
+        assert_eq!(decode(&[0xff, 0xff]), Err(FramingError::TruncatedLength));
+
+        // A whole message followed by a truncated length drops the datagram.
+        let mut buffer = vec![];
+        encode(b"whole", &mut buffer);
+        buffer.push(0x80);
+        assert_eq!(decode(&buffer), Err(FramingError::TruncatedLength));
+    }
+
+    #[test]
+    fn rejects_truncated_message() {
+        assert_eq!(decode(&[3, 1, 2]), Err(FramingError::TruncatedMessage));
+        assert_eq!(decode(&[0x80, 0x01]), Err(FramingError::TruncatedMessage));
+    }
+
+    #[test]
+    fn rejects_lengths_beyond_u32() {
+        // Continued past the maximum number of bytes.
+        assert_eq!(
+            decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x01]),
+            Err(FramingError::InvalidLength)
+        );
+        // Five bytes, but larger than `u32::MAX`.
+        assert_eq!(
+            decode(&[0xff, 0xff, 0xff, 0xff, 0x1f]),
+            Err(FramingError::InvalidLength)
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use std::convert::TryInto;
+
+/// Size of an encoded [`Header`] in bytes.
+pub const HEADER_SIZE: usize = 4;
+
+/// Metadata attached to a reliable message.
+///
+/// Encoded in front of the message data in every reliable frame as `channel (u8) | flags (u8) | kind (u16, big-endian)`.
+/// Messages sent without a header carry the default (all zero) header, as do all unreliable messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Header {
+    /// Logical channel the message belongs to.
+    pub channel: u8,
+    /// Application-defined flags.
+    pub flags: u8,
+    /// Application-defined message type.
+    pub kind: u16,
+}
+
+impl Header {
+    pub fn new(channel: u8, flags: u8, kind: u16) -> Self {
+        Self {
+            channel,
+            flags,
+            kind,
+        }
+    }
+
+    /// Encodes the header followed by the data into a single buffer.
+    pub(crate) fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + data.len());
+        bytes.push(self.channel);
+        bytes.push(self.flags);
+        bytes.extend(&self.kind.to_be_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    /// Removes the header from the front of a frame, leaving only the message data.
+    pub(crate) fn decode(frame: &mut Vec<u8>) -> Option<Self> {
+        if frame.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let header = Self {
+            channel: frame[0],
+            flags: frame[1],
+            kind: u16::from_be_bytes(frame[2..4].try_into().unwrap()),
+        };
+        frame.drain(..HEADER_SIZE);
+
+        Some(header)
+    }
+}
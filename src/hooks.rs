@@ -0,0 +1,159 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{ConnectionId, Delivery};
+
+type OnAccept = dyn Fn(SocketAddr) -> bool + Send + Sync;
+type OnConnect = dyn Fn(ConnectionId) -> Option<Vec<u8>> + Send + Sync;
+type OnResume = dyn Fn(ConnectionId, ConnectionId) + Send + Sync;
+type OnPayload = dyn Fn(&mut Vec<u8>, Delivery) + Send + Sync;
+
+/// Callbacks invoked by the server while establishing connections, see [`Server::listen_with_hooks`](crate::Server::listen_with_hooks),
+/// and by the client or server on the payload of every message, see [`Client::connect_with_hooks`](crate::Client::connect_with_hooks).
+/// The client only invokes [`Hooks::on_send`] and [`Hooks::on_receive`].
+#[derive(Default)]
+pub struct Hooks {
+    on_accept: Option<Box<OnAccept>>,
+    on_connect: Option<Box<OnConnect>>,
+    on_resume: Option<Box<OnResume>>,
+    on_send: Option<Box<OnPayload>>,
+    on_receive: Option<Box<OnPayload>>,
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_accept", &self.on_accept.is_some())
+            .field("on_connect", &self.on_connect.is_some())
+            .field("on_resume", &self.on_resume.is_some())
+            .field("on_send", &self.on_send.is_some())
+            .field("on_receive", &self.on_receive.is_some())
+            .finish()
+    }
+}
+
+impl Hooks {
+    /// Called with the remote address of every incoming TCP connection, before any handshake work is done.
+    /// Connections for which it returns false are closed right away, so they never produce events. Use it for an allowlist,
+    /// and [`Disconnector::ban`](crate::Disconnector::ban) to ban addresses at runtime.
+    pub fn on_accept<F: Fn(SocketAddr) -> bool + Send + Sync + 'static>(
+        mut self,
+        on_accept: F,
+    ) -> Self {
+        self.on_accept = Some(Box::new(on_accept));
+        self
+    }
+
+    /// Called with the id of every connection that passes validation.
+    /// The returned data is written as the first reliable message (with a default [`Header`](crate::Header)) before the connection is established.
+    /// If writing it fails, the connection is dropped and no [`ServerEvent::Connected`](crate::ServerEvent::Connected) is dispatched for it.
+    pub fn on_connect<F: Fn(ConnectionId) -> Option<Vec<u8>> + Send + Sync + 'static>(
+        mut self,
+        on_connect: F,
+    ) -> Self {
+        self.on_connect = Some(Box::new(on_connect));
+        self
+    }
+
+    /// Called with the old and the new connection id when a client resumes its session, that is,
+    /// when a connection presents the same (non-empty) token as an established connection or as a connection closed within [`Config::resume_window`](crate::Config::resume_window).
+    /// It is called after the new connection is established and before its [`ServerEvent::Connected`](crate::ServerEvent::Connected) is dispatched,
+    /// so state can be migrated and messages sent to the new connection before the application handles any of its events.
+    /// An old connection that is still established is kept open.
+    pub fn on_resume<F: Fn(ConnectionId, ConnectionId) + Send + Sync + 'static>(
+        mut self,
+        on_resume: F,
+    ) -> Self {
+        self.on_resume = Some(Box::new(on_resume));
+        self
+    }
+
+    /// Called by the sender on the data of every message as it is sent, before the data is checked against the size limits of the [`Config`](crate::Config).
+    /// It may change the data, for instance to prepend a sequence number or to compress or encrypt it, as long as the peer reverses it with [`Hooks::on_receive`].
+    /// A message that grows beyond the limits is refused with [`SendError::TooLarge`](crate::SendError::TooLarge), and an initial message from [`Hooks::on_connect`] fails the connection.
+    /// A message sent to several connections, see [`ServerSender::multicast`](crate::ServerSender::multicast), is passed once.
+    pub fn on_send<F: Fn(&mut Vec<u8>, Delivery) + Send + Sync + 'static>(
+        mut self,
+        on_send: F,
+    ) -> Self {
+        self.on_send = Some(Box::new(on_send));
+        self
+    }
+
+    /// Called by the task on the data of every message right after it is read, before it is dispatched as an event.
+    /// Unreliable messages are passed once they are reassembled from their fragments.
+    pub fn on_receive<F: Fn(&mut Vec<u8>, Delivery) + Send + Sync + 'static>(
+        mut self,
+        on_receive: F,
+    ) -> Self {
+        self.on_receive = Some(Box::new(on_receive));
+        self
+    }
+
+    pub(crate) fn accept(&self, address: SocketAddr) -> bool {
+        self.on_accept
+            .as_ref()
+            .is_none_or(|on_accept| on_accept(address))
+    }
+
+    pub(crate) fn connect(&self, id: ConnectionId) -> Option<Vec<u8>> {
+        self.on_connect
+            .as_ref()
+            .and_then(|on_connect| on_connect(id))
+    }
+
+    pub(crate) fn resume(&self, old_id: ConnectionId, new_id: ConnectionId) {
+        if let Some(on_resume) = self.on_resume.as_ref() {
+            on_resume(old_id, new_id);
+        }
+    }
+
+    pub(crate) fn has_resume(&self) -> bool {
+        self.on_resume.is_some()
+    }
+
+    pub(crate) fn send(&self, data: &mut Vec<u8>, delivery: Delivery) {
+        if let Some(on_send) = self.on_send.as_ref() {
+            on_send(data, delivery);
+        }
+    }
+
+    pub(crate) fn receive(&self, data: &mut Vec<u8>, delivery: Delivery) {
+        if let Some(on_receive) = self.on_receive.as_ref() {
+            on_receive(data, delivery);
+        }
+    }
+}
+
+/// Tokens of recently closed connections, used to recognize clients resuming their session.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    closed: Mutex<HashMap<Vec<u8>, (ConnectionId, Instant)>>,
+}
+
+impl Sessions {
+    pub fn close(&self, token: Vec<u8>, id: ConnectionId, window: Duration) {
+        if token.is_empty() {
+            return;
+        }
+
+        let mut closed = self.closed.lock().unwrap();
+        closed.retain(|_, (_, at)| at.elapsed() <= window);
+        closed.insert(token, (id, Instant::now()));
+    }
+
+    /// Removes the session with the token, returning its connection id if it was closed within the window.
+    pub fn take(&self, token: &[u8], window: Duration) -> Option<ConnectionId> {
+        self.closed
+            .lock()
+            .unwrap()
+            .remove(token)
+            .filter(|(_, at)| at.elapsed() <= window)
+            .map(|(id, _)| id)
+    }
+}
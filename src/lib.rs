@@ -8,33 +8,140 @@
 //! The Future represents the computation that drives the transport protocol.
 //! It attempts to perfom a handshake which establishes a connection between the client and the server.
 //! When the Future is running (for example with a Tokio runtime), you can start sending messages on the [`Sender`] and receiving events on the [`Receiver`].
+//!
+//! Instead of handling the events of every connection on the server's [`Receiver`], a [`Listener`] can accept connections one at a time,
+//! each with its own [`ConnectionHandle`] to send and receive on.
 
 #[derive(Debug, Clone, Copy)]
 pub enum Delivery {
     /// The message is guaranteed to reach the recipient (server or client).
     /// It is also encrypted if the `rustls` feature is enabled.
+    ///
+    /// Reliable messages share a single TCP stream and are delivered in the order they were sent, regardless of their [`Header::channel`].
+    /// A large message therefore delays every message sent after it until it has been received in full (head-of-line blocking).
+    /// Prefer [`Delivery::Unreliable`] for small, frequent updates that are superseded by later ones.
     Reliable,
     /// The message is not guaranteed to reach the recipient (server or client), nor is it guaranteed to arrive in order or once.
     Unreliable,
+    /// Like [`Delivery::Unreliable`], but a message is dropped if a sequenced message sent after it was already delivered, so only newer messages are received.
+    /// Suited for state updates where a stale update must not be applied after a newer one.
+    ///
+    /// All sequenced messages in the same direction of a connection share one sequence, regardless of their content.
+    /// If the peer does not support [`Features::SEQUENCING`], the message is sent as [`Delivery::Unreliable`].
+    UnreliableSequenced,
+}
+
+/// Order in which messages waiting for the client or server task are written, see [`ServerSender::send_with_priority`] and [`ClientSender::send_with_priority`].
+/// Messages of the same priority are written in the order they were sent. Messages already written to the sockets are not reordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+}
+
+/// Why a connection ended, carried by [`ClientEvent::Disconnected`] and [`ServerEvent::Disconnected`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// This side closed the connection, with [`ClientSender::disconnect`], [`ServerSender::disconnect`], a [`Disconnector`] or [`ServerSender::shutdown`].
+    ClosedLocally,
+    /// The peer closed the connection. For a client this means the server disconnected it, for instance by kicking it or shutting down.
+    ClosedByPeer,
+    /// Nothing was received within [`Config::idle_timeout`], or the stream timed out.
+    TimedOut,
+    /// The server rejected the token of the client.
+    InvalidToken,
+    /// The peer sent a frame that could not be read, see [`ProtocolErrorKind::MalformedFrame`].
+    MalformedFrame,
+    /// The TLS session failed, only with the `rustls` feature.
+    Tls,
+    /// Reading from or writing to the stream failed.
+    Io(std::io::ErrorKind),
 }
 
 mod connection;
 use connection::Connection;
-pub type ConnectionId = u32;
+
+/// Identifies a connection on the server.
+/// The slot of a connection is reused once it closes, so the id also carries a generation: an id kept after its connection closed
+/// is rejected (for instance with [`SendError::UnknownConnection`]) instead of reaching the client that took over the slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId {
+    index: u32,
+    generation: u32,
+}
+
+impl ConnectionId {
+    pub(crate) fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    /// Slot of the connection, unique among the open connections but reused after they close.
+    /// Small and dense, so it is suitable for indexing per-connection state.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.index, self.generation)
+    }
+}
 
 mod client;
+mod clock;
+#[cfg(feature = "lz4")]
+mod compression;
 mod config;
+mod datagram;
 mod disconnector;
+mod features;
+mod framing;
+mod header;
+mod hooks;
+mod limiter;
+mod listener;
+#[cfg(feature = "memory")]
+mod memory;
+mod netsim;
 mod receiver;
+mod registry;
+mod replay;
 mod sender;
 mod server;
+mod span;
+#[cfg(feature = "rustls")]
+mod tls;
 
-pub use config::Config;
+#[cfg(feature = "lz4")]
+pub use compression::Compression;
+pub use config::{
+    Config, ConfigBuilder, ConfigError, ConnectionRate, DuplicateKey, ReconnectPolicy,
+};
+pub use features::Features;
+pub use header::Header;
+pub use hooks::Hooks;
+pub use listener::{ConnectionHandle, Listener, ReceivedMessage};
+#[cfg(feature = "memory")]
+pub use memory::{Link, Network};
+pub use netsim::NetSim;
 
-pub use receiver::{Receiver, RecvError};
+pub use receiver::{Overflow, Receiver, RecvError};
+pub use registry::{ConnectionInfo, ConnectionStats, ProtocolError, ProtocolErrorKind};
 pub use sender::{SendError, Sender};
+#[cfg(feature = "rustls")]
+pub use tls::TlsInfo;
 
-pub use client::{Client, ClientEvent, ClientReceiver, ClientSender};
+pub use client::{
+    Client, ClientCommand, ClientError, ClientEvent, ClientHandle, ClientReceiver, ClientSender,
+    ConnectionState,
+};
 pub use server::{
-    DisconnectError, Disconnector, Server, ServerEvent, ServerReceiver, ServerSender,
+    DisconnectError, Disconnector, Server, ServerCommand, ServerError, ServerEvent, ServerReceiver,
+    ServerSender,
 };
@@ -0,0 +1,218 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::{ConnectionRate, Overflow};
+
+/// Number of tracked addresses at which buckets that have refilled completely are dropped.
+const MIN_PRUNE_SIZE: usize = 1024;
+
+/// A token bucket, refilled continuously at a fixed rate.
+/// Unless created with [`TokenBucket::with_capacity`], the bucket holds at most one second worth of tokens.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        Self::with_capacity(rate as f64, rate as f64)
+    }
+
+    pub fn with_capacity(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+
+    /// Attempts to take `amount` tokens from the bucket.
+    /// A full bucket always grants the request, so amounts larger than the capacity are let through and paid back over time.
+    pub fn try_consume(&mut self, amount: usize) -> bool {
+        self.refill();
+
+        let amount = amount as f64;
+        if self.tokens >= amount || self.is_full() {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Outcome of pacing a reliable frame, see [`Pacer::reliable`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Paced {
+    /// The frame can be written right away.
+    Ready(Arc<[u8]>),
+    /// The frame waits for the send rate, see [`Pacer::pop_ready`].
+    Queued,
+    /// The frame exceeds the send rate and was dropped.
+    Dropped,
+}
+
+/// Paces outbound reliable frames according to an optional send rate.
+/// Frames are shared, so a frame sent to several connections is built once, see [`Connection::frame`](crate::connection::Connection::frame).
+#[derive(Debug, Default)]
+pub struct Pacer {
+    limiter: Option<TokenBucket>,
+    queue: VecDeque<Arc<[u8]>>,
+}
+
+impl Pacer {
+    pub fn set_rate(&mut self, bytes_per_sec: Option<u32>) {
+        self.limiter = bytes_per_sec.map(TokenBucket::new);
+    }
+
+    fn acquire(&mut self, size: usize) -> bool {
+        self.limiter
+            .as_mut()
+            .map(|limiter| limiter.try_consume(size))
+            .unwrap_or(true)
+    }
+
+    /// Returns true if an unreliable message of the given size fits within the send rate.
+    pub fn unreliable(&mut self, size: usize) -> bool {
+        self.acquire(size)
+    }
+
+    /// Returns the reliable frame if it can be written immediately, otherwise it is queued behind any previously queued frames
+    /// or dropped, depending on `overflow`.
+    pub fn reliable(&mut self, data: Arc<[u8]>, overflow: Overflow) -> Paced {
+        if self.queue.is_empty() && self.acquire(data.len()) {
+            Paced::Ready(data)
+        } else if overflow == Overflow::DropNewest {
+            Paced::Dropped
+        } else {
+            self.queue.push_back(data);
+            Paced::Queued
+        }
+    }
+
+    /// Number of reliable frames waiting for the send rate.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Takes all queued reliable frames regardless of the send rate, for instance to write them before the connection closes.
+    pub fn drain(&mut self) -> VecDeque<Arc<[u8]>> {
+        std::mem::take(&mut self.queue)
+    }
+
+    /// Returns the next queued reliable frame if the send rate allows it to be written.
+    pub fn pop_ready(&mut self) -> Option<Arc<[u8]>> {
+        let size = self.queue.front()?.len();
+        if self.acquire(size) {
+            self.queue.pop_front()
+        } else {
+            None
+        }
+    }
+}
+
+/// Limits the rate of new connections from each IP address, see [`Config::connection_rate`](crate::Config::connection_rate).
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    rate: ConnectionRate,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    prune_at: usize,
+}
+
+impl ConnectionLimiter {
+    pub fn new(rate: ConnectionRate) -> Self {
+        Self {
+            rate,
+            buckets: HashMap::new(),
+            prune_at: MIN_PRUNE_SIZE,
+        }
+    }
+
+    /// Returns true if a new connection from the address is within the rate.
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        if self.buckets.len() >= self.prune_at {
+            // A full bucket behaves like a new one, so it can be dropped.
+            self.buckets.retain(|_, bucket| {
+                bucket.refill();
+                !bucket.is_full()
+            });
+            self.prune_at = (self.buckets.len() * 2).max(MIN_PRUNE_SIZE);
+        }
+
+        let rate = self.rate;
+        self.buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::with_capacity(rate.per_sec, rate.burst as f64))
+            .try_consume(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(byte: u8, size: usize) -> Arc<[u8]> {
+        vec![byte; size].into()
+    }
+
+    /// A pacer whose budget is spent by a first frame of `size` bytes.
+    fn exhausted(size: usize) -> Pacer {
+        let mut pacer = Pacer::default();
+        pacer.set_rate(Some(size as u32));
+        assert_eq!(
+            pacer.reliable(frame(0, size), Overflow::Block),
+            Paced::Ready(frame(0, size))
+        );
+        pacer
+    }
+
+    #[test]
+    fn queues_frames_over_the_rate() {
+        let mut pacer = exhausted(1000);
+        assert_eq!(pacer.reliable(frame(1, 1), Overflow::Block), Paced::Queued);
+        assert_eq!(pacer.reliable(frame(2, 1), Overflow::Block), Paced::Queued);
+        assert_eq!(pacer.queued(), 2);
+        assert_eq!(pacer.drain(), [frame(1, 1), frame(2, 1)]);
+    }
+
+    #[test]
+    fn drops_frames_over_the_rate() {
+        let mut pacer = exhausted(1000);
+        assert_eq!(
+            pacer.reliable(frame(1, 1), Overflow::DropNewest),
+            Paced::Dropped
+        );
+        assert_eq!(pacer.queued(), 0);
+    }
+
+    #[test]
+    fn dropping_keeps_frames_already_queued() {
+        let mut pacer = exhausted(1000);
+        assert_eq!(pacer.reliable(frame(1, 1), Overflow::Block), Paced::Queued);
+        // Frames queued before, such as those sent with a receipt, are still written.
+        assert_eq!(
+            pacer.reliable(frame(2, 1), Overflow::DropNewest),
+            Paced::Dropped
+        );
+        assert_eq!(pacer.drain(), [frame(1, 1)]);
+    }
+}
@@ -0,0 +1,192 @@
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{
+    receiver::{self, InnerSender},
+    ConnectionId, Delivery, DisconnectReason, Header, Receiver, RecvError, SendError, ServerEvent,
+    ServerReceiver, ServerSender,
+};
+
+/// Reason a connection closed, set by the routing future before the messages of the connection end.
+type SharedReason = Arc<Mutex<Option<DisconnectReason>>>;
+
+/// A message received on a [`ConnectionHandle`], with the fields of [`ServerEvent::Received`].
+#[derive(Debug, Clone)]
+pub struct ReceivedMessage {
+    pub header: Header,
+    pub data: Vec<u8>,
+    pub received_at: Instant,
+}
+
+impl receiver::Timestamped for ReceivedMessage {
+    fn received_at(&self) -> Option<Instant> {
+        Some(self.received_at)
+    }
+}
+
+/// Accepts connections one at a time, each with its own [`ConnectionHandle`], as an alternative to handling the events of every connection on one [`ServerReceiver`].
+///
+/// The events are routed to the handles by a future returned alongside the listener, which must be running (like the server task) for connections to be accepted and messages received.
+/// Only the connection, message and disconnect events are routed, the other [`ServerEvent`]s are dropped. Use the [`ServerReceiver`] directly to handle them.
+#[derive(Debug)]
+pub struct Listener<U> {
+    accepted: UnboundedReceiver<ConnectionHandle<U>>,
+}
+
+impl<U: Send + Sync + Clone + 'static> Listener<U> {
+    /// Creates a listener from the sender and receiver of a server, returning it together with the future that routes the events of the receiver.
+    /// The messages of each connection are queued like events, see [`Config::event_capacity`](crate::Config::event_capacity) and [`Config::event_overflow`](crate::Config::event_overflow).
+    pub fn new(
+        sender: ServerSender<U>,
+        receiver: ServerReceiver<U>,
+    ) -> (Self, impl Future<Output = ()>) {
+        let (accepted_sender, accepted) = unbounded();
+
+        (Self { accepted }, route(sender, receiver, accepted_sender))
+    }
+
+    /// Waits for the next established connection. Connections established before are queued until they are accepted.
+    /// Returns [`None`] once the server task has ended, or the routing future was dropped.
+    pub async fn accept(&mut self) -> Option<ConnectionHandle<U>> {
+        self.accepted.next().await
+    }
+}
+
+/// An established connection accepted by a [`Listener`], owning the messages received from the client.
+/// Dropping the handle closes the connection, like [`ConnectionHandle::close`].
+#[derive(Debug)]
+pub struct ConnectionHandle<U> {
+    id: ConnectionId,
+    claim: U,
+    peer_address: SocketAddr,
+    sender: ServerSender<U>,
+    receiver: Receiver<ReceivedMessage>,
+    reason: SharedReason,
+}
+
+impl<U> ConnectionHandle<U> {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// The claim returned by the token validation function when the connection was established.
+    pub fn claim(&self) -> &U {
+        &self.claim
+    }
+
+    /// Remote address of the reliable (TCP) stream.
+    pub fn peer_address(&self) -> SocketAddr {
+        self.peer_address
+    }
+
+    /// Send data to the client, see [`ServerSender::send`].
+    pub fn send<D: Into<Vec<u8>>>(&self, data: D, delivery: Delivery) -> Result<(), SendError> {
+        self.sender.send(self.id, data, delivery)
+    }
+
+    /// Send data to the client with reliable delivery.
+    pub fn reliable<D: Into<Vec<u8>>>(&self, data: D) -> Result<(), SendError> {
+        self.sender.reliable(self.id, data)
+    }
+
+    /// Send data to the client with unreliable delivery.
+    pub fn unreliable<D: Into<Vec<u8>>>(&self, data: D) -> Result<(), SendError> {
+        self.sender.unreliable(self.id, data)
+    }
+
+    /// Asynchronously receive a message from the client, returns [`None`] once the connection has closed and every message was received.
+    pub async fn recv(&mut self) -> Option<ReceivedMessage> {
+        self.receiver.recv().await
+    }
+
+    /// Attempts to receive a message from the client. This function is non-blocking.
+    pub fn try_recv(&mut self) -> Result<ReceivedMessage, RecvError> {
+        self.receiver.try_recv()
+    }
+
+    /// Why the connection closed, or [`None`] while it is open.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        *self.reason.lock().unwrap()
+    }
+
+    /// Close the connection, see [`ServerSender::disconnect`].
+    pub fn close(&self) -> Result<(), SendError> {
+        self.sender.disconnect(self.id)
+    }
+}
+
+impl<U> Drop for ConnectionHandle<U> {
+    fn drop(&mut self) {
+        if self.disconnect_reason().is_none() {
+            let _ = self.sender.disconnect(self.id);
+        }
+    }
+}
+
+/// Routes the events of a server to the handles of their connections, until the server task has ended.
+async fn route<U: Send + Sync + Clone + 'static>(
+    sender: ServerSender<U>,
+    mut receiver: ServerReceiver<U>,
+    accepted: UnboundedSender<ConnectionHandle<U>>,
+) {
+    let config = sender.config();
+    let mut connections: HashMap<ConnectionId, (InnerSender<ReceivedMessage>, SharedReason)> =
+        HashMap::new();
+
+    while let Some(event) = receiver.recv().await {
+        match event {
+            ServerEvent::Connected {
+                id,
+                claim,
+                peer_address,
+            } => {
+                let (message_sender, message_receiver) =
+                    receiver::channel::<ReceivedMessage>(config.event_capacity);
+                let reason = Arc::new(Mutex::new(None));
+                connections.insert(id, (message_sender, reason.clone()));
+
+                // A handle that cannot be accepted is dropped, which closes its connection.
+                let _ = accepted.unbounded_send(ConnectionHandle {
+                    id,
+                    claim,
+                    peer_address,
+                    sender: sender.clone(),
+                    receiver: Receiver::new(message_receiver).max_age(config.max_event_age),
+                    reason,
+                });
+            }
+            ServerEvent::Received {
+                id,
+                header,
+                data,
+                received_at,
+            } => {
+                if let Some((message_sender, _)) = connections.get_mut(&id) {
+                    let message = ReceivedMessage {
+                        header,
+                        data,
+                        received_at,
+                    };
+                    // The handle was dropped, its connection is closing.
+                    let _ =
+                        receiver::dispatch(message_sender, message, config.event_overflow).await;
+                }
+            }
+            ServerEvent::Disconnected { id, reason } => {
+                if let Some((_, disconnect_reason)) = connections.remove(&id) {
+                    *disconnect_reason.lock().unwrap() = Some(reason);
+                }
+            }
+            _ => {}
+        }
+    }
+}
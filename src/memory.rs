@@ -0,0 +1,504 @@
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedSender},
+        oneshot,
+    },
+    StreamExt,
+};
+use slab::Slab;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::{sleep_until, Instant};
+
+use crate::{
+    client::ClientState,
+    disconnector::BlockList,
+    receiver,
+    registry::{Record, Registry},
+    sender,
+    server::dispatch,
+    ClientCommand, ClientError, ClientEvent, ClientReceiver, ClientSender, Config, ConnectionId,
+    ConnectionState, Delivery, DisconnectReason, Disconnector, Header, Receiver, SendError, Sender,
+    ServerCommand, ServerError, ServerEvent, ServerReceiver, ServerSender,
+};
+
+/// Conditions simulated for every message passed over a [`Network`], in either direction.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Link {
+    /// Time from a message being written by a task until it is read by the other end.
+    pub latency: Duration,
+    /// Probability (`0.0..=1.0`) that an unreliable message is dropped. Reliable messages are never dropped.
+    pub loss: f32,
+}
+
+/// What is passed between the tasks, each direction of a connection preserving the order.
+#[derive(Debug)]
+enum Packet {
+    /// A message, with the reply of [`ClientSender::reliable_with_ack`](crate::ClientSender::reliable_with_ack) which is sent once the message is read.
+    Message {
+        header: Header,
+        data: Vec<u8>,
+        receipt: Option<oneshot::Sender<Result<(), SendError>>>,
+    },
+    Close,
+}
+
+/// Packets in flight to a client, each with the time it arrives.
+type PacketSender = UnboundedSender<(Instant, Packet)>;
+/// Packets in flight to the server, each with the time it arrives and the connection it arrives on.
+type ServerPacketSender = UnboundedSender<(Instant, ConnectionId, Packet)>;
+
+/// A connection request from a client task.
+struct Accept {
+    token: Vec<u8>,
+    to_client: PacketSender,
+    reply: oneshot::Sender<Result<(ConnectionId, ServerPacketSender), ClientError>>,
+}
+
+impl std::fmt::Debug for Accept {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Accept").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    link: Link,
+    listener: Mutex<Option<UnboundedSender<Accept>>>,
+}
+
+/// An in-memory transport for testing applications without binding sockets, enabled with the `memory` feature.
+///
+/// A network stands in for the addresses of [`Server::listen`](crate::Server::listen) and [`Client::connect`](crate::Client::connect):
+/// it returns the same senders, receivers and tasks, and the tasks dispatch the same events, but messages are passed through channels
+/// after the simulated [`Link::latency`], and unreliable messages are dropped with a probability of [`Link::loss`].
+/// Arrival times are kept with the Tokio clock, so tests can run without waiting by pausing time (`tokio::time::pause`).
+///
+/// There is no handshake on the wire, so the options of [`Config`] that concern the sockets, framing, datagrams or timers are ignored,
+/// as are send rates and corking. Round trip times are not measured, [`ClientSender::ping`](crate::ClientSender::ping) returns twice the latency.
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    inner: Arc<Inner>,
+}
+
+impl Network {
+    pub fn new(link: Link) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                link,
+                listener: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn link(&self) -> Link {
+        self.inner.link
+    }
+
+    /// Start a server on the network, like [`Server::listen`](crate::Server::listen).
+    /// Clients connected before the server task runs fail with [`ClientError::Unreachable`], and a server started later takes over the network from the previous one.
+    pub fn listen<
+        U: Send + Sync + Clone + 'static,
+        F: Fn(Vec<u8>) -> Option<U> + Send + Sync + Clone + 'static,
+    >(
+        &self,
+        config: Config,
+        validation_fn: F,
+    ) -> (
+        ServerSender<U>,
+        ServerReceiver<U>,
+        Disconnector<U>,
+        impl Future<Output = Result<(), ServerError>>,
+    ) {
+        let validated = config.validate();
+        let (disconnect_sender, disconnect_receiver) = sender::channel::<ConnectionId>();
+        let block_list = Arc::new(BlockList::default());
+        let registry = Arc::new(Registry::default());
+        let (outbound_sender, outbound_receiver) = sender::priority_channel::<ServerCommand>();
+        let (inbound_sender, inbound_receiver) =
+            receiver::channel::<ServerEvent<U>>(config.event_capacity);
+        let max_event_age = config.max_event_age;
+
+        let task = Self::server_task(
+            self.inner.clone(),
+            config,
+            inbound_sender,
+            outbound_receiver,
+            disconnect_receiver,
+            block_list.clone(),
+            registry.clone(),
+            validation_fn,
+        );
+
+        let receiver = Receiver::new(inbound_receiver).max_age(max_event_age);
+        let close_on_drop = receiver.close_on_drop();
+        let task = async move {
+            let _close_on_drop = close_on_drop;
+            validated?;
+            task.await
+        };
+
+        (
+            Sender::new(
+                outbound_sender,
+                registry.clone(),
+                config,
+                Default::default(),
+            ),
+            receiver,
+            Disconnector::new(disconnect_sender, block_list, registry),
+            task,
+        )
+    }
+
+    /// Connect to the server of the network, like [`Client::connect`](crate::Client::connect).
+    pub fn connect(
+        &self,
+        config: Config,
+        token: Vec<u8>,
+    ) -> (
+        ClientSender,
+        ClientReceiver,
+        impl Future<Output = Result<(), ClientError>>,
+    ) {
+        let validated = config.validate();
+        let (outbound_sender, outbound_receiver) = sender::priority_channel::<ClientCommand>();
+        let (inbound_sender, inbound_receiver) =
+            receiver::channel::<ClientEvent>(config.event_capacity);
+        let max_event_age = config.max_event_age;
+        let state = Arc::new(ClientState::default());
+
+        let task = Self::client_task(
+            self.inner.clone(),
+            config,
+            token,
+            inbound_sender,
+            outbound_receiver,
+            state.clone(),
+        );
+
+        let receiver = Receiver::new(inbound_receiver).max_age(max_event_age);
+        let close_on_drop = receiver.close_on_drop();
+        let task = async move {
+            let _close_on_drop = close_on_drop;
+            validated?;
+            task.await
+        };
+
+        (
+            Sender::new(outbound_sender, state, config, Default::default()),
+            receiver,
+            task,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn server_task<
+        U: Send + Sync + Clone + 'static,
+        F: Fn(Vec<u8>) -> Option<U> + Send + Sync + Clone + 'static,
+    >(
+        network: Arc<Inner>,
+        config: Config,
+        mut inbound_sender: receiver::InnerSender<ServerEvent<U>>,
+        mut outbound_receiver: sender::PriorityReceiver<ServerCommand>,
+        mut disconnect_receiver: sender::InnerReceiver<ConnectionId>,
+        block_list: Arc<BlockList>,
+        registry: Arc<Registry<U>>,
+        validation_fn: F,
+    ) -> Result<(), ServerError> {
+        let link = network.link;
+        let (accept_sender, mut accept_receiver) = unbounded::<Accept>();
+        *network.listener.lock().unwrap() = Some(accept_sender);
+
+        let (to_server, mut from_clients) = unbounded::<(Instant, ConnectionId, Packet)>();
+        let mut in_flight = VecDeque::new();
+        let mut connections: Slab<PacketSender> = Slab::new();
+        let mut next_generation: u32 = 0;
+        let mut next_port: u16 = 0;
+
+        loop {
+            let arrival = in_flight
+                .front()
+                .map_or_else(Instant::now, |(arrival, _, _)| *arrival);
+
+            tokio::select! {
+                Some(accept) = accept_receiver.next() => {
+                    let Accept { token, to_client, reply } = accept;
+                    next_port = next_port.wrapping_add(1);
+                    let peer_address = SocketAddr::from((Ipv4Addr::LOCALHOST, next_port));
+
+                    if block_list.contains(peer_address.ip()) {
+                        continue;
+                    }
+                    if config.max_connections.is_some_and(|max_connections| connections.len() >= max_connections) {
+                        let _ = reply.send(Err(ClientError::Rejected));
+                        continue;
+                    }
+
+                    let claim = match validation_fn(token) {
+                        Some(claim) => claim,
+                        None => {
+                            let _ = reply.send(Err(ClientError::InvalidToken));
+                            continue;
+                        }
+                    };
+
+                    let index = connections.insert(to_client) as u32;
+                    next_generation = next_generation.wrapping_add(1);
+                    let id = ConnectionId::new(index, next_generation);
+                    let record = registry.insert(Record::new(id, peer_address, config.recent_errors_capacity));
+                    record.set_established();
+
+                    if reply.send(Ok((id, to_server.clone()))).is_err() {
+                        connections.remove(index as usize);
+                        registry.remove(id);
+                        continue;
+                    }
+                    dispatch(&mut inbound_sender, ServerEvent::Connected { id, claim, peer_address }, &config).await;
+                },
+                Some((arrival, id, packet)) = from_clients.next() => {
+                    in_flight.push_back((arrival, id, packet));
+                },
+                _ = sleep_until(arrival), if !in_flight.is_empty() => {
+                    let now = Instant::now();
+                    while in_flight.front().is_some_and(|(arrival, _, _)| *arrival <= now) {
+                        let (_, id, packet) = in_flight.pop_front().unwrap();
+                        let record = match registry.get(id) {
+                            Some(record) => record,
+                            None => continue,
+                        };
+
+                        match packet {
+                            Packet::Message { header, data, receipt } => {
+                                record.seen();
+                                record.received(1, data.len());
+                                if record.is_receive_only() {
+                                    continue;
+                                }
+                                dispatch(&mut inbound_sender, ServerEvent::Received { id, header, data, received_at: now.into_std() }, &config).await;
+                                if let Some(receipt) = receipt {
+                                    let _ = receipt.send(Ok(()));
+                                }
+                            },
+                            Packet::Close => {
+                                connections.remove(id.index() as usize);
+                                registry.remove(id);
+                                dispatch(&mut inbound_sender, ServerEvent::Disconnected { id, reason: DisconnectReason::ClosedByPeer }, &config).await;
+                            },
+                        }
+                    }
+                },
+                Some(command) = outbound_receiver.next() => {
+                    let (ids, header, data, delivery, mut receipt) = match command {
+                        ServerCommand::Send { id, header, data, delivery } => (vec![id], header, data, delivery, None),
+                        ServerCommand::SendWithReceipt { id, header, data, reply } => (vec![id], header, data, Delivery::Reliable, Some(reply)),
+                        ServerCommand::Multicast { ids, header, data, delivery } => (ids, header, data, delivery, None),
+                        ServerCommand::Disconnect { id } => {
+                            close(id, link, &mut connections, &registry, &mut inbound_sender, &config).await;
+                            continue;
+                        },
+                        ServerCommand::Shutdown => {
+                            let ids: Vec<_> = connections
+                                .iter()
+                                .filter_map(|(index, _)| registry.slot(index as u32))
+                                .map(|record| record.id())
+                                .collect();
+
+                            for id in ids {
+                                close(id, link, &mut connections, &registry, &mut inbound_sender, &config).await;
+                            }
+                            return Ok(());
+                        },
+                        ServerCommand::SetSendRate { .. } | ServerCommand::Cork { .. } | ServerCommand::Uncork { .. } => continue,
+                        // Messages are handed to the link as they are sent, so there is nothing to write.
+                        ServerCommand::Flush { id, reply } => {
+                            let _ = reply.send(registry.get(id).map(|_| ()).ok_or(SendError::UnknownConnection));
+                            continue;
+                        },
+                    };
+
+                    for id in ids {
+                        let record = match registry.get(id) {
+                            Some(record) => record,
+                            None => continue,
+                        };
+                        record.queued().pop();
+
+                        if let Some(to_client) = connections.get(id.index() as usize) {
+                            record.sent(1, data.len());
+                            if !is_lost(link, delivery) {
+                                let packet = Packet::Message { header, data: data.clone(), receipt: receipt.take() };
+                                let _ = to_client.unbounded_send((Instant::now() + link.latency, packet));
+                            }
+                        }
+                    }
+                },
+                Some(id) = disconnect_receiver.next() => {
+                    close(id, link, &mut connections, &registry, &mut inbound_sender, &config).await;
+                },
+                else => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn client_task(
+        network: Arc<Inner>,
+        config: Config,
+        token: Vec<u8>,
+        mut inbound_sender: receiver::InnerSender<ClientEvent>,
+        mut outbound_receiver: sender::PriorityReceiver<ClientCommand>,
+        state: Arc<ClientState>,
+    ) -> Result<(), ClientError> {
+        let link = network.link;
+        let _disconnected = state.disconnect_on_drop();
+        receiver::dispatch(
+            &mut inbound_sender,
+            ClientEvent::Connecting,
+            config.event_overflow,
+        )
+        .await?;
+
+        let unreachable = || ClientError::Unreachable(io::ErrorKind::ConnectionRefused.into());
+        let listener = network
+            .listener
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(unreachable)?;
+
+        let (to_client, mut from_server) = unbounded::<(Instant, Packet)>();
+        let (reply, replied) = oneshot::channel();
+        listener
+            .unbounded_send(Accept {
+                token,
+                to_client,
+                reply,
+            })
+            .map_err(|_| unreachable())?;
+        let (id, to_server) = replied.await.map_err(|_| unreachable())??;
+        state.set_connection_state(ConnectionState::Connected);
+
+        receiver::dispatch(
+            &mut inbound_sender,
+            ClientEvent::Connected,
+            config.event_overflow,
+        )
+        .await?;
+
+        let mut in_flight = VecDeque::new();
+        let mut ended = false;
+
+        loop {
+            // The server task ended without closing the connection, once the packets in flight are read the connection is lost, as if it was aborted.
+            if ended && in_flight.is_empty() {
+                receiver::dispatch(
+                    &mut inbound_sender,
+                    ClientEvent::Disconnected {
+                        reason: DisconnectReason::Io(io::ErrorKind::ConnectionAborted),
+                    },
+                    config.event_overflow,
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let arrival = in_flight
+                .front()
+                .map_or_else(Instant::now, |(arrival, _)| *arrival);
+
+            tokio::select! {
+                arrived = from_server.next(), if !ended => match arrived {
+                    Some((arrival, packet)) => in_flight.push_back((arrival, packet)),
+                    None => ended = true,
+                },
+                _ = sleep_until(arrival), if !in_flight.is_empty() => {
+                    let now = Instant::now();
+                    while in_flight.front().is_some_and(|(arrival, _)| *arrival <= now) {
+                        match in_flight.pop_front().unwrap().1 {
+                            Packet::Message { header, data, receipt } => {
+                                receiver::dispatch(&mut inbound_sender, ClientEvent::Received { header, data, received_at: now.into_std() }, config.event_overflow).await?;
+                                if let Some(receipt) = receipt {
+                                    let _ = receipt.send(Ok(()));
+                                }
+                            },
+                            Packet::Close => {
+                                receiver::dispatch(&mut inbound_sender, ClientEvent::Disconnected { reason: DisconnectReason::ClosedByPeer }, config.event_overflow).await?;
+                                return Ok(());
+                            },
+                        }
+                    }
+                },
+                Some(command) = outbound_receiver.next() => match command {
+                    ClientCommand::Send { header, data, delivery } => {
+                        state.queued.pop();
+                        if !is_lost(link, delivery) {
+                            let packet = Packet::Message { header, data, receipt: None };
+                            let _ = to_server.unbounded_send((Instant::now() + link.latency, id, packet));
+                        }
+                    },
+                    ClientCommand::SendWithReceipt { header, data, reply } => {
+                        state.queued.pop();
+                        let packet = Packet::Message { header, data, receipt: Some(reply) };
+                        let _ = to_server.unbounded_send((Instant::now() + link.latency, id, packet));
+                    },
+                    ClientCommand::Ping { reply } => {
+                        let _ = reply.send(Ok(link.latency * 2));
+                    },
+                    ClientCommand::Flush { reply } => {
+                        let _ = reply.send(Ok(()));
+                    },
+                    ClientCommand::Disconnect => {
+                        let _ = to_server.unbounded_send((Instant::now() + link.latency, id, Packet::Close));
+                        receiver::dispatch(&mut inbound_sender, ClientEvent::Disconnected { reason: DisconnectReason::ClosedLocally }, config.event_overflow).await?;
+                        return Ok(());
+                    },
+                },
+            }
+        }
+    }
+}
+
+/// Whether a message is dropped by the link.
+fn is_lost(link: Link, delivery: Delivery) -> bool {
+    matches!(
+        delivery,
+        Delivery::Unreliable | Delivery::UnreliableSequenced
+    ) && rand::random::<f32>() < link.loss
+}
+
+/// Closes a connection from the server side, the client reading the close after the latency of the link.
+async fn close<U: Send + Sync + Clone>(
+    id: ConnectionId,
+    link: Link,
+    connections: &mut Slab<PacketSender>,
+    registry: &Registry<U>,
+    inbound_sender: &mut receiver::InnerSender<ServerEvent<U>>,
+    config: &Config,
+) {
+    // A stale id must not close the connection that took over the slot.
+    if registry.get(id).is_none() {
+        return;
+    }
+
+    if let Some(to_client) = connections.try_remove(id.index() as usize) {
+        let _ = to_client.unbounded_send((Instant::now() + link.latency, Packet::Close));
+    }
+    registry.remove(id);
+    dispatch(
+        inbound_sender,
+        ServerEvent::Disconnected {
+            id,
+            reason: DisconnectReason::ClosedLocally,
+        },
+        config,
+    )
+    .await;
+}
@@ -0,0 +1,138 @@
+use std::{cmp::Ordering, collections::BinaryHeap, io, net::SocketAddr, time::Duration};
+use tokio::{net::UdpSocket, time::Instant};
+
+/// Network conditions simulated on the unreliable datagrams sent by a client or server, see [`Config::network_simulation`](crate::Config::network_simulation).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NetSim {
+    /// Delay added to every datagram.
+    pub latency: Duration,
+    /// Upper bound of a random delay added on top of the latency, which lets datagrams arrive out of order.
+    pub jitter: Duration,
+    /// Probability (`0.0..=1.0`) that a datagram is dropped.
+    pub loss: f32,
+}
+
+impl NetSim {
+    pub(crate) fn is_valid(&self) -> bool {
+        (0.0..=1.0).contains(&self.loss)
+    }
+}
+
+/// A datagram held back until it is due, `to` is [`None`] for the peer of a connected socket.
+#[derive(Debug)]
+struct Delayed {
+    due: Instant,
+    sequence: u64,
+    bytes: Vec<u8>,
+    to: Option<SocketAddr>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Reversed, so the heap yields the datagram that is due first, and datagrams due at the same time in the order they were sent.
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.due, other.sequence).cmp(&(self.due, self.sequence))
+    }
+}
+
+/// Drops and delays outgoing datagrams according to a [`NetSim`].
+#[derive(Debug)]
+pub struct Simulator {
+    netsim: NetSim,
+    delayed: BinaryHeap<Delayed>,
+    sequence: u64,
+}
+
+impl Simulator {
+    pub fn new(netsim: NetSim) -> Self {
+        Self {
+            netsim,
+            delayed: BinaryHeap::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Time the next datagram is due, if any are held back.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.delayed.peek().map(|delayed| delayed.due)
+    }
+
+    fn push(&mut self, bytes: Vec<u8>, to: Option<SocketAddr>) {
+        if rand::random::<f32>() < self.netsim.loss {
+            return;
+        }
+
+        let jitter = self.netsim.jitter.mul_f64(rand::random::<f64>());
+        self.sequence += 1;
+        self.delayed.push(Delayed {
+            due: Instant::now() + self.netsim.latency + jitter,
+            sequence: self.sequence,
+            bytes,
+            to,
+        });
+    }
+
+    /// Sends the datagrams that are due.
+    pub async fn flush(&mut self, socket: &UdpSocket) {
+        let now = Instant::now();
+        while self
+            .delayed
+            .peek()
+            .is_some_and(|delayed| delayed.due <= now)
+        {
+            let delayed = self.delayed.pop().unwrap();
+            if let Err(err) = send_now(socket, &delayed.bytes, delayed.to).await {
+                log::debug!("Error writing delayed datagram (UDP): {}", err);
+            }
+        }
+    }
+}
+
+/// Sends a datagram to `to`, or to the peer of a connected socket if it is [`None`].
+/// With a [`Simulator`] the datagram is handed to it instead, and counts as written in full.
+/// A datagram the socket only wrote in part is reported as an error, since the peer cannot authenticate it.
+pub async fn send(
+    socket: &UdpSocket,
+    simulator: &mut Option<Simulator>,
+    bytes: Vec<u8>,
+    to: Option<SocketAddr>,
+) -> io::Result<usize> {
+    match simulator {
+        Some(simulator) => {
+            let size = bytes.len();
+            simulator.push(bytes, to);
+            Ok(size)
+        }
+        None => send_now(socket, &bytes, to).await,
+    }
+}
+
+async fn send_now(socket: &UdpSocket, bytes: &[u8], to: Option<SocketAddr>) -> io::Result<usize> {
+    let size = match to {
+        Some(to) => socket.send_to(bytes, to).await?,
+        None => socket.send(bytes).await?,
+    };
+
+    if size != bytes.len() {
+        return Err(io::Error::other(format!(
+            "Datagram of {} bytes was truncated to {} bytes.",
+            bytes.len(),
+            size
+        )));
+    }
+
+    Ok(size)
+}
@@ -1,38 +1,172 @@
 pub use futures::channel::mpsc::{
     channel, Receiver as InnerReceiver, Sender as InnerSender, TryRecvError, TrySendError,
 };
-use futures::StreamExt;
+use futures::{future::poll_fn, Stream, StreamExt};
 
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
+
 #[derive(Debug, Error)]
 pub enum RecvError {
     #[error("No messages available.")]
     Empty,
     #[error("The receiver is empty and disconnected.")]
     Disconnected,
+    #[error("No messages received before the timeout.")]
+    Timeout,
+}
+
+/// What happens to an event dispatched while the event queue is full, see [`Config::event_overflow`](crate::Config::event_overflow),
+/// or to a reliable message sent faster than the send rate allows, see [`Config::send_rate_overflow`](crate::Config::send_rate_overflow).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait until the application has received an event. No events are lost, but the client or server stops reading from the network while it waits.
+    Block,
+    /// Drop the event and keep going, which keeps latency low when the application falls behind.
+    DropNewest,
+}
+
+/// Dispatches an event to the application, applying the overflow policy if the event queue is full.
+/// Fails only if the [`Receiver`] was dropped.
+pub(crate) async fn dispatch<T>(
+    sender: &mut InnerSender<T>,
+    event: T,
+    overflow: Overflow,
+) -> Result<(), TrySendError<T>> {
+    if overflow == Overflow::Block {
+        // A dropped receiver is reported by `try_send` below.
+        let _ = poll_fn(|cx| sender.poll_ready(cx)).await;
+    }
+
+    match sender.try_send(event) {
+        Err(err) if err.is_full() => {
+            log::debug!("Event queue is full, dropping event.");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Marks a [`Receiver`] as closed when dropped, held by the task dispatching its events.
+#[derive(Debug)]
+pub(crate) struct CloseOnDrop(Arc<AtomicBool>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// An event that may carry the time it was received, which [`Config::max_event_age`](crate::Config::max_event_age) is measured from.
+/// Events without a timestamp are never dropped for age.
+pub(crate) trait Timestamped {
+    fn received_at(&self) -> Option<Instant>;
 }
 
+/// Returns the time an event was received, used to tell whether it is stale.
+type ReceivedAt<T> = fn(&T) -> Option<Instant>;
+
 #[derive(Debug)]
 pub struct Receiver<T> {
     receiver: InnerReceiver<T>,
+    closed: Arc<AtomicBool>,
+    max_age: Option<(Duration, ReceivedAt<T>)>,
 }
 
 impl<T> Receiver<T> {
     pub fn new(receiver: InnerReceiver<T>) -> Self {
-        Self { receiver }
+        Self {
+            receiver,
+            closed: Arc::new(AtomicBool::new(false)),
+            max_age: None,
+        }
+    }
+
+    /// Drop events older than `max_age` when they are received, see [`Config::max_event_age`](crate::Config::max_event_age).
+    pub(crate) fn max_age(mut self, max_age: Option<Duration>) -> Self
+    where
+        T: Timestamped,
+    {
+        self.max_age = max_age.map(|max_age| (max_age, T::received_at as ReceivedAt<T>));
+        self
+    }
+
+    fn is_stale(&self, event: &T) -> bool {
+        self.max_age.is_some_and(|(max_age, received_at)| {
+            received_at(event).is_some_and(|received_at| received_at.elapsed() > max_age)
+        })
+    }
+
+    pub(crate) fn close_on_drop(&self) -> CloseOnDrop {
+        CloseOnDrop(self.closed.clone())
+    }
+
+    /// Whether the client or server task has ended, so no more events will be dispatched.
+    /// Events dispatched before it ended can still be received.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
     }
 
     /// Asynchronously receive an event, returns [`None`] when the receiver is empty and disconnected.
     pub async fn recv(&mut self) -> Option<T> {
-        self.receiver.next().await
+        self.next().await
     }
 
-    /// Attempts to receive an event. This function is non-blocking.
-    pub fn try_recv(&mut self) -> Result<T, RecvError> {
-        match self.receiver.try_next() {
+    /// Receive an event, blocking the current thread until one is available. Returns [`None`] when the receiver is empty and disconnected.
+    /// Must not be called within an async runtime, use [`Receiver::recv`] there instead.
+    pub fn recv_blocking(&mut self) -> Option<T> {
+        futures::executor::block_on(self.next())
+    }
+
+    /// Asynchronously receive an event, giving up with [`RecvError::Timeout`] if none is received within the timeout.
+    /// Must be called within a Tokio runtime.
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvError> {
+        match tokio::time::timeout(timeout, self.next()).await {
             Ok(Some(t)) => Ok(t),
             Ok(None) => Err(RecvError::Disconnected),
-            Err(_) => Err(RecvError::Empty),
+            Err(_) => Err(RecvError::Timeout),
+        }
+    }
+
+    /// Attempts to receive an event. This function is non-blocking.
+    pub fn try_recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(t) if self.is_stale(&t) => log::debug!("Dropping stale event."),
+                Ok(t) => return Ok(t),
+                Err(TryRecvError::Closed) => return Err(RecvError::Disconnected),
+                Err(TryRecvError::Empty) => return Err(RecvError::Empty),
+            }
+        }
+    }
+}
+
+/// Events can also be consumed as a [`Stream`], which ends when the receiver is empty and disconnected.
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        loop {
+            match self.receiver.poll_next_unpin(cx) {
+                Poll::Ready(Some(t)) if self.is_stale(&t) => log::debug!("Dropping stale event."),
+                poll => return poll,
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.receiver.size_hint() {
+            // Stale events may be dropped, so none are guaranteed.
+            (_, upper) if self.max_age.is_some() => (0, upper),
+            size_hint => size_hint,
         }
     }
 }
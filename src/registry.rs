@@ -0,0 +1,377 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "rustls")]
+use crate::TlsInfo;
+use crate::{sender::Queued, ConnectionId, Features};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// A reliable frame could not be decoded.
+    MalformedFrame,
+    /// An unreliable datagram could not be decoded.
+    MalformedDatagram,
+    /// An unreliable datagram failed message authentication.
+    InvalidTag,
+    /// An unreliable datagram arrived from an address other than the one established during the handshake.
+    UnexpectedAddress,
+    /// A message was received from a receive-only connection.
+    ReceiveOnly,
+    /// An unreliable datagram was a replay or duplicate of an accepted datagram, or arrived too far out of order, see [`Config::replay_window`](crate::Config::replay_window).
+    Replayed,
+    /// A message could not be written to the connection, or a datagram was only written in part. Not caused by the client breaking the protocol, but recorded alongside the protocol errors.
+    WriteFailed,
+}
+
+/// A protocol violation observed on a connection.
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    pub at: Instant,
+    pub kind: ProtocolErrorKind,
+    pub detail: String,
+}
+
+/// A snapshot of the state of a connection on the server.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    /// Remote address of the reliable (TCP) stream.
+    pub peer_address: SocketAddr,
+    /// Whether messages from the connection are dropped, see [`ServerSender::set_receive_only`](crate::ServerSender::set_receive_only).
+    pub receive_only: bool,
+    /// Optional features supported by both the client and the server, empty until the handshake completes.
+    pub features: Features,
+    /// Whether the connection completed the handshake and was reported with [`ServerEvent::Connected`](crate::ServerEvent::Connected).
+    pub established: bool,
+    /// Time since the connection was accepted.
+    pub duration: Duration,
+}
+
+/// Traffic counters of a connection on the server, see [`Config::stats_interval`](crate::Config::stats_interval) and [`ServerSender::stats`](crate::ServerSender::stats).
+/// Throughput can be derived from the counters of two snapshots and the difference of their durations.
+/// Reliable messages are not retransmitted by the server, that is left to TCP. Bytes are counted as written to and read from the sockets, including framing but excluding TLS, TCP and UDP overhead.
+#[derive(Debug, Clone)]
+pub struct ConnectionStats {
+    pub id: ConnectionId,
+    /// Time since the connection was accepted.
+    pub duration: Duration,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    /// Number of messages sent with [`ServerSender`](crate::ServerSender) that are waiting for the server task, see [`Config::send_queue_capacity`](crate::Config::send_queue_capacity).
+    pub queued: usize,
+    /// Number of reliable messages held back by the send rate, see [`ServerSender::set_send_rate`](crate::ServerSender::set_send_rate).
+    pub queued_reliable: usize,
+    /// Estimated fraction (`0.0..=1.0`) of recent unreliable datagrams from the client that were lost, or [`None`] until a datagram has been received.
+    /// Derived from the counters of the datagrams, so datagrams that arrive late but within [`Config::replay_window`](crate::Config::replay_window) are not counted as lost.
+    pub packet_loss: Option<f32>,
+}
+
+/// A NaN, which is never a valid estimate.
+const NO_PACKET_LOSS: u32 = u32::MAX;
+
+/// Per-connection state shared between the server task and its handles, `U` being the type of the claims of the server.
+#[derive(Debug)]
+pub struct Record<U = ()> {
+    id: ConnectionId,
+    peer_address: SocketAddr,
+    receive_only: AtomicBool,
+    established: AtomicBool,
+    features: AtomicU32,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    queued_reliable: AtomicUsize,
+    queued: Queued,
+    /// Bits of the estimated packet loss, [`NO_PACKET_LOSS`] until a datagram has been received.
+    packet_loss: AtomicU32,
+    accepted_at: Instant,
+    /// Microseconds from `accepted_at` until something was last received from the connection.
+    last_seen: AtomicU64,
+    #[cfg(feature = "rustls")]
+    tls: Option<TlsInfo>,
+    errors: Mutex<VecDeque<ProtocolError>>,
+    error_capacity: usize,
+    /// Application data associated with the connection, see [`ServerSender::set_user_data`](crate::ServerSender::set_user_data).
+    user_data: Mutex<Option<U>>,
+}
+
+impl<U> Record<U> {
+    pub fn new(id: ConnectionId, peer_address: SocketAddr, error_capacity: usize) -> Self {
+        Self {
+            id,
+            peer_address,
+            receive_only: AtomicBool::new(false),
+            established: AtomicBool::new(false),
+            features: AtomicU32::new(Features::empty().bits()),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            queued_reliable: AtomicUsize::new(0),
+            queued: Queued::default(),
+            packet_loss: AtomicU32::new(NO_PACKET_LOSS),
+            accepted_at: Instant::now(),
+            last_seen: AtomicU64::new(0),
+            #[cfg(feature = "rustls")]
+            tls: None,
+            errors: Mutex::new(VecDeque::with_capacity(error_capacity)),
+            error_capacity,
+            user_data: Mutex::new(None),
+        }
+    }
+
+    /// Counts the age of the connection from when its stream was accepted, before the TLS handshake.
+    pub fn with_accepted_at(self, accepted_at: Instant) -> Self {
+        Self {
+            accepted_at,
+            ..self
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn with_tls(self, tls: TlsInfo) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            peer_address: self.peer_address,
+            receive_only: self.is_receive_only(),
+            features: Features::from_bits(self.features.load(Ordering::Relaxed)),
+            established: self.is_established(),
+            duration: self.accepted_at.elapsed(),
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.established.load(Ordering::Relaxed)
+    }
+
+    pub fn set_established(&self) {
+        self.established.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_receive_only(&self) -> bool {
+        self.receive_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_receive_only(&self, receive_only: bool) {
+        self.receive_only.store(receive_only, Ordering::Relaxed);
+    }
+
+    pub fn user_data(&self) -> Option<U>
+    where
+        U: Clone,
+    {
+        self.user_data.lock().unwrap().clone()
+    }
+
+    pub fn set_user_data(&self, user_data: U) {
+        *self.user_data.lock().unwrap() = Some(user_data);
+    }
+
+    pub fn set_features(&self, features: Features) {
+        self.features.store(features.bits(), Ordering::Relaxed);
+    }
+
+    /// Counts a message, or several messages carried by a single datagram, written to the connection.
+    pub fn sent(&self, messages: u64, bytes: usize) {
+        self.messages_sent.fetch_add(messages, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a message, or several messages carried by a single datagram, read from the connection.
+    pub fn received(&self, messages: u64, bytes: usize) {
+        self.messages_received
+            .fetch_add(messages, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn queued(&self) -> &Queued {
+        &self.queued
+    }
+
+    /// Marks the connection as active, see [`Config::idle_timeout`](crate::Config::idle_timeout).
+    pub fn seen(&self) {
+        let elapsed = self.accepted_at.elapsed().as_micros() as u64;
+        self.last_seen.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time since the connection was accepted.
+    pub fn age(&self) -> Duration {
+        self.accepted_at.elapsed()
+    }
+
+    /// Time since something was last received from the connection, or since it was accepted.
+    pub fn idle(&self) -> Duration {
+        let last_seen = Duration::from_micros(self.last_seen.load(Ordering::Relaxed));
+        self.accepted_at.elapsed().saturating_sub(last_seen)
+    }
+
+    pub fn set_queued_reliable(&self, queued_reliable: usize) {
+        self.queued_reliable
+            .store(queued_reliable, Ordering::Relaxed);
+    }
+
+    pub fn set_packet_loss(&self, packet_loss: Option<f32>) {
+        let bits = packet_loss.map_or(NO_PACKET_LOSS, f32::to_bits);
+        self.packet_loss.store(bits, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let packet_loss = self.packet_loss.load(Ordering::Relaxed);
+
+        ConnectionStats {
+            id: self.id,
+            duration: self.accepted_at.elapsed(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            queued: self.queued.get(),
+            queued_reliable: self.queued_reliable.load(Ordering::Relaxed),
+            packet_loss: (packet_loss != NO_PACKET_LOSS).then(|| f32::from_bits(packet_loss)),
+        }
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Records a protocol error, dropping the oldest one if the capacity is reached.
+    pub fn report<D: Into<String>>(&self, kind: ProtocolErrorKind, detail: D) -> ProtocolError {
+        let error = ProtocolError {
+            at: Instant::now(),
+            kind,
+            detail: detail.into(),
+        };
+        if self.error_capacity == 0 {
+            return error;
+        }
+
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == self.error_capacity {
+            errors.pop_front();
+        }
+        errors.push_back(error.clone());
+
+        error
+    }
+
+    pub fn recent_errors(&self) -> Vec<ProtocolError> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Connections known to the server, and the addresses it is bound to, readable from synchronous contexts.
+/// Records are kept by [`ConnectionId::index`], and looking one up by id checks the generation, so stale ids find nothing.
+#[derive(Debug)]
+pub struct Registry<U = ()> {
+    records: RwLock<HashMap<u32, Arc<Record<U>>>>,
+    local_addresses: Mutex<Vec<SocketAddr>>,
+}
+
+impl<U> Default for Registry<U> {
+    fn default() -> Self {
+        Self {
+            records: RwLock::default(),
+            local_addresses: Mutex::default(),
+        }
+    }
+}
+
+impl<U> Registry<U> {
+    pub fn local_addresses(&self) -> Vec<SocketAddr> {
+        self.local_addresses.lock().unwrap().clone()
+    }
+
+    pub fn set_local_addresses(&self, local_addresses: Vec<SocketAddr>) {
+        *self.local_addresses.lock().unwrap() = local_addresses;
+    }
+
+    pub fn insert(&self, record: Record<U>) -> Arc<Record<U>> {
+        let record = Arc::new(record);
+        self.records
+            .write()
+            .unwrap()
+            .insert(record.id.index(), record.clone());
+        record
+    }
+
+    pub fn remove(&self, id: ConnectionId) {
+        let mut records = self.records.write().unwrap();
+        if records
+            .get(&id.index())
+            .is_some_and(|record| record.id == id)
+        {
+            // Messages still on their way to the task are dropped with the connection.
+            if let Some(record) = records.remove(&id.index()) {
+                record.queued.reset();
+            }
+        }
+    }
+
+    pub fn get(&self, id: ConnectionId) -> Option<Arc<Record<U>>> {
+        self.slot(id.index()).filter(|record| record.id == id)
+    }
+
+    /// The record of the connection currently in the slot, whatever its generation.
+    pub fn slot(&self, index: u32) -> Option<Arc<Record<U>>> {
+        self.records.read().unwrap().get(&index).cloned()
+    }
+
+    /// Ids of the established connections, in ascending order.
+    pub fn established(&self) -> Vec<ConnectionId> {
+        let mut ids: Vec<_> = self
+            .records
+            .read()
+            .unwrap()
+            .values()
+            .filter(|record| record.is_established())
+            .map(|record| record.id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Ids of the connections whose reliable stream is from the IP address, established or not.
+    pub fn with_ip(&self, address: IpAddr) -> Vec<ConnectionId> {
+        let address = address.to_canonical();
+        self.records
+            .read()
+            .unwrap()
+            .values()
+            .filter(|record| record.peer_address.ip().to_canonical() == address)
+            .map(|record| record.id)
+            .collect()
+    }
+
+    pub fn established_count(&self) -> usize {
+        self.records
+            .read()
+            .unwrap()
+            .values()
+            .filter(|record| record.is_established())
+            .count()
+    }
+}
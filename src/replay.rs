@@ -0,0 +1,149 @@
+/// Largest supported reorder window, one bit per counter below the highest accepted counter.
+pub const MAX_REPLAY_WINDOW: u32 = 64;
+
+/// Number of counters after which the loss estimate is halved, so that it reflects recent datagrams.
+const LOSS_WINDOW: f64 = 256.0;
+
+/// Rejects unreliable datagrams whose counter was already accepted, or is too old to tell.
+///
+/// A counter is accepted if it is higher than any previously accepted counter, or if it is within `size` of the highest
+/// accepted counter and has not been accepted before. This lets datagrams arrive out of order by up to `size` counters.
+///
+/// Since counters are sequential, the window also estimates packet loss as the fraction of counters up to the highest accepted one that were not accepted.
+#[derive(Debug)]
+pub struct ReplayWindow {
+    highest: u64,
+    /// Bit `i` is set if counter `highest - i` has been accepted.
+    seen: u64,
+    size: u32,
+    expected: f64,
+    received: f64,
+}
+
+impl ReplayWindow {
+    pub fn new(size: u32) -> Self {
+        Self {
+            highest: 0,
+            seen: 0,
+            size: size.min(MAX_REPLAY_WINDOW),
+            expected: 0.0,
+            received: 0.0,
+        }
+    }
+
+    pub fn accept(&mut self, counter: u64) -> bool {
+        if counter == 0 {
+            return false;
+        }
+
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+            self.count(shift as f64);
+
+            return true;
+        }
+
+        let offset = self.highest - counter;
+        if offset >= self.size as u64 {
+            return false;
+        }
+
+        let bit = 1 << offset;
+        if self.seen & bit != 0 {
+            false
+        } else {
+            self.seen |= bit;
+            self.count(0.0);
+            true
+        }
+    }
+
+    /// Estimated fraction of datagrams lost, or [`None`] until a datagram has been accepted.
+    pub fn loss(&self) -> Option<f32> {
+        if self.expected == 0.0 {
+            return None;
+        }
+
+        Some((1.0 - self.received / self.expected).clamp(0.0, 1.0) as f32)
+    }
+
+    /// Counts an accepted datagram, which advanced the highest counter by `advance`.
+    fn count(&mut self, advance: f64) {
+        self.expected += advance;
+        self.received = (self.received + 1.0).min(self.expected);
+
+        if self.expected > LOSS_WINDOW {
+            self.expected /= 2.0;
+            self.received /= 2.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_and_duplicates() {
+        let mut window = ReplayWindow::new(MAX_REPLAY_WINDOW);
+        assert!(!window.accept(0));
+        assert!(window.accept(1));
+        assert!(!window.accept(1));
+        assert!(window.accept(5));
+        assert!(!window.accept(5));
+    }
+
+    #[test]
+    fn accepts_out_of_order_within_the_window() {
+        let mut window = ReplayWindow::new(8);
+        assert!(window.accept(10));
+        assert!(window.accept(8));
+        assert!(window.accept(3));
+        assert!(!window.accept(8));
+        assert!(!window.accept(3));
+    }
+
+    #[test]
+    fn rejects_counters_older_than_the_window() {
+        let mut window = ReplayWindow::new(8);
+        assert!(window.accept(10));
+        // The oldest counter in the window is `highest - (size - 1)`.
+        assert!(!window.accept(2));
+        assert!(window.accept(3));
+    }
+
+    #[test]
+    fn shifts_of_the_whole_window_forget_old_counters() {
+        for &shift in &[63, 64, 65, 1000] {
+            let mut window = ReplayWindow::new(MAX_REPLAY_WINDOW);
+            assert!(window.accept(1));
+            assert!(window.accept(1 + shift));
+            assert!(!window.accept(1 + shift), "shift {}", shift);
+            // The oldest counter in the window is 1 after a shift of 63, which was accepted before, and new after larger shifts.
+            assert_eq!(window.accept(shift - 62), shift > 63, "shift {}", shift);
+            assert!(!window.accept(shift - 63), "shift {}", shift);
+        }
+    }
+
+    #[test]
+    fn clamps_the_size() {
+        let mut window = ReplayWindow::new(1000);
+        assert!(window.accept(100));
+        assert!(window.accept(37));
+        assert!(!window.accept(36));
+    }
+
+    #[test]
+    fn estimates_loss() {
+        let mut window = ReplayWindow::new(MAX_REPLAY_WINDOW);
+        assert_eq!(window.loss(), None);
+        for counter in (1..=100).step_by(2) {
+            window.accept(counter);
+        }
+        let loss = window.loss().unwrap();
+        assert!((loss - 0.5).abs() < 0.02, "{}", loss);
+    }
+}
@@ -1,10 +1,28 @@
 pub use futures::channel::mpsc::{
-    unbounded as channel, TryRecvError, TrySendError, UnboundedReceiver as InnerReceiver,
+    unbounded as channel, SendError as InnerSendError, UnboundedReceiver as InnerReceiver,
     UnboundedSender as InnerSender,
 };
+use futures::{channel::oneshot, Sink, Stream};
 
-use crate::{ClientSender, ConnectionId, Delivery, ServerSender};
+#[cfg(feature = "rustls")]
+use crate::TlsInfo;
+use crate::{
+    datagram, header::HEADER_SIZE, ClientCommand, ClientSender, Config, ConnectionId,
+    ConnectionInfo, ConnectionState, ConnectionStats, Delivery, Header, Hooks, Priority,
+    ProtocolError, ServerCommand, ServerSender,
+};
 
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -13,68 +31,819 @@ pub enum SendError {
     Full,
     #[error("The sender is disconnected.")]
     Disconnected,
+    #[error("The connection does not exist.")]
+    UnknownConnection,
+    /// The message is larger than [`Config::max_reliable_size`] (reliable) or [`Config::max_unreliable_size`] (unreliable) allows.
+    #[error("The message is {size} bytes, which exceeds the maximum of {max} bytes.")]
+    TooLarge { size: usize, max: usize },
+    /// The operation relies on an optional feature that was not negotiated with the peer, see [`Features`](crate::Features).
+    #[error("The feature was not negotiated with the peer.")]
+    Unsupported,
+    /// Writing to the reliable stream failed, see [`ClientSender::flush`].
+    #[error("Writing to the stream failed: {0:?}.")]
+    WriteFailed(std::io::ErrorKind),
+}
+
+/// Number of messages dispatched to a client or server task that it has not written yet, see [`Config::send_queue_capacity`].
+#[derive(Debug, Default)]
+pub struct Queued(AtomicUsize);
+
+impl Queued {
+    /// Counts a message about to be dispatched, failing with [`SendError::Full`] if the capacity is reached.
+    pub fn push(&self, capacity: Option<usize>) -> Result<(), SendError> {
+        self.0
+            .fetch_update(
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+                |queued| match capacity {
+                    Some(capacity) if queued >= capacity => None,
+                    _ => Some(queued + 1),
+                },
+            )
+            .map(|_| ())
+            .map_err(|_| SendError::Full)
+    }
+
+    /// Counts a message taken by the task.
+    pub fn pop(&self) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                queued.checked_sub(1)
+            });
+    }
+
+    /// Counts a message while `dispatch` hands it to the task, uncounting it again if that fails.
+    pub fn dispatch(
+        &self,
+        capacity: Option<usize>,
+        dispatch: impl FnOnce() -> Result<(), SendError>,
+    ) -> Result<(), SendError> {
+        self.push(capacity)?;
+        dispatch().inspect_err(|_| self.pop())
+    }
+
+    /// Forgets the messages the task will no longer take, such as those left when a connection closes.
+    pub fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Creates a channel of commands to a client or server task with a lane per [`Priority`].
+pub fn priority_channel<T>() -> (PrioritySender<T>, PriorityReceiver<T>) {
+    let (high_sender, high_receiver) = channel();
+    let (normal_sender, normal_receiver) = channel();
+
+    (
+        PrioritySender {
+            high: high_sender,
+            normal: normal_sender,
+        },
+        PriorityReceiver {
+            high: high_receiver,
+            normal: normal_receiver,
+        },
+    )
+}
+
+#[derive(Debug)]
+pub struct PrioritySender<T> {
+    high: InnerSender<T>,
+    normal: InnerSender<T>,
+}
+
+impl<T> Clone for PrioritySender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            high: self.high.clone(),
+            normal: self.normal.clone(),
+        }
+    }
+}
+
+impl<T> PrioritySender<T> {
+    fn lane(&self, priority: Priority) -> &InnerSender<T> {
+        match priority {
+            Priority::High => &self.high,
+            Priority::Normal => &self.normal,
+        }
+    }
+}
+
+/// Yields the commands of the high priority lane before those of the normal lane, each lane in the order the commands were sent.
+#[derive(Debug)]
+pub struct PriorityReceiver<T> {
+    high: InnerReceiver<T>,
+    normal: InnerReceiver<T>,
+}
+
+impl<T> PriorityReceiver<T> {
+    /// Takes a command that is ready without waiting, if any.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.high
+            .try_recv()
+            .or_else(|_| self.normal.try_recv())
+            .ok()
+    }
+}
+
+impl<T> Stream for PriorityReceiver<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let high = Pin::new(&mut self.high).poll_next(cx);
+        if let Poll::Ready(Some(item)) = high {
+            return Poll::Ready(Some(item));
+        }
+
+        match Pin::new(&mut self.normal).poll_next(cx) {
+            // The lanes are closed together, as every sender holds both.
+            Poll::Ready(None) if high.is_pending() => Poll::Pending,
+            poll => poll,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Sender<T> {
-    sender: InnerSender<T>,
+/// Sends commands to a client or server task. `S` is state shared with the task that can be queried without going through the task.
+#[derive(Debug)]
+pub struct Sender<T, S = ()> {
+    sender: PrioritySender<T>,
+    shared: Arc<S>,
+    config: Config,
+    hooks: Arc<Hooks>,
 }
 
-impl<T> Sender<T> {
-    pub fn new(sender: InnerSender<T>) -> Self {
-        Self { sender }
+impl<T, S> Clone for Sender<T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            shared: self.shared.clone(),
+            config: self.config,
+            hooks: self.hooks.clone(),
+        }
+    }
+}
+
+impl<T, S> Sender<T, S> {
+    pub fn new(
+        sender: PrioritySender<T>,
+        shared: Arc<S>,
+        config: Config,
+        hooks: Arc<Hooks>,
+    ) -> Self {
+        Self {
+            sender,
+            shared,
+            config,
+            hooks,
+        }
+    }
+
+    pub(crate) fn config(&self) -> Config {
+        self.config
+    }
+
+    /// Whether the client or server task has ended, after which sending fails with [`SendError::Disconnected`].
+    pub fn is_closed(&self) -> bool {
+        self.sender.normal.is_closed()
+    }
+
+    /// Checks that a message fits within the limits of the configuration before it is dispatched to the task.
+    /// Passes the data of a message to [`Hooks::on_send`], then checks the final data against the size limits.
+    fn prepare(&self, data: &mut Vec<u8>, delivery: Delivery) -> Result<(), SendError> {
+        self.hooks.send(data, delivery);
+        check(&self.config, data, delivery)
+    }
+
+    fn dispatch(&self, item: T) -> Result<(), SendError> {
+        self.dispatch_with_priority(item, Priority::Normal)
+    }
+
+    fn dispatch_with_priority(&self, item: T, priority: Priority) -> Result<(), SendError> {
+        self.sender
+            .lane(priority)
+            .unbounded_send(item)
+            .map_err(|err| err.into_send_error().into())
+    }
+
+    fn poll_ready_inner(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Pin::new(&mut self.sender.normal)
+            .poll_ready(cx)
+            .map_err(Into::into)
+    }
+
+    fn start_send_inner(&mut self, item: T) -> Result<(), SendError> {
+        Pin::new(&mut self.sender.normal)
+            .start_send(item)
+            .map_err(Into::into)
+    }
+
+    fn poll_close_inner(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.sender.high.disconnect();
+        Pin::new(&mut self.sender.normal)
+            .poll_close(cx)
+            .map_err(Into::into)
+    }
+}
+
+/// Checks the data of a message against the size limits of the config.
+pub(crate) fn check(config: &Config, data: &[u8], delivery: Delivery) -> Result<(), SendError> {
+    let max = match delivery {
+        // Reliable frames carry the header in addition to the data.
+        Delivery::Reliable => (config.max_reliable_size as usize).saturating_sub(HEADER_SIZE),
+        Delivery::Unreliable | Delivery::UnreliableSequenced => datagram::max_message_size(config),
+    };
+
+    if data.len() > max {
+        Err(SendError::TooLarge {
+            size: data.len(),
+            max,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Waits for the reply to a command, such as the receipt of a message, unless it could not be dispatched. The task drops the reply when the connection is lost.
+async fn receive_reply(
+    dispatched: Result<(), SendError>,
+    reply: oneshot::Receiver<Result<(), SendError>>,
+) -> Result<(), SendError> {
+    dispatched?;
+    reply.await.unwrap_or(Err(SendError::Disconnected))
+}
+
+impl From<InnerSendError> for SendError {
+    fn from(err: InnerSendError) -> Self {
+        if err.is_full() {
+            Self::Full
+        } else {
+            Self::Disconnected
+        }
     }
 }
 
 /// # Sender used for Client
 impl ClientSender {
-    pub fn send(&self, data: Vec<u8>, delivery: Delivery) -> Result<(), SendError> {
-        self.sender.unbounded_send((data, delivery)).map_err(|err| {
-            if err.is_full() {
-                SendError::Full
-            } else {
-                SendError::Disconnected
-            }
-        })
+    /// Send data to the server. The data is taken as anything that converts into a `Vec<u8>`,
+    /// so an owned vector is moved without copying, and a borrowed slice (for instance of a larger buffer) is copied once.
+    pub fn send<D: Into<Vec<u8>>>(&self, data: D, delivery: Delivery) -> Result<(), SendError> {
+        self.send_with_header(Header::default(), data.into(), delivery, Priority::Normal)
+    }
+
+    /// Send data to the server, letting the client task write it before waiting messages of a lower [`Priority`].
+    pub fn send_with_priority<D: Into<Vec<u8>>>(
+        &self,
+        data: D,
+        delivery: Delivery,
+        priority: Priority,
+    ) -> Result<(), SendError> {
+        self.send_with_header(Header::default(), data.into(), delivery, priority)
+    }
+
+    fn send_with_header(
+        &self,
+        header: Header,
+        mut data: Vec<u8>,
+        delivery: Delivery,
+        priority: Priority,
+    ) -> Result<(), SendError> {
+        self.prepare(&mut data, delivery)?;
+        self.shared
+            .queued
+            .dispatch(self.config.send_queue_capacity, || {
+                self.dispatch_with_priority(
+                    ClientCommand::Send {
+                        header,
+                        data,
+                        delivery,
+                    },
+                    priority,
+                )
+            })
     }
 
     /// Send data to the server with reliable delivery.
-    pub fn reliable(&self, data: Vec<u8>) -> Result<(), SendError> {
+    pub fn reliable<D: Into<Vec<u8>>>(&self, data: D) -> Result<(), SendError> {
         self.send(data, Delivery::Reliable)
     }
 
+    /// Send data along with a [`Header`] to the server with reliable delivery.
+    pub fn reliable_with_header<D: Into<Vec<u8>>>(
+        &self,
+        header: Header,
+        data: D,
+    ) -> Result<(), SendError> {
+        self.send_with_header(header, data.into(), Delivery::Reliable, Priority::Normal)
+    }
+
+    /// Send data to the server with reliable delivery, returning a future that resolves once the server has read the message.
+    /// The message is dispatched right away, in order with the messages sent before and after it, whether or not the future is awaited.
+    /// Fails with [`SendError::Unsupported`] if receipts were not negotiated, see [`Features::RECEIPTS`](crate::Features::RECEIPTS),
+    /// and with [`SendError::Disconnected`] if the connection is lost before the receipt arrives, in which case the server may or may not have read the message.
+    pub fn reliable_with_ack<D: Into<Vec<u8>>>(
+        &self,
+        data: D,
+    ) -> impl Future<Output = Result<(), SendError>> {
+        let mut data = data.into();
+        let (reply, receipt) = oneshot::channel();
+        let dispatched = self.prepare(&mut data, Delivery::Reliable).and_then(|_| {
+            self.shared
+                .queued
+                .dispatch(self.config.send_queue_capacity, || {
+                    self.dispatch(ClientCommand::SendWithReceipt {
+                        header: Header::default(),
+                        data,
+                        reply,
+                    })
+                })
+        });
+
+        receive_reply(dispatched, receipt)
+    }
+
     /// Send data to the server with unreliable delivery.
-    pub fn unreliable(&self, data: Vec<u8>) -> Result<(), SendError> {
+    pub fn unreliable<D: Into<Vec<u8>>>(&self, data: D) -> Result<(), SendError> {
         self.send(data, Delivery::Unreliable)
     }
+
+    /// Estimated current wall-clock time of the server, or [`None`] until the first time response has been received.
+    /// The estimate follows the monotonic clock of this process between samples, so it is not affected by changes to the local wall clock.
+    /// It is accurate to within half the round trip time, see [`ClientSender::round_trip_time`], and usually much better on symmetric paths.
+    /// Samples delayed by jitter are rejected and the rest are smoothed, so the estimate takes a few samples to settle, see [`Config::time_sync_interval`](crate::Config::time_sync_interval).
+    pub fn estimated_server_time(&self) -> Option<SystemTime> {
+        self.shared.clock.estimated_server_time()
+    }
+
+    /// Smoothed round trip time measured by time synchronization, or [`None`] until the first time response has been received.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.shared.clock.round_trip_time()
+    }
+
+    /// Measures the round trip time to the server on demand, by sending a time request (see [`Config::time_sync_interval`](crate::Config::time_sync_interval)) and waiting for the response.
+    /// Fails with [`SendError::Unsupported`] if time synchronization was not negotiated, see [`Features::TIME_SYNC`](crate::Features::TIME_SYNC),
+    /// and with [`SendError::Disconnected`] if the connection is lost first. The request is an unreliable datagram that can be lost, in which case
+    /// the future does not complete, so it should be awaited with a timeout.
+    pub async fn ping(&self) -> Result<Duration, SendError> {
+        let (reply, response) = oneshot::channel();
+        self.dispatch(ClientCommand::Ping { reply })?;
+
+        response.await.unwrap_or(Err(SendError::Disconnected))
+    }
+
+    /// Close the connection to the server. Messages sent before are written first, then the stream is closed and the client task ends with
+    /// [`ClientEvent::Disconnected`](crate::ClientEvent::Disconnected) instead of reconnecting. The server observes the closed stream right away and
+    /// dispatches [`ServerEvent::Disconnected`](crate::ServerEvent::Disconnected), unless the stream cannot be closed cleanly, in which case it relies on
+    /// TCP or [`Config::idle_timeout`](crate::Config::idle_timeout) to notice. The server closes a connection with [`ServerSender::disconnect`].
+    pub fn disconnect(&self) -> Result<(), SendError> {
+        self.dispatch(ClientCommand::Disconnect)
+    }
+
+    /// Returns a future that resolves once the reliable messages sent before it have been written to the operating system, which is the boundary of a batch.
+    /// Nagle's algorithm is disabled (`TCP_NODELAY`), so every write is sent right away and messages are only written together within [`Config::reliable_coalesce_window`](crate::Config::reliable_coalesce_window),
+    /// whose held back messages the flush writes early. The flush is dispatched right away, in order with the messages sent before and after it, whether or not the future is awaited.
+    /// Fails with [`SendError::WriteFailed`] if writing fails, and with [`SendError::Disconnected`] if the connection is lost first.
+    pub fn flush(&self) -> impl Future<Output = Result<(), SendError>> {
+        let (reply, flushed) = oneshot::channel();
+        let dispatched = self.dispatch(ClientCommand::Flush { reply });
+
+        receive_reply(dispatched, flushed)
+    }
+
+    /// Smoothed mean deviation of the round trip time (RTTVAR in RFC 6298), or [`None`] until the first time response has been received.
+    /// Unlike [`ClientSender::round_trip_time`] it includes the samples rejected as delayed, which makes it suitable for sizing interpolation buffers.
+    pub fn jitter(&self) -> Option<Duration> {
+        self.shared.clock.jitter()
+    }
+
+    /// Estimated fraction (`0.0..=1.0`) of recent unreliable datagrams from the server that were lost, or [`None`] until a datagram has been received.
+    /// Derived from the counters of the datagrams, so it includes time responses, see [`Config::time_sync_interval`](crate::Config::time_sync_interval).
+    pub fn packet_loss(&self) -> Option<f32> {
+        self.shared.packet_loss()
+    }
+
+    /// Number of messages waiting for the client task to write them, see [`Config::send_queue_capacity`].
+    pub fn queued(&self) -> usize {
+        self.shared.queued.get()
+    }
+
+    /// Current state of the connection to the server, updated by the client task as the connection changes, so there is no need to track the events for it.
+    /// A message sent while the client is not [`ConnectionState::Connected`](crate::ConnectionState::Connected) waits for the connection, or is dropped, see [`ReconnectPolicy::retain_messages`](crate::ReconnectPolicy::retain_messages).
+    pub fn state(&self) -> ConnectionState {
+        self.shared.connection_state()
+    }
+
+    /// Local address of the UDP socket, including the port assigned by the operating system, or [`None`] until the client has connected.
+    /// This is the address unreliable messages originate from, as seen before any NAT. It can change when the client reconnects, see [`Config::reconnect`](crate::Config::reconnect).
+    pub fn local_udp_address(&self) -> Option<SocketAddr> {
+        self.shared.local_addresses().map(|(udp, _)| udp)
+    }
+
+    /// Local address of the TCP stream, or [`None`] until the client has connected. Unless [`Config::local_address`](crate::Config::local_address) is set,
+    /// its port differs from the one of [`ClientSender::local_udp_address`].
+    pub fn local_tcp_address(&self) -> Option<SocketAddr> {
+        self.shared.local_addresses().map(|(_, tcp)| tcp)
+    }
+
+    /// Details of the TLS session with the server, such as its certificate chain for pinning, or [`None`] until the client has connected.
+    #[cfg(feature = "rustls")]
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.shared.tls()
+    }
+}
+
+/// Messages can also be sent to the server by forwarding a [`Stream`](futures::Stream) of `(data, delivery)` into the sender.
+/// Messages are dispatched to the client task right away, so flushing completes immediately.
+/// Closing the sink drops its handle to the client task, while clones of the sender keep working.
+impl Sink<(Vec<u8>, Delivery)> for ClientSender {
+    type Error = SendError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.poll_ready_inner(cx)
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        (mut data, delivery): (Vec<u8>, Delivery),
+    ) -> Result<(), SendError> {
+        self.prepare(&mut data, delivery)?;
+        let capacity = self.config.send_queue_capacity;
+        let shared = self.shared.clone();
+        shared.queued.dispatch(capacity, || {
+            self.start_send_inner(ClientCommand::Send {
+                header: Header::default(),
+                data,
+                delivery,
+            })
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.poll_close_inner(cx)
+    }
 }
 
 /// # Sender used for Server
-impl ServerSender {
-    pub fn send(
+impl<U> ServerSender<U> {
+    /// Send data to a client. Like [`ClientSender::send`], an owned vector is moved without copying, and a borrowed slice is copied once.
+    pub fn send<D: Into<Vec<u8>>>(
         &self,
         id: ConnectionId,
-        data: Vec<u8>,
+        data: D,
         delivery: Delivery,
     ) -> Result<(), SendError> {
-        self.sender
-            .unbounded_send((id, data, delivery))
-            .map_err(|err| {
-                if err.is_full() {
-                    SendError::Full
-                } else {
-                    SendError::Disconnected
-                }
+        self.send_with_header(
+            id,
+            Header::default(),
+            data.into(),
+            delivery,
+            Priority::Normal,
+        )
+    }
+
+    /// Send data to a client, letting the server task write it before waiting messages of a lower [`Priority`].
+    pub fn send_with_priority<D: Into<Vec<u8>>>(
+        &self,
+        id: ConnectionId,
+        data: D,
+        delivery: Delivery,
+        priority: Priority,
+    ) -> Result<(), SendError> {
+        self.send_with_header(id, Header::default(), data.into(), delivery, priority)
+    }
+
+    fn send_with_header(
+        &self,
+        id: ConnectionId,
+        header: Header,
+        mut data: Vec<u8>,
+        delivery: Delivery,
+        priority: Priority,
+    ) -> Result<(), SendError> {
+        let record = self.shared.get(id).ok_or(SendError::UnknownConnection)?;
+        self.prepare(&mut data, delivery)?;
+
+        record
+            .queued()
+            .dispatch(self.config.send_queue_capacity, || {
+                self.dispatch_with_priority(
+                    ServerCommand::Send {
+                        id,
+                        header,
+                        data,
+                        delivery,
+                    },
+                    priority,
+                )
+            })
+    }
+
+    /// Send the same data to several clients, dispatching it to the server task once instead of once per client.
+    /// Unknown connections and connections whose send queue is full (see [`Config::send_queue_capacity`]) are skipped,
+    /// and the number of connections the message was queued for is returned.
+    pub fn multicast<D: Into<Vec<u8>>>(
+        &self,
+        ids: &[ConnectionId],
+        data: D,
+        delivery: Delivery,
+    ) -> Result<usize, SendError> {
+        let mut data = data.into();
+        self.prepare(&mut data, delivery)?;
+
+        let records: Vec<_> = ids
+            .iter()
+            .filter_map(|id| self.shared.get(*id))
+            .filter(|record| {
+                record
+                    .queued()
+                    .push(self.config.send_queue_capacity)
+                    .is_ok()
             })
+            .collect();
+        let queued = records.len();
+
+        if queued > 0 {
+            let ids = records.iter().map(|record| record.id()).collect();
+            let dispatched = self.dispatch(ServerCommand::Multicast {
+                ids,
+                header: Header::default(),
+                data,
+                delivery,
+            });
+            if let Err(err) = dispatched {
+                for record in records {
+                    record.queued().pop();
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(queued)
+    }
+
+    /// Send the same data to every established connection, see [`ServerSender::connection_ids`].
+    /// Like [`ServerSender::multicast`], connections that disconnect in the meantime or whose send queue is full are skipped,
+    /// and the number of connections the message was queued for is returned.
+    pub fn broadcast<D: Into<Vec<u8>>>(
+        &self,
+        data: D,
+        delivery: Delivery,
+    ) -> Result<usize, SendError> {
+        self.multicast(&self.shared.established(), data, delivery)
+    }
+
+    /// Send data to every established connection with reliable delivery, see [`ServerSender::broadcast`].
+    pub fn broadcast_reliable<D: Into<Vec<u8>>>(&self, data: D) -> Result<usize, SendError> {
+        self.broadcast(data, Delivery::Reliable)
+    }
+
+    /// Send data to every established connection with unreliable delivery, see [`ServerSender::broadcast`].
+    pub fn broadcast_unreliable<D: Into<Vec<u8>>>(&self, data: D) -> Result<usize, SendError> {
+        self.broadcast(data, Delivery::Unreliable)
     }
 
     /// Send data to a client with reliable delivery.
-    pub fn reliable(&self, id: ConnectionId, data: Vec<u8>) -> Result<(), SendError> {
+    pub fn reliable<D: Into<Vec<u8>>>(&self, id: ConnectionId, data: D) -> Result<(), SendError> {
         self.send(id, data, Delivery::Reliable)
     }
 
+    /// Send data along with a [`Header`] to a client with reliable delivery.
+    pub fn reliable_with_header<D: Into<Vec<u8>>>(
+        &self,
+        id: ConnectionId,
+        header: Header,
+        data: D,
+    ) -> Result<(), SendError> {
+        self.send_with_header(
+            id,
+            header,
+            data.into(),
+            Delivery::Reliable,
+            Priority::Normal,
+        )
+    }
+
+    /// Send data to a client with reliable delivery, returning a future that resolves once the client has read the message.
+    /// Behaves like [`ClientSender::reliable_with_ack`], and fails with [`SendError::Disconnected`] if the connection is closed before the receipt arrives.
+    pub fn reliable_with_ack<D: Into<Vec<u8>>>(
+        &self,
+        id: ConnectionId,
+        data: D,
+    ) -> impl Future<Output = Result<(), SendError>> {
+        let mut data = data.into();
+        let (reply, receipt) = oneshot::channel();
+        let dispatched = self
+            .shared
+            .get(id)
+            .ok_or(SendError::UnknownConnection)
+            .and_then(|record| {
+                self.prepare(&mut data, Delivery::Reliable)?;
+                record
+                    .queued()
+                    .dispatch(self.config.send_queue_capacity, || {
+                        self.dispatch(ServerCommand::SendWithReceipt {
+                            id,
+                            header: Header::default(),
+                            data,
+                            reply,
+                        })
+                    })
+            });
+
+        receive_reply(dispatched, receipt)
+    }
+
     /// Send data to a client with unreliable delivery.
-    pub fn unreliable(&self, id: ConnectionId, data: Vec<u8>) -> Result<(), SendError> {
+    pub fn unreliable<D: Into<Vec<u8>>>(&self, id: ConnectionId, data: D) -> Result<(), SendError> {
         self.send(id, data, Delivery::Unreliable)
     }
+
+    /// Limit the rate at which data is sent to a client, or remove the limit with [`None`].
+    /// Reliable messages exceeding the rate are queued and sent in order once the rate allows it, or dropped, see [`Config::send_rate_overflow`](crate::Config::send_rate_overflow),
+    /// while unreliable messages exceeding the rate are dropped.
+    /// Fails with [`SendError::UnknownConnection`] if there is no such connection.
+    pub fn set_send_rate(
+        &self,
+        id: ConnectionId,
+        bytes_per_sec: Option<u32>,
+    ) -> Result<(), SendError> {
+        self.shared.get(id).ok_or(SendError::UnknownConnection)?;
+        self.dispatch(ServerCommand::SetSendRate { id, bytes_per_sec })
+    }
+
+    /// Returns the most recent protocol errors observed on a connection, oldest first.
+    /// At most [`Config::recent_errors_capacity`](crate::Config::recent_errors_capacity) errors are kept per connection, and they are dropped when the connection is.
+    pub fn recent_errors(&self, id: ConnectionId) -> Vec<ProtocolError> {
+        self.shared
+            .get(id)
+            .map(|record| record.recent_errors())
+            .unwrap_or_default()
+    }
+
+    /// Local address the server is bound to, including the port assigned by the operating system when listening on port 0, or [`None`] until the server task has bound it.
+    /// The UDP socket and the TCP listener share the address. A server listening on several addresses returns the first, see [`ServerSender::local_addresses`].
+    pub fn local_address(&self) -> Option<SocketAddr> {
+        self.shared.local_addresses().first().copied()
+    }
+
+    /// Local addresses the server is bound to, in the order they were given to [`Server::listen_multi`](crate::Server::listen_multi), or empty until the server task has bound them.
+    pub fn local_addresses(&self) -> Vec<SocketAddr> {
+        self.shared.local_addresses()
+    }
+
+    /// Number of established connections, that is, connections reported with [`ServerEvent::Connected`](crate::ServerEvent::Connected) and not yet disconnected.
+    pub fn connection_count(&self) -> usize {
+        self.shared.established_count()
+    }
+
+    /// Ids of the established connections in ascending order, see [`ServerSender::connection_count`].
+    /// Use [`ServerSender::connection_info`] for details such as how long a connection has been open.
+    pub fn connection_ids(&self) -> Vec<ConnectionId> {
+        self.shared.established()
+    }
+
+    /// Returns a snapshot of the state of a connection, or [`None`] if there is no such connection.
+    pub fn connection_info(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        self.shared.get(id).map(|record| record.info())
+    }
+
+    /// Returns the remote address of the reliable (TCP) stream of a connection, or [`None`] if there is no such connection.
+    pub fn peer_address(&self, id: ConnectionId) -> Option<SocketAddr> {
+        self.shared.get(id).map(|record| record.info().peer_address)
+    }
+
+    /// Returns the details of the TLS session of a connection, or [`None`] if there is no such connection.
+    #[cfg(feature = "rustls")]
+    pub fn tls_info(&self, id: ConnectionId) -> Option<TlsInfo> {
+        self.shared.get(id).and_then(|record| record.tls().cloned())
+    }
+
+    /// Returns the traffic counters of a connection, or [`None`] if there is no such connection.
+    /// The counters are kept in atomics updated by the server task, so this does not wait for the task.
+    pub fn stats(&self, id: ConnectionId) -> Option<ConnectionStats> {
+        self.shared.get(id).map(|record| record.stats())
+    }
+
+    /// Mark a connection as receive-only (a spectator), or revert it to a regular connection.
+    /// Messages received from a receive-only connection are dropped and recorded as a [`ProtocolError`], while messages can still be sent to it.
+    /// To enforce this from the start, call it when handling [`ServerEvent::Connected`](crate::ServerEvent::Connected).
+    /// Returns false if there is no such connection.
+    pub fn set_receive_only(&self, id: ConnectionId, receive_only: bool) -> bool {
+        self.shared
+            .get(id)
+            .map(|record| record.set_receive_only(receive_only))
+            .is_some()
+    }
+
+    /// Associate application data (such as a player id, room or auth claims) with a connection, replacing any data set before.
+    /// The data has the type of the claims returned by the validation function given to [`Server::listen`](crate::Server::listen), for instance to keep the claim updated or to use an enum of session states.
+    /// The data is kept with the connection and dropped when it closes, so there is no separate map to keep in sync with the events.
+    /// Returns false if there is no such connection.
+    pub fn set_user_data(&self, id: ConnectionId, user_data: U) -> bool {
+        self.shared
+            .get(id)
+            .map(|record| record.set_user_data(user_data))
+            .is_some()
+    }
+
+    /// Returns a clone of the data set with [`ServerSender::set_user_data`], or [`None`] if there is no such connection or no data was set.
+    /// Use an [`Arc`] (around a [`Mutex`](std::sync::Mutex) for mutable state) as the claim type to share the data rather than clone it.
+    pub fn user_data(&self, id: ConnectionId) -> Option<U>
+    where
+        U: Clone,
+    {
+        self.shared.get(id)?.user_data()
+    }
+
+    /// Close a connection from the server side, or fail with [`SendError::UnknownConnection`] if there is no such connection.
+    /// The connection is closed asynchronously, once the server task handles the command: it is removed then, and a [`ServerEvent::Disconnected`](crate::ServerEvent::Disconnected)
+    /// is dispatched for it if it was established, after which sending to it fails with [`SendError::UnknownConnection`].
+    /// The client observes the closed stream and dispatches [`ClientEvent::Disconnected`](crate::ClientEvent::Disconnected).
+    /// Reliable messages sent before are written first, including those held back by [`ServerSender::cork`] or [`ServerSender::set_send_rate`].
+    pub fn disconnect(&self, id: ConnectionId) -> Result<(), SendError> {
+        self.shared.get(id).ok_or(SendError::UnknownConnection)?;
+        self.dispatch(ServerCommand::Disconnect { id })
+    }
+
+    /// Stop the server: every connection is closed as with [`ServerSender::disconnect`], after the messages sent before the shutdown have been written,
+    /// and the server task resolves with `Ok(())`, releasing its sockets. Connections still performing the handshake are dropped.
+    pub fn shutdown(&self) -> Result<(), SendError> {
+        self.dispatch(ServerCommand::Shutdown)
+    }
+
+    /// Hold back reliable messages to a client so that several small messages can be written together.
+    /// Messages are buffered by the server (not with `TCP_CORK`, since the stream may be wrapped in TLS) and written on [`ServerSender::uncork`],
+    /// or earlier if the buffer reaches 64KB.
+    /// Fails with [`SendError::UnknownConnection`] if there is no such connection.
+    pub fn cork(&self, id: ConnectionId) -> Result<(), SendError> {
+        self.shared.get(id).ok_or(SendError::UnknownConnection)?;
+        self.dispatch(ServerCommand::Cork { id })
+    }
+
+    /// Write reliable messages held back since [`ServerSender::cork`] as one batch, and stop holding back messages.
+    /// Fails with [`SendError::UnknownConnection`] if there is no such connection.
+    pub fn uncork(&self, id: ConnectionId) -> Result<(), SendError> {
+        self.shared.get(id).ok_or(SendError::UnknownConnection)?;
+        self.dispatch(ServerCommand::Uncork { id })
+    }
+
+    /// Returns a future that resolves once the reliable messages sent to a client before it have been written to the operating system, see [`ClientSender::flush`].
+    /// Messages held back by [`ServerSender::cork`] stay held back, as do messages held back by a send rate, see [`ServerSender::set_send_rate`].
+    /// Fails with [`SendError::UnknownConnection`] if there is no such connection.
+    pub fn flush(&self, id: ConnectionId) -> impl Future<Output = Result<(), SendError>> {
+        let (reply, flushed) = oneshot::channel();
+        let dispatched = self.dispatch(ServerCommand::Flush { id, reply });
+
+        receive_reply(dispatched, flushed)
+    }
+}
+
+/// Messages can also be sent to clients by forwarding a [`Stream`](futures::Stream) of `(id, data, delivery)` into the sender.
+/// Unlike [`ServerSender::send`], messages to unknown connections are not rejected up front, they are dropped by the server task.
+/// Closing the sink drops its handle to the server task, while clones of the sender keep working.
+impl<U> Sink<(ConnectionId, Vec<u8>, Delivery)> for ServerSender<U> {
+    type Error = SendError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.poll_ready_inner(cx)
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        (id, mut data, delivery): (ConnectionId, Vec<u8>, Delivery),
+    ) -> Result<(), SendError> {
+        self.prepare(&mut data, delivery)?;
+        let command = ServerCommand::Send {
+            id,
+            header: Header::default(),
+            data,
+            delivery,
+        };
+        match self.shared.get(id) {
+            Some(record) => {
+                let capacity = self.config.send_queue_capacity;
+                record
+                    .queued()
+                    .dispatch(capacity, || self.start_send_inner(command))
+            }
+            // Not counted, as the server task finds no connection to count it against.
+            None => self.start_send_inner(command),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError>> {
+        self.poll_close_inner(cx)
+    }
 }
@@ -1,43 +1,170 @@
-use futures::StreamExt;
+use futures::{channel::oneshot, future::poll_fn, stream::FuturesUnordered, StreamExt};
 use hibitset::BitSet;
 use slab::Slab;
-use std::{convert::TryInto, future::Future, sync::Arc};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    convert::TryInto, future::Future, io, net::SocketAddr, sync::Arc, task::Poll, time::Instant,
+};
 use thiserror::Error;
 use tokio::{
-    io::{split, AsyncWriteExt},
-    net::{TcpListener, ToSocketAddrs, UdpSocket},
+    io::{split, AsyncRead, AsyncWrite, ReadBuf},
+    net::{lookup_host, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
     sync::RwLock,
+    time::{interval, sleep_until, Duration},
 };
 
-use crate::{receiver, sender, Config, Connection, ConnectionId, Delivery, Receiver, Sender};
+use crate::{
+    clock::Clock,
+    connection,
+    datagram::{self, Payload},
+    limiter::Paced,
+    netsim::{self, Simulator},
+    receiver, sender,
+    span::{self, Instrument},
+    Config, ConfigError, Connection, ConnectionId, Delivery, DisconnectReason, DuplicateKey,
+    Features, Header, Hooks, Overflow, Receiver, SendError, Sender,
+};
 
+#[cfg(feature = "rustls")]
+use crate::tls;
 #[cfg(feature = "rustls")]
 use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 
+/// Number of times binding is attempted, see [`Server::bind`].
+const BIND_ATTEMPTS: usize = 8;
+
 #[derive(Debug, Clone)]
 pub enum ServerEvent<U: Send + Sync + Clone> {
-    Connected { id: u32, claim: U },
-    Received { id: u32, data: Vec<u8> },
-    Disconnected { id: u32 },
+    Connected {
+        id: ConnectionId,
+        claim: U,
+        /// Remote address of the reliable (TCP) stream, also available through [`ServerSender::peer_address`] for the lifetime of the connection.
+        peer_address: SocketAddr,
+    },
+    /// A message was received from a client.
+    /// `received_at` is captured from the monotonic clock of this process when the frame (reliable) or datagram (unreliable) was read from the socket,
+    /// it does not include the time the event spent in the event queue.
+    Received {
+        id: ConnectionId,
+        header: Header,
+        data: Vec<u8>,
+        received_at: Instant,
+    },
+    Disconnected {
+        id: ConnectionId,
+        reason: DisconnectReason,
+    },
+    /// Periodic traffic statistics of an established connection, see [`Config::stats_interval`].
+    /// Dropped instead of dispatched when the event queue is full, so it never delays other events.
+    Stats(ConnectionStats),
+    /// A newly connected client matches an already established connection according to [`Config::duplicate_key`].
+    /// Dispatched right after the [`ServerEvent::Connected`] event of the new connection, both connections are kept open.
+    DuplicateConnection {
+        existing_id: ConnectionId,
+        new_id: ConnectionId,
+        token: Vec<u8>,
+    },
+    /// A protocol error was recorded for a connection, see [`Config::error_events`] and [`ServerSender::recent_errors`].
+    /// Dropped instead of dispatched when the event queue is full, so it never delays other events.
+    Error {
+        id: ConnectionId,
+        error: ProtocolError,
+    },
+}
+
+impl<U: Send + Sync + Clone> receiver::Timestamped for ServerEvent<U> {
+    fn received_at(&self) -> Option<Instant> {
+        match self {
+            ServerEvent::Received { received_at, .. } => Some(*received_at),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum ServerError {
     #[error("Unable to create server.")]
     Io(#[from] std::io::Error),
+    /// The [`Config`] is invalid, see [`Config::validate`]. The server task resolves with this error before binding any socket.
+    #[error("Invalid configuration.")]
+    Config(#[from] ConfigError),
+}
+
+/// Commands dispatched from a [`ServerSender`] to the server task.
+#[derive(Debug)]
+pub enum ServerCommand {
+    Send {
+        id: ConnectionId,
+        header: Header,
+        data: Vec<u8>,
+        delivery: Delivery,
+    },
+    /// Send a reliable message and reply once the client has read it, see [`ServerSender::reliable_with_ack`].
+    SendWithReceipt {
+        id: ConnectionId,
+        header: Header,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<(), SendError>>,
+    },
+    /// The same message sent to several connections, see [`ServerSender::multicast`].
+    Multicast {
+        ids: Vec<ConnectionId>,
+        header: Header,
+        data: Vec<u8>,
+        delivery: Delivery,
+    },
+    SetSendRate {
+        id: ConnectionId,
+        bytes_per_sec: Option<u32>,
+    },
+    Cork {
+        id: ConnectionId,
+    },
+    Uncork {
+        id: ConnectionId,
+    },
+    Disconnect {
+        id: ConnectionId,
+    },
+    /// Close every connection and end the server task, see [`ServerSender::shutdown`].
+    Shutdown,
+    /// Write the reliable messages sent to a connection before and reply once they are written, see [`ServerSender::flush`].
+    Flush {
+        id: ConnectionId,
+        reply: oneshot::Sender<Result<(), SendError>>,
+    },
 }
 
-pub type ServerSender = Sender<(ConnectionId, Vec<u8>, Delivery)>;
+/// Sends commands to the server task, `U` being the type of the claims, which is also the type of the data set with [`ServerSender::set_user_data`].
+pub type ServerSender<U = ()> = Sender<ServerCommand, Registry<U>>;
 pub type ServerReceiver<U> = Receiver<ServerEvent<U>>;
 
 pub use crate::disconnector::{DisconnectError, Disconnector};
+use crate::{
+    disconnector::BlockList,
+    hooks::Sessions,
+    limiter::ConnectionLimiter,
+    registry::{ConnectionStats, ProtocolError, ProtocolErrorKind, Record, Registry},
+};
+
+/// How often reliable frames held back by a send rate are retried.
+const PACING_INTERVAL: Duration = Duration::from_millis(5);
 
 pub struct Server;
 
-impl<'a> Server {
+/// A UDP socket of the server, with the simulator of the datagrams sent on it, see [`Config::network_simulation`].
+struct Endpoint {
+    socket: UdpSocket,
+    simulator: Option<Simulator>,
+}
+
+impl Server {
     /// Start a server listening on the specified address.
     /// Returns a [`Sender`], [`Receiver`] and a [`Future`] which must be awaited in an async executor (see the examples in the [repository](https://github.com/oskarbraten/zelda/)).
     /// The server can run in a separate thread and messages/events can be sent/received in a synchronous context.
+    ///
+    /// `validation_fn` is called with the token presented by each client during the handshake (at most [`Config::max_token_size`] bytes).
+    /// Returning a claim establishes the connection, while returning [`None`] drops it without dispatching any events, and the client fails with [`ClientError::InvalidToken`](crate::ClientError::InvalidToken).
     pub fn listen<
         A: ToSocketAddrs,
         U: Send + Sync + Clone + 'static,
@@ -48,189 +175,852 @@ impl<'a> Server {
         #[cfg(feature = "rustls")] server_config: ServerConfig,
         validation_fn: F,
     ) -> (
-        Sender<(ConnectionId, Vec<u8>, Delivery)>,
-        Receiver<ServerEvent<U>>,
-        Disconnector,
+        ServerSender<U>,
+        ServerReceiver<U>,
+        Disconnector<U>,
+        impl Future<Output = Result<(), ServerError>>,
+    ) {
+        Self::listen_with_hooks(
+            address,
+            config,
+            #[cfg(feature = "rustls")]
+            server_config,
+            validation_fn,
+            Hooks::default(),
+        )
+    }
+
+    /// Start a server like [`Server::listen`], calling `on_connect` for every connection that passes validation, see [`Hooks::on_connect`].
+    pub fn listen_with_on_connect<
+        A: ToSocketAddrs,
+        U: Send + Sync + Clone + 'static,
+        F: Fn(Vec<u8>) -> Option<U> + Send + Sync + Clone + 'static,
+        C: Fn(ConnectionId) -> Option<Vec<u8>> + Send + Sync + 'static,
+    >(
+        address: A,
+        config: Config,
+        #[cfg(feature = "rustls")] server_config: ServerConfig,
+        validation_fn: F,
+        on_connect: C,
+    ) -> (
+        ServerSender<U>,
+        ServerReceiver<U>,
+        Disconnector<U>,
+        impl Future<Output = Result<(), ServerError>>,
+    ) {
+        Self::listen_with_hooks(
+            address,
+            config,
+            #[cfg(feature = "rustls")]
+            server_config,
+            validation_fn,
+            Hooks::default().on_connect(on_connect),
+        )
+    }
+
+    /// Start a server like [`Server::listen`], invoking the [`Hooks`] while establishing connections and on the payload of every message.
+    pub fn listen_with_hooks<
+        A: ToSocketAddrs,
+        U: Send + Sync + Clone + 'static,
+        F: Fn(Vec<u8>) -> Option<U> + Send + Sync + Clone + 'static,
+    >(
+        address: A,
+        config: Config,
+        #[cfg(feature = "rustls")] server_config: ServerConfig,
+        validation_fn: F,
+        hooks: Hooks,
+    ) -> (
+        ServerSender<U>,
+        ServerReceiver<U>,
+        Disconnector<U>,
+        impl Future<Output = Result<(), ServerError>>,
+    ) {
+        Self::listen_multi_with_hooks(
+            [address],
+            config,
+            #[cfg(feature = "rustls")]
+            server_config,
+            validation_fn,
+            hooks,
+        )
+    }
+
+    /// Start a server like [`Server::listen`], listening on several addresses, for instance an IPv4 and an IPv6 address or the addresses of several interfaces.
+    /// Every address is bound to its own UDP socket and TCP listener (see [`Config::dual_stack`]), and the server task fails if any of them cannot be bound.
+    /// Connections accepted on any of the addresses share the sender, the receiver and the space of [`ConnectionId`]s.
+    pub fn listen_multi<
+        I: IntoIterator<Item = A>,
+        A: ToSocketAddrs,
+        U: Send + Sync + Clone + 'static,
+        F: Fn(Vec<u8>) -> Option<U> + Send + Sync + Clone + 'static,
+    >(
+        addresses: I,
+        config: Config,
+        #[cfg(feature = "rustls")] server_config: ServerConfig,
+        validation_fn: F,
+    ) -> (
+        ServerSender<U>,
+        ServerReceiver<U>,
+        Disconnector<U>,
+        impl Future<Output = Result<(), ServerError>>,
+    ) {
+        Self::listen_multi_with_hooks(
+            addresses,
+            config,
+            #[cfg(feature = "rustls")]
+            server_config,
+            validation_fn,
+            Hooks::default(),
+        )
+    }
+
+    /// Start a server like [`Server::listen_multi`], invoking the [`Hooks`] while establishing connections and on the payload of every message.
+    pub fn listen_multi_with_hooks<
+        I: IntoIterator<Item = A>,
+        A: ToSocketAddrs,
+        U: Send + Sync + Clone + 'static,
+        F: Fn(Vec<u8>) -> Option<U> + Send + Sync + Clone + 'static,
+    >(
+        addresses: I,
+        config: Config,
+        #[cfg(feature = "rustls")] server_config: ServerConfig,
+        validation_fn: F,
+        hooks: Hooks,
+    ) -> (
+        ServerSender<U>,
+        ServerReceiver<U>,
+        Disconnector<U>,
         impl Future<Output = Result<(), ServerError>>,
     ) {
+        // A config built without [`ConfigBuilder::build`](crate::ConfigBuilder::build) has not been validated yet.
+        let validated = config.validate();
         let (disconnect_sender, disconnect_receiver) = sender::channel::<ConnectionId>();
-        let (outbound_sender, outbound_receiver) =
-            sender::channel::<(ConnectionId, Vec<u8>, Delivery)>();
+        let block_list = Arc::new(BlockList::default());
+        let registry = Arc::new(Registry::default());
+        let (outbound_sender, outbound_receiver) = sender::priority_channel::<ServerCommand>();
         let (inbound_sender, inbound_receiver) =
             receiver::channel::<ServerEvent<U>>(config.event_capacity);
+        let max_event_age = config.max_event_age;
+        let hooks = Arc::new(hooks);
 
         let task = Self::task(
-            address,
+            addresses.into_iter().collect(),
             config,
             inbound_sender,
             outbound_receiver,
             disconnect_receiver,
+            block_list.clone(),
+            registry.clone(),
             #[cfg(feature = "rustls")]
             server_config,
             validation_fn,
+            hooks.clone(),
         );
 
+        let receiver = Receiver::new(inbound_receiver).max_age(max_event_age);
+        let close_on_drop = receiver.close_on_drop();
+        let task = async move {
+            let _close_on_drop = close_on_drop;
+            validated?;
+            task.await
+        }
+        .instrument(span::server());
+
         (
-            Sender::new(outbound_sender),
-            Receiver::new(inbound_receiver),
-            Disconnector::new(disconnect_sender),
+            Sender::new(outbound_sender, registry.clone(), config, hooks),
+            receiver,
+            Disconnector::new(disconnect_sender, block_list, registry),
             task,
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn task<
         A: ToSocketAddrs,
         U: Send + Sync + Clone + 'static,
         F: Fn(Vec<u8>) -> Option<U> + Send + Sync + Clone + 'static,
     >(
-        address: A,
+        addresses: Vec<A>,
         config: Config,
         mut inbound_sender: receiver::InnerSender<ServerEvent<U>>,
-        mut outbound_receiver: sender::InnerReceiver<(ConnectionId, Vec<u8>, Delivery)>,
+        mut outbound_receiver: sender::PriorityReceiver<ServerCommand>,
         mut disconnect_receiver: sender::InnerReceiver<ConnectionId>,
+        block_list: Arc<BlockList>,
+        registry: Arc<Registry<U>>,
         #[cfg(feature = "rustls")] server_config: ServerConfig,
         validation_fn: F,
+        hooks: Arc<Hooks>,
     ) -> Result<(), ServerError> {
         let validation_fn = Arc::new(validation_fn);
+        let sessions = hooks.has_resume().then(|| Arc::new(Sessions::default()));
 
-        let socket = UdpSocket::bind(&address).await?;
+        if addresses.is_empty() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "No address to listen on.").into(),
+            );
+        }
 
+        let mut endpoints = vec![];
+        let mut listeners = vec![];
+        for address in addresses.iter() {
+            let (socket, listener) = Self::bind(address, &config).await?;
+            config.apply_udp_options(&socket)?;
+            endpoints.push(Endpoint {
+                socket,
+                simulator: config.network_simulation.map(Simulator::new),
+            });
+            listeners.push(listener);
+        }
+        registry.set_local_addresses(
+            endpoints
+                .iter()
+                .map(|endpoint| endpoint.socket.local_addr())
+                .collect::<io::Result<_>>()?,
+        );
+
+        #[cfg(feature = "rustls")]
+        let require_alpn = !server_config.alpn_protocols.is_empty();
         #[cfg(feature = "rustls")]
         let acceptor = TlsAcceptor::from(Arc::new(server_config));
 
-        let listener = TcpListener::bind(&address).await?;
-
         let connections = Arc::new(RwLock::new(Slab::new()));
         let established_connections = Arc::new(RwLock::new(BitSet::new()));
 
-        let mut recv_buffer = [0u8; std::u16::MAX as usize];
+        let mut pacing_interval = interval(PACING_INTERVAL);
+        let mut connection_limiter = config.connection_rate.map(ConnectionLimiter::new);
+
+        let coalesce = config
+            .reliable_coalesce_window
+            .filter(|window| !window.is_zero());
+        let mut coalesce_interval = interval(coalesce.unwrap_or(Duration::from_secs(1)));
+
+        let stats = config.stats_interval.filter(|interval| !interval.is_zero());
+        let mut stats_interval = interval(stats.unwrap_or(Duration::from_secs(1)));
+
+        let idle_timeout = config.idle_timeout;
+        let handshake_timeout = config.handshake_timeout;
+        let mut idle_interval = interval(config.idle_check_interval);
+
+        // Rotates the listener and socket polled first, so busy ones do not starve the others.
+        let mut poll_offset: usize = 0;
+
+        let mut handshakes = FuturesUnordered::new();
+        // Distinguishes connections that occupy the same slot one after another, see [`ConnectionId`].
+        let mut next_generation: u32 = 0;
+
+        let mut recv_buffer = [0u8; u16::MAX as usize];
         loop {
+            let simulator_due = endpoints
+                .iter()
+                .filter_map(|endpoint| endpoint.simulator.as_ref()?.next_due())
+                .min();
+            poll_offset = poll_offset.wrapping_add(1);
+
             tokio::select! {
-                result = listener.accept() => {
+                result = accept_any(&listeners, poll_offset) => {
                     if let Ok((stream, address)) = result {
+                        if block_list.contains(address.ip()) {
+                            log::debug!("Refusing connection from blocked address: {}", address);
+                            continue;
+                        }
+
+                        if !hooks.accept(address) {
+                            log::debug!("Refusing connection, it was not accepted by the hook: {}", address);
+                            continue;
+                        }
+
+                        if let Some(connection_limiter) = connection_limiter.as_mut() {
+                            if !connection_limiter.allow(address.ip()) {
+                                log::debug!("Refusing connection, the connection rate is exceeded: {}", address);
+                                continue;
+                            }
+                        }
+
                         log::debug!("Accepting a new connection: {}", address);
 
                         let _ = stream.set_nodelay(true);
+                        let accepted_at = Instant::now();
 
+                        // The TLS handshake is performed alongside the other branches, so a slow client does not hold up the server.
                         #[cfg(feature = "rustls")]
-                        let (read_stream, write_stream) = {
+                        {
                             let acceptor = acceptor.clone();
-                            let stream = acceptor.accept(stream).await?;
-                            split(stream)
-                        };
+                            let deadline = config.handshake_timeout.map(|timeout| accepted_at + timeout);
+                            handshakes.push(async move { (before(deadline, acceptor.accept(stream)).await, address, accepted_at) }.instrument(span::handshake(address)));
+                        }
 
                         #[cfg(not(feature = "rustls"))]
-                        let (read_stream, write_stream) = split(stream);
-
-                        let id = {
-                            let mut connections = connections.write().await;
+                        handshakes.push(async move { (std::io::Result::Ok(stream), address, accepted_at) }.instrument(span::handshake(address)));
+                    }
+                },
+                Some((result, address, accepted_at)) = handshakes.next() => {
+                    let stream = match result {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            log::debug!("Error performing TLS handshake: {}", err);
+                            continue;
+                        }
+                    };
+                    #[cfg(feature = "rustls")]
+                    let tls = crate::TlsInfo::new(stream.get_ref().1);
+                    #[cfg(feature = "rustls")]
+                    let key = match tls::export_key(stream.get_ref().1) {
+                        Ok(key) => key,
+                        Err(err) => {
+                            log::debug!("Error exporting key from TLS session: {}", err);
+                            continue;
+                        }
+                    };
+                    // A client without ALPN completes the handshake without agreeing on a protocol.
+                    #[cfg(feature = "rustls")]
+                    if require_alpn && tls.alpn_protocol.is_none() {
+                        log::debug!("Refusing connection, no application protocol was agreed on (ALPN): {}", address);
+                        continue;
+                    }
+                    let (read_stream, write_stream) = split(stream);
 
-                            let entry = connections.vacant_entry();
+                    if let Some(max_connections) = config.max_connections {
+                        if connections.read().await.len() >= max_connections {
+                            log::debug!("Rejecting connection, the maximum number of connections is reached: {}", address);
+                            match Connection::reject(write_stream, connection::REJECTED).await {
+                                Ok(()) => {},
+                                Err(err) => log::debug!("Error rejecting connection (TCP): {}", err)
+                            }
+                            continue;
+                        }
+                    }
 
-                            let id = entry.key() as u32;
+                    let (id, connection_span) = {
+                        let mut connections = connections.write().await;
 
-                            let connection = Connection::accept(id, write_stream).await.unwrap();
+                        let entry = connections.vacant_entry();
 
-                            entry.insert(connection);
+                        let id = ConnectionId::new(entry.key() as u32, next_generation);
 
-                            id
+                        // The client may already have given up, for instance when no application protocol was agreed on.
+                        let connection = match Connection::accept(id, write_stream, address, #[cfg(feature = "rustls")] key, config).instrument(span::handshake(address)).await {
+                            Ok(connection) => connection,
+                            Err(err) => {
+                                log::debug!("Error accepting connection (TCP): {}", err);
+                                continue;
+                            }
                         };
 
-                        let connections = connections.clone();
-                        let established_connections = established_connections.clone();
-                        let mut inbound_sender = inbound_sender.clone();
-                        let validation_fn = validation_fn.clone();
-
-                        tokio::spawn(async move {
-                            let mut read_stream = read_stream;
-                            loop {
-                                match Connection::read(&mut read_stream, config.max_reliable_size).await {
-                                    Ok(data) => {
-                                        let is_connected = established_connections.read().await.contains(id);
-                                        if is_connected {
-                                            inbound_sender.try_send(ServerEvent::Received { id, data, }).unwrap();
-                                        } else if &data[0..3] == b"ACK" {
-
-                                            let claim: Option<U> = {
-                                                let token = data[3..].to_vec();
-                                                validation_fn(token)
+                        let connection_span = connection.span.clone();
+                        entry.insert(connection);
+                        next_generation = next_generation.wrapping_add(1);
+
+                        (id, connection_span)
+                    };
+                    let record = Record::new(id, address, config.recent_errors_capacity).with_accepted_at(accepted_at);
+                    #[cfg(feature = "rustls")]
+                    let record = record.with_tls(tls);
+                    let record = registry.insert(record);
+
+                    let slab = connections.clone();
+                    let connections = connections.clone();
+                    let established_connections = established_connections.clone();
+                    let mut inbound_sender = inbound_sender.clone();
+                    let validation_fn = validation_fn.clone();
+                    let hooks = hooks.clone();
+                    let sessions = sessions.clone();
+                    let registry = registry.clone();
+
+                    let reader_span = connection_span.clone();
+                    let reader = tokio::spawn(async move {
+                        let mut read_stream = read_stream;
+                        let mut max_size = b"ACK".len() as u32 + 4 + config.max_token_size;
+                        // Whether the client waits for a receipt of the next message, see [`connection::RECEIPT`].
+                        let mut receipt_requested = false;
+                        loop {
+                            match Connection::read(&mut read_stream, max_size).await {
+                                Ok(mut data) => {
+                                    let received_at = Instant::now();
+                                    record.seen();
+                                    let is_connected = established_connections.read().await.contains(id.index());
+                                    if is_connected && data == connection::RECEIVED {
+                                        let expected = connections.read().await.get(id.index() as usize).is_some_and(|connection| connection.receipt());
+                                        if !expected {
+                                            report_error(&mut inbound_sender, &record, ProtocolErrorKind::MalformedFrame, "Unexpected receipt.", &config);
+                                        }
+                                    } else if is_connected && record.is_receive_only() {
+                                        report_error(&mut inbound_sender, &record, ProtocolErrorKind::ReceiveOnly, format!("Frame of {} bytes.", data.len()), &config);
+                                    } else if is_connected && data == connection::RECEIPT {
+                                        receipt_requested = true;
+                                    } else if is_connected {
+                                        record.received(1, 4 + data.len());
+                                        match Header::decode(&mut data) {
+                                            Some(header) => {
+                                                hooks.receive(&mut data, Delivery::Reliable);
+                                                let receive = span::receive(&connection_span, data.len(), Delivery::Reliable);
+                                                dispatch(&mut inbound_sender, ServerEvent::Received { id, header, data, received_at }, &config).instrument(receive).await
+                                            },
+                                            None => {
+                                                log::debug!("Error decoding frame (TCP): missing header.");
+                                                report_error(&mut inbound_sender, &record, ProtocolErrorKind::MalformedFrame, "Missing header.", &config);
+                                            }
+                                        }
+
+                                        // Receipts are matched to messages by their order, so a malformed message is acknowledged as well.
+                                        if std::mem::take(&mut receipt_requested) {
+                                            if let Some(connection) = connections.read().await.get(id.index() as usize) {
+                                                if let Err(err) = connection.write(connection::RECEIVED).await {
+                                                    log::debug!("Error writing receipt (TCP): {}", err);
+                                                }
+                                            }
+                                        }
+                                    } else if data.len() < 7 || !data.starts_with(b"ACK") {
+                                        report_error(&mut inbound_sender, &record, ProtocolErrorKind::MalformedFrame, "Expected handshake ACK and features.", &config);
+                                    } else {
+                                        let features = Features::from_bits(u32::from_be_bytes(data[3..7].try_into().unwrap())) & config.advertised_features();
+                                        let token = data[7..].to_vec();
+                                        let claim: Option<U> = validation_fn(token.clone());
+
+                                        if let Some(claim) = claim {
+                                            let initial = hooks.connect(id);
+                                            let accepted = match connections.read().await.get(id.index() as usize) {
+                                                Some(connection) => {
+                                                    *connection.token.lock().unwrap() = token.clone();
+                                                    connection.set_features(features);
+                                                    record.set_features(features);
+
+                                                    match initial {
+                                                        Some(mut data) => {
+                                                            hooks.send(&mut data, Delivery::Reliable);
+                                                            match sender::check(&config, &data, Delivery::Reliable) {
+                                                                Ok(()) => connection.write(&Header::default().encode(&data)).await.map_err(|err| log::debug!("Error writing initial message (TCP): {}", err)).is_ok(),
+                                                                Err(err) => {
+                                                                    log::debug!("Error writing initial message (TCP): {}", err);
+                                                                    false
+                                                                }
+                                                            }
+                                                        },
+                                                        None => true,
+                                                    }
+                                                },
+                                                None => false,
                                             };
 
-                                            if let Some(claim) = claim {
-                                                established_connections.write().await.add(id);
-                                                inbound_sender.try_send(ServerEvent::Connected { id, claim }).unwrap();
-                                            } else {
-                                                // Token validation failed, remove and drop connection.
-                                                let mut connections = connections.write().await;
-                                                connections.remove(id as usize);
+                                            if !accepted {
+                                                connections.write().await.try_remove(id.index() as usize);
+                                                registry.remove(id);
                                                 break;
                                             }
+
+                                            established_connections.write().await.add(id.index());
+                                            record.set_established();
+                                            max_size = config.max_reliable_size;
+
+                                            if let Some(sessions) = sessions.as_ref().filter(|_| !token.is_empty()) {
+                                                let established_id = {
+                                                    let connections = connections.read().await;
+                                                    let established_connections = established_connections.read().await;
+
+                                                    connections.iter().find(|(other_index, other)| {
+                                                        *other_index as u32 != id.index()
+                                                            && established_connections.contains(*other_index as u32)
+                                                            && *other.token.lock().unwrap() == token
+                                                    }).and_then(|(other_index, _)| registry.slot(other_index as u32)).map(|record| record.id())
+                                                };
+
+                                                if let Some(old_id) = established_id.or_else(|| sessions.take(&token, config.resume_window)) {
+                                                    hooks.resume(old_id, id);
+                                                }
+                                            }
+
+                                            dispatch(&mut inbound_sender, ServerEvent::Connected { id, claim, peer_address: address }, &config).await;
+
+                                            if let Some(duplicate_key) = config.duplicate_key {
+                                                let existing_id = {
+                                                    let connections = connections.read().await;
+                                                    let established_connections = established_connections.read().await;
+
+                                                    connections.get(id.index() as usize).and_then(|connection| {
+                                                        connections.iter().find(|(other_index, other)| {
+                                                            *other_index as u32 != id.index()
+                                                                && established_connections.contains(*other_index as u32)
+                                                                && match duplicate_key {
+                                                                    DuplicateKey::Token => !token.is_empty() && *other.token.lock().unwrap() == token,
+                                                                    DuplicateKey::Ip => other.peer_address.ip() == connection.peer_address.ip(),
+                                                                }
+                                                        }).and_then(|(other_index, _)| registry.slot(other_index as u32)).map(|record| record.id())
+                                                    })
+                                                };
+
+                                                if let Some(existing_id) = existing_id {
+                                                    dispatch(&mut inbound_sender, ServerEvent::DuplicateConnection { existing_id, new_id: id, token }, &config).await;
+                                                }
+                                            }
+                                        } else {
+                                            // Token validation failed, reject and drop connection.
+                                            let connection = connections.write().await.try_remove(id.index() as usize);
+                                            registry.remove(id);
+                                            if let Some(connection) = connection {
+                                                match Connection::reject(connection.write_stream.into_inner(), connection::INVALID_TOKEN).await {
+                                                    Ok(()) => {},
+                                                    Err(err) => log::debug!("Error rejecting connection (TCP): {}", err)
+                                                }
+                                            }
+                                            break;
                                         }
-                                    },
-                                    Err(err) => {
-                                        log::debug!("Error reading frame (TCP): {:#?}", err);
-                                        let mut connections = connections.write().await;
-                                        connections.remove(id as usize);
-                                        established_connections.write().await.remove(id);
-                                        inbound_sender.try_send(ServerEvent::Disconnected { id }).unwrap();
-                                        break;
                                     }
+                                },
+                                Err(err) => {
+                                    log::debug!("Error reading frame (TCP): {:#?}", err);
+                                    if let Some(frame_error) = connection::frame_error(&err) {
+                                        report_error(&mut inbound_sender, &record, ProtocolErrorKind::MalformedFrame, frame_error.to_string(), &config);
+                                    }
+
+                                    let connection = connections.write().await.try_remove(id.index() as usize);
+                                    registry.remove(id);
+                                    if established_connections.write().await.remove(id.index()) {
+                                        if let (Some(sessions), Some(connection)) = (sessions.as_ref(), connection) {
+                                            sessions.close(connection.token.into_inner().unwrap(), id, config.resume_window);
+                                        }
+                                        dispatch(&mut inbound_sender, ServerEvent::Disconnected { id, reason: connection::disconnect_reason(&err) }, &config).await;
+                                    }
+                                    break;
                                 }
                             }
-                        });
+                        }
+                    }.instrument(reader_span));
+
+                    let slab = slab.read().await;
+                    if let Some(connection) = slab.get(id.index() as usize) {
+                        *connection.reader.lock().unwrap() = Some(reader);
                     }
                 },
-                result = socket.recv_from(&mut recv_buffer) => {
+                (endpoint_index, result) = recv_any(&endpoints, &mut recv_buffer, poll_offset) => {
+                    // Errors are transient for UDP, for example Windows reports an ICMP port unreachable as a reset on the next receive.
+                    let result = result.map_err(|err| log::debug!("Error receiving datagram (UDP): {}", err));
                     if let Ok((bytes_read, remote_address)) = result {
+                        let received_at = Instant::now();
+
+                        if block_list.contains(remote_address.ip()) {
+                            continue;
+                        }
+
+                        // Events are dispatched and the connection is closed once the guards below are released,
+                        // so a full event queue does not hold up the other connections, and closing can take the write lock.
+                        let mut received = Vec::new();
+                        let mut handshake_error = None;
+
                         // Must receive more than tag (u64) bytes + id (u32)
                         if bytes_read >= 14 {
-                            let id = recv_buffer[8..12].try_into().map(|bytes| u32::from_be_bytes(bytes));
+                            let index = recv_buffer[8..12].try_into().map(u32::from_be_bytes);
                             let connections = connections.read().await;
-                            let result = id.ok().and_then(|id| connections.get(id as usize).map(|c| (id, c)));
-                            if let Some((id, connection)) = result {
+                            let result = index.ok().and_then(|index| connections.get(index as usize).map(|c| (index, c)));
+                            if let Some((index, connection)) = result {
 
                                 let tag = &recv_buffer[0..8];
                                 let data = &recv_buffer[12..bytes_read];
 
-                                let is_connected = established_connections.read().await.contains(id);
+                                let is_connected = established_connections.read().await.contains(index);
                                 let mut connection_address = connection.address.lock().await;
-                                if is_connected && connection_address.map(|addr| addr == remote_address).unwrap_or(false) && connection.verify(data, tag) {
-                                    // Verified sender, create event:
-                                    inbound_sender.try_send(ServerEvent::Received { id, data: data.to_vec() }).unwrap();
+                                if is_connected {
+                                    let record = registry.slot(index);
+                                    let id = match record.as_ref() {
+                                        Some(record) => record.id(),
+                                        None => continue,
+                                    };
+                                    let mut report = |kind, detail: String| {
+                                        if let Some(record) = record.as_ref() {
+                                            report_error(&mut inbound_sender, record, kind, detail, &config);
+                                        }
+                                    };
+
+                                    if *connection_address != Some(remote_address) || connection.endpoint() != endpoint_index {
+                                        report(ProtocolErrorKind::UnexpectedAddress, format!("Datagram from {}.", remote_address));
+                                    } else if !connection.verify(data, tag) {
+                                        report(ProtocolErrorKind::InvalidTag, format!("Datagram of {} bytes.", bytes_read));
+                                    } else if !connection.accept_counter(data) {
+                                        report(ProtocolErrorKind::Replayed, format!("Datagram of {} bytes.", bytes_read));
+                                    } else {
+                                        if let Some(record) = record.as_ref() {
+                                            record.seen();
+                                            record.set_packet_loss(connection.packet_loss());
+                                        }
+
+                                        let data = &data[connection::COUNTER_SIZE..];
+                                        let is_receive_only = record.as_ref().map(|record| record.is_receive_only()).unwrap_or(false);
+                                        let features = connection.features();
+
+                                        let (sequence, payload) = datagram::decode_sequenced(data);
+                                        let delivery = if sequence.is_some() { Delivery::UnreliableSequenced } else { Delivery::Unreliable };
+                                        // Sequenced messages older than one already delivered are dropped.
+                                        let is_fresh = || sequence.is_none_or(|sequence| connection.accept_sequence(sequence));
+
+                                        // Verified sender, create events:
+                                        match payload {
+                                            Ok(Payload::TimeRequest { .. }) if !features.contains(Features::TIME_SYNC) => {
+                                                report(ProtocolErrorKind::MalformedDatagram, "Time sync was not negotiated.".to_string());
+                                            },
+                                            Ok(Payload::Fragment(_)) if !features.contains(Features::FRAGMENTATION) => {
+                                                report(ProtocolErrorKind::MalformedDatagram, "Fragmentation was not negotiated.".to_string());
+                                            },
+                                            Ok(_) if sequence.is_some() && !features.contains(Features::SEQUENCING) => {
+                                                report(ProtocolErrorKind::MalformedDatagram, "Sequencing was not negotiated.".to_string());
+                                            },
+                                            Ok(Payload::TimeRequest { client_time }) => {
+                                                let payload = connection.sequence(datagram::encode_time_response(client_time, Clock::server_time()));
+                                                let mut bytes = connection.sign(&payload).to_vec(); // Add tag.
+                                                bytes.extend(payload); // Add payload.
+
+                                                let endpoint = &mut endpoints[endpoint_index];
+                                                match netsim::send(&endpoint.socket, &mut endpoint.simulator, bytes, Some(remote_address)).await {
+                                                    Ok(_) => {},
+                                                    Err(err) => {
+                                                        log::debug!("Error writing time response (UDP): {}", err);
+                                                        report(ProtocolErrorKind::WriteFailed, err.to_string());
+                                                    }
+                                                }
+                                            },
+                                            Ok(Payload::TimeResponse { .. }) => {
+                                                report(ProtocolErrorKind::MalformedDatagram, "Unexpected time response.".to_string());
+                                            },
+                                            Ok(_) if is_receive_only => {
+                                                report(ProtocolErrorKind::ReceiveOnly, format!("Datagram of {} bytes.", bytes_read));
+                                            },
+                                            Ok(Payload::Messages(messages)) => {
+                                                let messages = if is_fresh() { messages } else { Vec::new() };
+                                                if let Some(record) = record.as_ref() {
+                                                    record.received(messages.len() as u64, bytes_read);
+                                                }
+                                                for message in messages {
+                                                    let mut data = message.to_vec();
+                                                    hooks.receive(&mut data, delivery);
+                                                    let receive = span::receive(&connection.span, data.len(), delivery);
+                                                    received.push((ServerEvent::Received { id, header: Header::default(), data, received_at }, receive));
+                                                }
+                                            },
+                                            Ok(Payload::Fragment(fragment)) => {
+                                                let message = connection.reassembler.lock().unwrap().insert(fragment).filter(|_| is_fresh());
+                                                if let Some(record) = record.as_ref() {
+                                                    record.received(message.is_some() as u64, bytes_read);
+                                                }
+                                                if let Some(mut data) = message {
+                                                    hooks.receive(&mut data, delivery);
+                                                    let receive = span::receive(&connection.span, data.len(), delivery);
+                                                    received.push((ServerEvent::Received { id, header: Header::default(), data, received_at }, receive));
+                                                }
+                                            },
+                                            Err(err) => {
+                                                log::debug!("Error decoding datagram (UDP): {}", err);
+                                                report(ProtocolErrorKind::MalformedDatagram, err.to_string());
+                                            }
+                                        }
+                                    }
                                 } else if !is_connected && connection_address.is_none() && data == b"ACK" && connection.verify(data, tag) {
                                     // Handshake - Received UDP, respond with ACK (3):
                                     *connection_address = Some(remote_address);
-                                    connection.write(b"ACK").await.unwrap(); // TODO: handle possible error?
+                                    connection.set_endpoint(endpoint_index);
+                                    if let Err(err) = connection.write(b"ACK").await {
+                                        log::debug!("Error writing handshake ACK (TCP): {}", err);
+                                        if let Some(record) = registry.slot(index) {
+                                            report_error(&mut inbound_sender, &record, ProtocolErrorKind::WriteFailed, err.to_string(), &config);
+                                            handshake_error = Some((record.id(), err.kind()));
+                                        }
+                                    }
                                 }
                             }
                         }
+
+                        for (event, receive) in received {
+                            dispatch(&mut inbound_sender, event, &config).instrument(receive).await;
+                        }
+                        if let Some((id, kind)) = handshake_error {
+                            Self::close(id, DisconnectReason::Io(kind), &config, &connections, &established_connections, &registry, sessions.as_deref(), &mut inbound_sender).await;
+                        }
                     }
                 },
-                result = outbound_receiver.next() => {
-                    if let Some((id, mut data, delivery)) = result {
-                        let is_connected = established_connections.read().await.contains(id);
+                Some(command) = outbound_receiver.next() => {
+                    let mut overflow = config.send_rate_overflow;
+                    let command = match command {
+                        ServerCommand::SendWithReceipt { id, header, data, reply } => {
+                            let is_connected = established_connections.read().await.contains(id.index());
+                            let connections = connections.read().await;
+                            match registry.get(id).and(connections.get(id.index() as usize)).filter(|_| is_connected) {
+                                Some(connection) if !connection.features().contains(Features::RECEIPTS) => {
+                                    if let Some(record) = registry.get(id) {
+                                        record.queued().pop();
+                                    }
+                                    let _ = reply.send(Err(SendError::Unsupported));
+                                    continue;
+                                },
+                                Some(connection) => {
+                                    // The receipt frame is paced like the message, so the message is the next frame the client reads.
+                                    // Both are queued rather than dropped, as the receipt would otherwise never arrive.
+                                    overflow = Overflow::Block;
+                                    connection.expect_receipt(reply);
+                                    let ready = connection.pacer.lock().unwrap().reliable(connection.frame(connection::RECEIPT).into(), overflow);
+                                    if let Paced::Ready(frame) = ready {
+                                        if let Err(err) = connection.write_frame(&frame).await {
+                                            log::debug!("Error writing receipt request (TCP): {}", err);
+                                        }
+                                    }
+                                },
+                                // Dropping the reply fails the receipt, as the message is not written.
+                                None => {},
+                            }
+                            ServerCommand::Send { id, header, data, delivery: Delivery::Reliable }
+                        },
+                        ServerCommand::Flush { id, reply } => {
+                            let connections = connections.read().await;
+                            let flushed = match registry.get(id).and(connections.get(id.index() as usize)) {
+                                Some(connection) => connection.flush().await.map_err(|err| {
+                                    log::debug!("Error flushing stream (TCP): {}", err);
+                                    if let Some(record) = registry.get(id) {
+                                        report_error(&mut inbound_sender, &record, ProtocolErrorKind::WriteFailed, err.to_string(), &config);
+                                    }
+                                    SendError::WriteFailed(err.kind())
+                                }),
+                                None => Err(SendError::UnknownConnection),
+                            };
+                            let _ = reply.send(flushed);
+                            continue;
+                        },
+                        command => command,
+                    };
+
+                    let (ids, header, data, delivery) = match &command {
+                        ServerCommand::Send { id, header, data, delivery } => (std::slice::from_ref(id), *header, &data[..], *delivery),
+                        ServerCommand::Multicast { ids, header, data, delivery } => (&ids[..], *header, &data[..], *delivery),
+                        // Replaced by a send above, once the receipt is expected.
+                        ServerCommand::SendWithReceipt { id, header, data, .. } => (std::slice::from_ref(id), *header, &data[..], Delivery::Reliable),
+                        // Replied to above.
+                        ServerCommand::Flush { .. } => continue,
+                        ServerCommand::SetSendRate { id, bytes_per_sec } => {
+                            let connections = connections.read().await;
+                            if let Some(connection) = registry.get(*id).and(connections.get(id.index() as usize)) {
+                                connection.pacer.lock().unwrap().set_rate(*bytes_per_sec);
+                            }
+                            continue;
+                        },
+                        ServerCommand::Cork { id } => {
+                            let connections = connections.read().await;
+                            if let Some(connection) = registry.get(*id).and(connections.get(id.index() as usize)) {
+                                connection.cork();
+                            }
+                            continue;
+                        },
+                        ServerCommand::Disconnect { id } => {
+                            Self::close(*id, DisconnectReason::ClosedLocally, &config, &connections, &established_connections, &registry, sessions.as_deref(), &mut inbound_sender).await;
+                            continue;
+                        },
+                        ServerCommand::Shutdown => {
+                            let ids: Vec<_> = connections
+                                .read()
+                                .await
+                                .iter()
+                                .filter_map(|(index, _)| registry.slot(index as u32))
+                                .map(|record| record.id())
+                                .collect();
+
+                            for id in ids {
+                                Self::close(id, DisconnectReason::ClosedLocally, &config, &connections, &established_connections, &registry, sessions.as_deref(), &mut inbound_sender).await;
+                            }
+
+                            log::debug!("Server shut down.");
+                            return Ok(());
+                        },
+                        ServerCommand::Uncork { id } => {
+                            let connections = connections.read().await;
+                            if let Some(connection) = registry.get(*id).and(connections.get(id.index() as usize)) {
+                                match connection.uncork().await {
+                                    Ok(()) => {},
+                                    Err(err) => {
+                                        log::debug!("Error writing message (TCP): {}", err);
+                                        if let Some(record) = registry.get(*id) {
+                                            report_error(&mut inbound_sender, &record, ProtocolErrorKind::WriteFailed, err.to_string(), &config);
+                                        }
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                    };
+
+                    // A reliable message is encoded once, and framed once for the connections that compress frames and once for those that do not.
+                    // The frames are shared by all connections the message is sent to.
+                    let mut encoded = None;
+                    let mut frames: [Option<Arc<[u8]>>; 2] = [None, None];
+
+                    for &id in ids {
+                        // Stale ids find no record, even when another connection took over the slot.
+                        match registry.get(id) {
+                            Some(record) => record.queued().pop(),
+                            None => continue,
+                        }
+                        let is_connected = established_connections.read().await.contains(id.index());
                         if is_connected {
                             let connections = connections.read().await;
-                            if let Some(connection) = connections.get(id as usize) {
+                            if let Some(connection) = connections.get(id.index() as usize) {
 
                                 match delivery {
                                     Delivery::Reliable => {
-                                        match connection.write(&data).await {
-                                            Ok(()) => {},
-                                            Err(err) => log::debug!("Error writing message (TCP): {}", err)
+                                        let encoded = encoded.get_or_insert_with(|| header.encode(data));
+                                        let frame = frames[connection.compresses() as usize].get_or_insert_with(|| connection.frame(encoded).into()).clone();
+                                        let size = frame.len();
+                                        let record = registry.get(id);
+
+                                        let (ready, queued_reliable) = {
+                                            let mut pacer = connection.pacer.lock().unwrap();
+                                            (pacer.reliable(frame, overflow), pacer.queued())
+                                        };
+                                        if let Some(record) = record.as_ref() {
+                                            if ready != Paced::Dropped {
+                                                record.sent(1, size);
+                                            }
+                                            record.set_queued_reliable(queued_reliable);
+                                        }
+
+                                        if let Paced::Ready(frame) = ready {
+                                            match connection.write_frame(&frame).instrument(span::send(&connection.span, frame.len(), Delivery::Reliable)).await {
+                                                Ok(()) => {},
+                                                Err(err) => {
+                                                    log::debug!("Error writing message (TCP): {}", err);
+                                                    if let Some(record) = record.as_ref() {
+                                                        report_error(&mut inbound_sender, record, ProtocolErrorKind::WriteFailed, err.to_string(), &config);
+                                                    }
+                                                }
+                                            }
                                         }
                                     },
-                                    Delivery::Unreliable => {
+                                    Delivery::Unreliable | Delivery::UnreliableSequenced => {
                                         let connection_address = connection.address.lock().await;
-                                        if let Some(connection_address) = *connection_address {
-                                            let tag = connection.sign(&data);
+                                        let connection_address = connection_address.filter(|_| connection.pacer.lock().unwrap().unreliable(data.len()));
+                                        if let Some(connection_address) = connection_address {
+                                            let sequence = connection.next_sequence(delivery);
+                                            match datagram::encode(data, || connection.next_fragment_id(), connection.max_fragments(&config)) {
+                                                Some(payloads) => {
+                                                    let mut bytes_sent = 0;
+                                                    for payload in payloads {
+                                                        let payload = match sequence {
+                                                            Some(sequence) => datagram::encode_sequenced(sequence, payload),
+                                                            None => payload,
+                                                        };
+                                                        let mut payload = connection.sequence(payload);
+                                                        let mut bytes = connection.sign(&payload).to_vec(); // Add tag.
+                                                        bytes.append(&mut payload); // Add payload.
 
-                                            let mut bytes = tag.to_vec();
-                                            bytes.append(&mut data);
+                                                        let endpoint = &mut endpoints[connection.endpoint()];
+                                                        let send = span::send(&connection.span, data.len(), delivery);
+                                                        match netsim::send(&endpoint.socket, &mut endpoint.simulator, bytes, Some(connection_address)).instrument(send).await {
+                                                            Ok(size) => bytes_sent += size,
+                                                            Err(err) => {
+                                                                log::debug!("Error writing message (UDP): {}", err);
+                                                                if let Some(record) = registry.get(id) {
+                                                                    report_error(&mut inbound_sender, &record, ProtocolErrorKind::WriteFailed, err.to_string(), &config);
+                                                                }
+                                                            }
+                                                        }
+                                                    }
 
-                                            match socket.send_to(&bytes, connection_address).await {
-                                                Ok(_) => {},
-                                                Err(err) => log::debug!("Error writing message (UDP): {}", err)
+                                                    if let Some(record) = registry.get(id) {
+                                                        record.sent(1, bytes_sent);
+                                                    }
+                                                },
+                                                None => log::debug!("Error writing message (UDP): {} bytes exceeds the maximum number of fragments.", data.len())
                                             }
                                         }
                                     }
@@ -239,18 +1029,302 @@ impl<'a> Server {
                         }
                     }
                 },
-                result = disconnect_receiver.next() => {
-                    if let Some(id) = result {
-                        let mut connections = connections.write().await;
-                        if let Some(connection) = connections.get_mut(id as usize) {
-                            match connection.write_stream.lock().await.shutdown().await {
-                                Ok(_) => {},
-                                Err(err) => log::error!("Disconnector error: {}", err)
+                _ = pacing_interval.tick() => {
+                    // Write reliable frames that were held back by a send rate:
+                    let connections = connections.read().await;
+                    for (index, connection) in connections.iter() {
+                        loop {
+                            let ready = connection.pacer.lock().unwrap().pop_ready();
+                            match ready {
+                                Some(frame) => match connection.write_frame(&frame).instrument(span::send(&connection.span, frame.len(), Delivery::Reliable)).await {
+                                    Ok(()) => {},
+                                    Err(err) => {
+                                        log::debug!("Error writing message (TCP): {}", err);
+                                        if let Some(record) = registry.slot(index as u32) {
+                                            report_error(&mut inbound_sender, &record, ProtocolErrorKind::WriteFailed, err.to_string(), &config);
+                                        }
+                                        break;
+                                    }
+                                },
+                                None => break
                             }
                         }
+
+                        if let Some(record) = registry.slot(index as u32) {
+                            record.set_queued_reliable(connection.pacer.lock().unwrap().queued());
+                        }
+                    }
+                },
+                _ = coalesce_interval.tick(), if coalesce.is_some() => {
+                    let connections = connections.read().await;
+                    for (index, connection) in connections.iter() {
+                        match connection.flush_coalesced().await {
+                            Ok(()) => {},
+                            Err(err) => {
+                                log::debug!("Error writing message (TCP): {}", err);
+                                if let Some(record) = registry.slot(index as u32) {
+                                    report_error(&mut inbound_sender, &record, ProtocolErrorKind::WriteFailed, err.to_string(), &config);
+                                }
+                            }
+                        }
+                    }
+                },
+                _ = stats_interval.tick(), if stats.is_some() => {
+                    let connections = connections.read().await;
+                    let established_connections = established_connections.read().await;
+                    for (index, _) in connections.iter() {
+                        let index = index as u32;
+                        if !established_connections.contains(index) {
+                            continue;
+                        }
+
+                        if let Some(record) = registry.slot(index) {
+                            let _ = inbound_sender.try_send(ServerEvent::Stats(record.stats()));
+                        }
+                    }
+                },
+                _ = idle_interval.tick(), if idle_timeout.is_some() || handshake_timeout.is_some() => {
+                    let records: Vec<_> = connections
+                        .read()
+                        .await
+                        .iter()
+                        .filter_map(|(index, _)| registry.slot(index as u32))
+                        .collect();
+
+                    for record in records {
+                        if idle_timeout.is_some_and(|idle_timeout| record.idle() > idle_timeout) {
+                            log::debug!("Closing connection {}, nothing was received for {:?}.", record.id(), idle_timeout);
+                        } else if !record.is_established() && handshake_timeout.is_some_and(|handshake_timeout| record.age() > handshake_timeout) {
+                            log::debug!("Closing connection {}, the handshake was not completed within {:?}.", record.id(), handshake_timeout);
+                        } else {
+                            continue;
+                        }
+
+                        Self::close(record.id(), DisconnectReason::TimedOut, &config, &connections, &established_connections, &registry, sessions.as_deref(), &mut inbound_sender).await;
+                    }
+                },
+                Some(id) = disconnect_receiver.next() => {
+                    Self::close(id, DisconnectReason::ClosedLocally, &config, &connections, &established_connections, &registry, sessions.as_deref(), &mut inbound_sender).await;
+                },
+                _ = sleep_until(simulator_due.unwrap_or_else(tokio::time::Instant::now)), if simulator_due.is_some() => {
+                    for endpoint in endpoints.iter_mut() {
+                        if let Some(simulator) = endpoint.simulator.as_mut() {
+                            simulator.flush(&endpoint.socket).await;
+                        }
                     }
                 }
             }
         }
     }
+
+    /// Binds the UDP socket and the TCP listener to the address, see [`Config::dual_stack`].
+    /// The listener is bound to the address the socket ended up with, so both share the port assigned by the operating system for port 0.
+    /// That port is only known to be free for UDP, so binding starts over with another port if it is taken for TCP.
+    async fn bind<A: ToSocketAddrs>(
+        address: &A,
+        config: &Config,
+    ) -> io::Result<(UdpSocket, TcpListener)> {
+        let mut attempt = 1;
+        loop {
+            match Self::bind_once(address, config).await {
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse && attempt < BIND_ATTEMPTS => {
+                    attempt += 1
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn bind_once<A: ToSocketAddrs>(
+        address: &A,
+        config: &Config,
+    ) -> io::Result<(UdpSocket, TcpListener)> {
+        if !config.dual_stack {
+            let socket = UdpSocket::bind(address).await?;
+            let listener = TcpListener::bind(socket.local_addr()?).await?;
+            return Ok((socket, listener));
+        }
+
+        let mut last_err = None;
+        for address in lookup_host(address).await? {
+            match Self::bind_dual_stack(address) {
+                Ok(sockets) => return Ok(sockets),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Could not resolve to any address.",
+            )
+        }))
+    }
+
+    fn bind_dual_stack(address: SocketAddr) -> io::Result<(UdpSocket, TcpListener)> {
+        let domain = Domain::for_address(address);
+
+        let udp = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        let tcp = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+        if address.is_ipv6() {
+            udp.set_only_v6(false)?;
+            tcp.set_only_v6(false)?;
+        }
+        // Like `TcpListener::bind`, so the server can restart while old connections are in TIME_WAIT.
+        #[cfg(unix)]
+        tcp.set_reuse_address(true)?;
+
+        udp.bind(&address.into())?;
+        tcp.bind(&udp.local_addr()?)?;
+        tcp.listen(1024)?;
+        udp.set_nonblocking(true)?;
+        tcp.set_nonblocking(true)?;
+
+        Ok((
+            UdpSocket::from_std(udp.into())?,
+            TcpListener::from_std(tcp.into())?,
+        ))
+    }
+
+    /// Closes a connection from the server side without waiting for the client,
+    /// dispatching [`ServerEvent::Disconnected`] with the reason if the connection was established.
+    #[allow(clippy::too_many_arguments)]
+    async fn close<T: AsyncRead + AsyncWrite, U: Send + Sync + Clone + 'static>(
+        id: ConnectionId,
+        reason: DisconnectReason,
+        config: &Config,
+        connections: &RwLock<Slab<Connection<T>>>,
+        established_connections: &RwLock<BitSet>,
+        registry: &Registry<U>,
+        sessions: Option<&Sessions>,
+        inbound_sender: &mut receiver::InnerSender<ServerEvent<U>>,
+    ) {
+        // A stale id must not close the connection that took over the slot.
+        if registry.get(id).is_none() {
+            return;
+        }
+
+        let connection = connections.write().await.try_remove(id.index() as usize);
+        if let Some(connection) = connection {
+            if let Some(reader) = connection.reader.lock().unwrap().take() {
+                reader.abort();
+            }
+
+            // Frames held back by the send rate or a cork are written first, so that closing does not lose them.
+            let frames = connection.pacer.lock().unwrap().drain();
+            let closed = async {
+                for frame in frames {
+                    connection.write_frame(&frame).await?;
+                }
+                connection.uncork().await?;
+                connection.close().await
+            };
+            match closed.await {
+                Ok(()) => {}
+                Err(err) => log::debug!("Error closing connection (TCP): {}", err),
+            }
+
+            registry.remove(id);
+            if established_connections.write().await.remove(id.index()) {
+                if let Some(sessions) = sessions {
+                    sessions.close(
+                        connection.token.into_inner().unwrap(),
+                        id,
+                        config.resume_window,
+                    );
+                }
+                dispatch(
+                    inbound_sender,
+                    ServerEvent::Disconnected { id, reason },
+                    config,
+                )
+                .await;
+            }
+        }
+    }
+}
+
+/// Records a protocol error of a connection, and dispatches it as [`ServerEvent::Error`] if [`Config::error_events`] is set.
+/// Like [`ServerEvent::Stats`], the event is dropped when the event queue is full.
+fn report_error<U: Send + Sync + Clone, D: Into<String>>(
+    inbound_sender: &mut receiver::InnerSender<ServerEvent<U>>,
+    record: &Record<U>,
+    kind: ProtocolErrorKind,
+    detail: D,
+    config: &Config,
+) {
+    let error = record.report(kind, detail);
+    if config.error_events {
+        let _ = inbound_sender.try_send(ServerEvent::Error {
+            id: record.id(),
+            error,
+        });
+    }
+}
+
+/// Fails with [`io::ErrorKind::TimedOut`] if the handshake does not complete by the deadline, see [`Config::handshake_timeout`].
+#[cfg(feature = "rustls")]
+async fn before<T, F: Future<Output = io::Result<T>>>(
+    deadline: Option<Instant>,
+    handshake: F,
+) -> io::Result<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), handshake)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Handshake timed out.",
+                ))
+            }),
+        None => handshake.await,
+    }
+}
+
+/// Dispatches an event to the application, logging instead of failing if the [`ServerReceiver`] was dropped.
+pub(crate) async fn dispatch<U: Send + Sync + Clone>(
+    inbound_sender: &mut receiver::InnerSender<ServerEvent<U>>,
+    event: ServerEvent<U>,
+    config: &Config,
+) {
+    if let Err(err) = receiver::dispatch(inbound_sender, event, config.event_overflow).await {
+        log::debug!("Error dispatching event: {}", err);
+    }
+}
+
+/// Accepts the next stream on any of the listeners, polling them in turn from `offset`.
+fn accept_any(
+    listeners: &[TcpListener],
+    offset: usize,
+) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + '_ {
+    poll_fn(move |cx| {
+        for i in 0..listeners.len() {
+            if let Poll::Ready(result) = listeners[(offset + i) % listeners.len()].poll_accept(cx) {
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    })
+}
+
+/// Receives the next datagram on any of the endpoints, polling them in turn from `offset`.
+/// Returns the index of the endpoint along with the size of the datagram and the address it came from.
+fn recv_any<'a>(
+    endpoints: &'a [Endpoint],
+    buffer: &'a mut [u8],
+    offset: usize,
+) -> impl Future<Output = (usize, io::Result<(usize, SocketAddr)>)> + 'a {
+    poll_fn(move |cx| {
+        for i in 0..endpoints.len() {
+            let index = (offset + i) % endpoints.len();
+            let mut read_buffer = ReadBuf::new(buffer);
+            if let Poll::Ready(result) =
+                endpoints[index].socket.poll_recv_from(cx, &mut read_buffer)
+            {
+                let size = read_buffer.filled().len();
+                return Poll::Ready((index, result.map(|address| (size, address))));
+            }
+        }
+        Poll::Pending
+    })
 }
@@ -0,0 +1,77 @@
+//! Spans correlating the log output of a client or a connection, recorded with `tracing` when the `tracing` feature is enabled.
+//!
+//! Every connection has a span carrying its id and peer address, which its handshake and messages are recorded under.
+//! Without the feature, spans are empty and instrumenting a future returns it unchanged.
+
+use std::{fmt::Display, net::SocketAddr};
+
+use crate::Delivery;
+
+#[cfg(feature = "tracing")]
+pub use tracing::{Instrument, Span};
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub trait Instrument: Sized {
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl<T> Instrument for T {}
+
+/// Task of a client, covering all of its connections when it reconnects.
+pub fn client() -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!("client");
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// Task of a server, covering the connections it accepts.
+pub fn server() -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!("server");
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// Handshake with a peer, before the connection is established.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub fn handshake(peer_address: SocketAddr) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::debug_span!("handshake", %peer_address);
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// Lifetime of a connection, from the end of its handshake until it closes.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub fn connection<I: Display>(id: I, peer_address: SocketAddr) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!("connection", %id, %peer_address);
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// Message sent on a connection.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub fn send(connection: &Span, bytes: usize, delivery: Delivery) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::trace_span!(parent: connection, "send", bytes, ?delivery);
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// Message received on a connection.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub fn receive(connection: &Span, bytes: usize, delivery: Delivery) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::trace_span!(parent: connection, "receive", bytes, ?delivery);
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
@@ -0,0 +1,55 @@
+use std::io;
+
+use crate::connection::Key;
+use tokio_rustls::rustls::{
+    internal::msgs::enums::AlertDescription, Certificate, CipherSuite, ProtocolVersion, Session,
+    TLSError,
+};
+
+/// Details of the negotiated TLS session of a connection.
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    /// Certificate chain presented by the peer, starting with its own certificate.
+    /// Empty if the peer did not authenticate, which is the case for clients unless the server config requires client certificates.
+    pub peer_certificates: Vec<Certificate>,
+    pub protocol_version: Option<ProtocolVersion>,
+    pub cipher_suite: Option<CipherSuite>,
+    /// Protocol agreed on with ALPN, if any, see [`ClientConfig::set_protocols`](tokio_rustls::rustls::ClientConfig::set_protocols) and [`ServerConfig::set_protocols`](tokio_rustls::rustls::ServerConfig::set_protocols).
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+impl TlsInfo {
+    pub(crate) fn new<S: Session>(session: &S) -> Self {
+        Self {
+            peer_certificates: session.get_peer_certificates().unwrap_or_default(),
+            protocol_version: session.get_protocol_version(),
+            cipher_suite: session
+                .get_negotiated_ciphersuite()
+                .map(|suite| suite.suite),
+            alpn_protocol: session.get_alpn_protocol().map(<[u8]>::to_vec),
+        }
+    }
+}
+
+/// Label of the keying material exported for the unreliable datagrams, see [`Key`].
+const KEY_LABEL: &[u8] = b"EXPORTER-zelda-datagram-key";
+
+/// Exports the key of the unreliable datagrams from a completed TLS session (RFC 5705, or RFC 8446 section 7.5 for TLS 1.3).
+pub(crate) fn export_key<S: Session>(session: &S) -> io::Result<Key> {
+    let mut key = Key::default();
+    session
+        .export_keying_material(&mut key, KEY_LABEL, None)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(key)
+}
+
+/// Whether a handshake failed because the peer supports none of the ALPN protocols.
+pub(crate) fn is_no_application_protocol(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref().and_then(|err| err.downcast_ref::<TLSError>()),
+        Some(TLSError::NoApplicationProtocol)
+            | Some(TLSError::AlertReceived(
+                AlertDescription::NoApplicationProtocol
+            ))
+    )
+}
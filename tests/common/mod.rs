@@ -0,0 +1,149 @@
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use tokio::time::{sleep, timeout, Duration};
+use zelda::{
+    Client, ClientEvent, ClientReceiver, ClientSender, Config, ConnectionId, Hooks, Server,
+    ServerEvent, ServerReceiver, ServerSender,
+};
+
+/// Generous bound for events that should arrive right away on loopback.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "rustls")]
+fn server_config() -> tokio_rustls::rustls::ServerConfig {
+    use tokio_rustls::rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys},
+        NoClientAuth, ServerConfig,
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    let certificates = {
+        let file = std::fs::File::open("./examples/certs/cert.pem").unwrap();
+        certs(&mut std::io::BufReader::new(file)).unwrap()
+    };
+    let key = {
+        let file = std::fs::File::open("./examples/certs/key.pem").unwrap();
+        pkcs8_private_keys(&mut std::io::BufReader::new(file)).unwrap()[0].clone()
+    };
+    config.set_single_cert(certificates, key).unwrap();
+    config
+}
+
+#[cfg(feature = "rustls")]
+fn client_config() -> tokio_rustls::rustls::ClientConfig {
+    let mut config = tokio_rustls::rustls::ClientConfig::new();
+    let file = std::fs::File::open("./examples/certs/cert.pem").unwrap();
+    config
+        .root_store
+        .add_pem_file(&mut std::io::BufReader::new(file))
+        .unwrap();
+    config
+}
+
+/// Starts a server accepting every token, and waits until it is bound.
+pub async fn listen(
+    address: &str,
+    config: Config,
+) -> (ServerSender, ServerReceiver<()>, SocketAddr) {
+    listen_with(address, config, |_| Some(())).await
+}
+
+/// Starts a server validating tokens with `validation_fn`, and waits until it is bound.
+pub async fn listen_with<U, F>(
+    address: &str,
+    config: Config,
+    validation_fn: F,
+) -> (ServerSender<U>, ServerReceiver<U>, SocketAddr)
+where
+    U: Send + Sync + Clone + 'static,
+    F: Fn(Vec<u8>) -> Option<U> + Send + Sync + Clone + 'static,
+{
+    let (sender, receiver, _, task) = Server::listen(
+        address.to_owned(),
+        config,
+        #[cfg(feature = "rustls")]
+        server_config(),
+        validation_fn,
+    );
+    tokio::spawn(task);
+
+    let address = timeout(TIMEOUT, async {
+        loop {
+            match sender.local_address() {
+                Some(address) => return address,
+                None => sleep(Duration::from_millis(1)).await,
+            }
+        }
+    })
+    .await
+    .expect("server did not bind");
+    (sender, receiver, address)
+}
+
+/// Starts a client, returning its task so tests can inspect how it ended.
+pub fn connect(
+    address: SocketAddr,
+    config: Config,
+) -> (
+    ClientSender,
+    ClientReceiver,
+    tokio::task::JoinHandle<Result<(), zelda::ClientError>>,
+) {
+    connect_with(address, config, vec![], Hooks::default())
+}
+
+/// Starts a client presenting `token`, and invoking the [`Hooks`] on the payload of every message, see [`connect`].
+pub fn connect_with(
+    address: SocketAddr,
+    config: Config,
+    token: Vec<u8>,
+    hooks: Hooks,
+) -> (
+    ClientSender,
+    ClientReceiver,
+    tokio::task::JoinHandle<Result<(), zelda::ClientError>>,
+) {
+    let (sender, receiver, task) = Client::connect_with_hooks(
+        address,
+        config,
+        #[cfg(feature = "rustls")]
+        tokio_rustls::webpki::DNSNameRef::try_from_ascii_str("localhost")
+            .unwrap()
+            .to_owned(),
+        #[cfg(feature = "rustls")]
+        client_config(),
+        token,
+        hooks,
+    );
+    (sender, receiver, tokio::spawn(task))
+}
+
+/// Waits for the next client event other than [`ClientEvent::Connecting`].
+pub async fn next_client_event(receiver: &mut ClientReceiver) -> ClientEvent {
+    loop {
+        match timeout(TIMEOUT, receiver.recv()).await {
+            Ok(Some(ClientEvent::Connecting)) => continue,
+            Ok(Some(event)) => return event,
+            Ok(None) => panic!("client receiver closed"),
+            Err(_) => panic!("timed out waiting for a client event"),
+        }
+    }
+}
+
+/// Waits for the next server event.
+pub async fn next_server_event(receiver: &mut ServerReceiver<()>) -> ServerEvent<()> {
+    match timeout(TIMEOUT, receiver.recv()).await {
+        Ok(Some(event)) => event,
+        Ok(None) => panic!("server receiver closed"),
+        Err(_) => panic!("timed out waiting for a server event"),
+    }
+}
+
+/// Waits until the server reports a new connection, returning its id.
+pub async fn accept(receiver: &mut ServerReceiver<()>) -> ConnectionId {
+    match next_server_event(receiver).await {
+        ServerEvent::Connected { id, .. } => id,
+        event => panic!("expected a connection, got {:?}", event),
+    }
+}
@@ -0,0 +1,304 @@
+mod common;
+
+use common::{
+    accept, connect, connect_with, listen, listen_with, next_client_event, next_server_event,
+    TIMEOUT,
+};
+use std::net::{IpAddr, SocketAddr};
+use tokio::time::{timeout, Duration, Instant};
+use zelda::{
+    ClientError, ClientEvent, Config, ConfigError, DisconnectReason, DuplicateKey, Hooks,
+    SendError, Server, ServerError, ServerEvent, ServerReceiver, ServerSender,
+};
+
+/// Clients past the maximum are rejected, until a connection closes.
+#[tokio::test]
+async fn connections_past_the_maximum_are_rejected() {
+    let config = Config::builder().max_connections(Some(2)).build().unwrap();
+    let (server, mut server_events, address) = listen("127.0.0.1:0", config).await;
+
+    let mut clients = vec![];
+    let mut ids = vec![];
+    for _ in 0..2 {
+        let (sender, mut events, task) = connect(address, Config::default());
+        ids.push(accept(&mut server_events).await);
+        assert!(matches!(
+            next_client_event(&mut events).await,
+            ClientEvent::Connected
+        ));
+        clients.push((sender, events, task));
+    }
+
+    let (_sender, _events, task) = connect(address, Config::default());
+    let result = timeout(TIMEOUT, task).await.unwrap().unwrap();
+    assert!(matches!(result, Err(ClientError::Rejected)));
+
+    // Existing connections are unaffected.
+    server
+        .broadcast_reliable(b"still connected".to_vec())
+        .unwrap();
+    for (_, events, _) in clients.iter_mut() {
+        match next_client_event(events).await {
+            ClientEvent::Received { data, .. } => assert_eq!(data, b"still connected"),
+            event => panic!("expected a message, got {:?}", event),
+        }
+    }
+
+    server.disconnect(ids[0]).unwrap();
+    assert!(matches!(
+        next_server_event(&mut server_events).await,
+        ServerEvent::Disconnected { .. }
+    ));
+
+    let (_sender, mut events, _task) = connect(address, Config::default());
+    accept(&mut server_events).await;
+    assert!(matches!(
+        next_client_event(&mut events).await,
+        ClientEvent::Connected
+    ));
+}
+
+/// Clients presenting the same token are reported as duplicates, clients without a token never are.
+#[tokio::test]
+async fn duplicate_tokens_are_reported() {
+    let config = Config::builder()
+        .duplicate_key(Some(DuplicateKey::Token))
+        .build()
+        .unwrap();
+    let (_server, mut server_events, address) = listen("127.0.0.1:0", config).await;
+
+    let mut clients = vec![];
+    let mut ids = vec![];
+    for token in [&b"player"[..], b"", b"", b"player"] {
+        clients.push(connect_with(
+            address,
+            Config::default(),
+            token.to_vec(),
+            Hooks::default(),
+        ));
+        ids.push(accept(&mut server_events).await);
+    }
+
+    // The duplicate is reported right after the connection event, so the token-less clients caused none.
+    match next_server_event(&mut server_events).await {
+        ServerEvent::DuplicateConnection {
+            existing_id,
+            new_id,
+            token,
+        } => {
+            assert_eq!((existing_id, new_id), (ids[0], ids[3]));
+            assert_eq!(token, b"player");
+        }
+        event => panic!("expected a duplicate connection, got {:?}", event),
+    }
+}
+
+/// A config that was not built with the builder is still validated, failing the task rather than panicking later.
+#[tokio::test]
+async fn invalid_config_fails_the_task() {
+    let config = Config {
+        max_reliable_size: 0,
+        ..Config::default()
+    };
+
+    let (_sender, _receiver, _, task) = Server::listen(
+        "127.0.0.1:0",
+        config,
+        #[cfg(feature = "rustls")]
+        tokio_rustls::rustls::ServerConfig::new(tokio_rustls::rustls::NoClientAuth::new()),
+        |_| Some(()),
+    );
+    // Spawned rather than awaited in place, as the server task is too large for the stack of the test.
+    assert!(matches!(
+        tokio::spawn(task).await.unwrap(),
+        Err(ServerError::Config(ConfigError::ZeroMaxReliableSize))
+    ));
+
+    let (_sender, _receiver, task) = connect("127.0.0.1:1".parse().unwrap(), config);
+    assert!(matches!(
+        task.await.unwrap(),
+        Err(ClientError::Config(ConfigError::ZeroMaxReliableSize))
+    ));
+}
+
+/// Commands to a connection that has closed fail up front instead of being dropped by the server task.
+#[tokio::test]
+async fn commands_to_a_closed_connection_fail() {
+    let (server, mut server_events, address) = listen("127.0.0.1:0", Config::default()).await;
+    let (_client, _client_events, _task) = connect(address, Config::default());
+    let id = accept(&mut server_events).await;
+
+    server.disconnect(id).unwrap();
+    // The connection is removed by the server task, not by the call.
+    assert!(matches!(
+        next_server_event(&mut server_events).await,
+        ServerEvent::Disconnected {
+            reason: DisconnectReason::ClosedLocally,
+            ..
+        }
+    ));
+
+    assert!(matches!(
+        server.disconnect(id),
+        Err(SendError::UnknownConnection)
+    ));
+    assert!(matches!(
+        server.set_send_rate(id, Some(1024)),
+        Err(SendError::UnknownConnection)
+    ));
+    assert!(matches!(server.cork(id), Err(SendError::UnknownConnection)));
+    assert!(matches!(
+        server.uncork(id),
+        Err(SendError::UnknownConnection)
+    ));
+}
+
+/// A silent client is disconnected once the idle timeout has passed, at the latest one check interval later.
+#[tokio::test]
+async fn silent_client_times_out_within_check_interval() {
+    let idle_timeout = Duration::from_millis(300);
+    let idle_check_interval = Duration::from_millis(50);
+    let config = Config::builder()
+        .idle_timeout(Some(idle_timeout))
+        .idle_check_interval(idle_check_interval)
+        .build()
+        .unwrap();
+    let (_server, mut server_events, address) = listen("127.0.0.1:0", config).await;
+
+    let silent = Config::builder()
+        .keep_alive_interval(None)
+        .time_sync_interval(None)
+        .build()
+        .unwrap();
+    let (_client, mut client_events, _task) = connect(address, silent);
+    accept(&mut server_events).await;
+    let connected_at = Instant::now();
+
+    match next_server_event(&mut server_events).await {
+        ServerEvent::Disconnected { reason, .. } => assert_eq!(reason, DisconnectReason::TimedOut),
+        event => panic!("expected a disconnect, got {:?}", event),
+    }
+    let elapsed = connected_at.elapsed();
+    // The connection was last seen just before it was reported as connected.
+    assert!(
+        elapsed + Duration::from_millis(20) >= idle_timeout,
+        "{:?}",
+        elapsed
+    );
+    assert!(
+        elapsed <= idle_timeout + idle_check_interval + Duration::from_millis(100),
+        "{:?}",
+        elapsed
+    );
+
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Disconnected {
+            reason: DisconnectReason::ClosedByPeer
+        }
+    ));
+}
+
+/// Exchanges a reliable and an unreliable message each way with a client connecting to `address`.
+async fn exchange(
+    server: &ServerSender,
+    server_events: &mut ServerReceiver<()>,
+    address: SocketAddr,
+) -> SocketAddr {
+    let (client, mut client_events, _task) = connect(address, Config::default());
+    let (id, peer_address) = match next_server_event(server_events).await {
+        ServerEvent::Connected {
+            id, peer_address, ..
+        } => (id, peer_address),
+        event => panic!("expected a connection, got {:?}", event),
+    };
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+
+    client.reliable(b"reliable".to_vec()).unwrap();
+    client.unreliable(b"unreliable".to_vec()).unwrap();
+    // The two channels are not ordered with respect to each other.
+    let mut received = vec![];
+    for _ in 0..2 {
+        match next_server_event(server_events).await {
+            ServerEvent::Received { data, .. } => received.push(data),
+            event => panic!("expected a message, got {:?}", event),
+        }
+    }
+    received.sort();
+    assert_eq!(received, [b"reliable".to_vec(), b"unreliable".to_vec()]);
+
+    server.reliable(id, b"reliable".to_vec()).unwrap();
+    server.unreliable(id, b"unreliable".to_vec()).unwrap();
+    let mut received = vec![];
+    for _ in 0..2 {
+        match next_client_event(&mut client_events).await {
+            ClientEvent::Received { data, .. } => received.push(data),
+            event => panic!("expected a message, got {:?}", event),
+        }
+    }
+    received.sort();
+    assert_eq!(received, [b"reliable".to_vec(), b"unreliable".to_vec()]);
+
+    peer_address
+}
+
+#[tokio::test]
+async fn ipv6_loopback_peer() {
+    let (server, mut server_events, address) = listen("[::1]:0", Config::default()).await;
+    assert!(address.is_ipv6());
+
+    let peer_address = exchange(&server, &mut server_events, address).await;
+    assert_eq!(peer_address.ip(), "::1".parse::<IpAddr>().unwrap());
+}
+
+#[tokio::test]
+async fn dual_stack_accepts_both_families() {
+    let config = Config::builder().dual_stack(true).build().unwrap();
+    let (server, mut server_events, address) = listen("[::]:0", config).await;
+
+    let v6 = SocketAddr::new("::1".parse().unwrap(), address.port());
+    let peer_address = exchange(&server, &mut server_events, v6).await;
+    assert_eq!(peer_address.ip(), "::1".parse::<IpAddr>().unwrap());
+
+    // IPv4 peers show up as IPv4-mapped IPv6 addresses.
+    let v4 = SocketAddr::new("127.0.0.1".parse().unwrap(), address.port());
+    let peer_address = exchange(&server, &mut server_events, v4).await;
+    assert_eq!(
+        peer_address.ip(),
+        "::ffff:127.0.0.1".parse::<IpAddr>().unwrap()
+    );
+}
+
+/// User data has the type of the claims, and is dropped with its connection.
+#[tokio::test]
+async fn user_data_is_typed_by_the_claim() {
+    let (server, mut server_events, address) =
+        listen_with("127.0.0.1:0", Config::default(), |_| {
+            Some(String::from("guest"))
+        })
+        .await;
+    let (_client, _client_events, _task) = connect(address, Config::default());
+    let (id, claim) = match timeout(TIMEOUT, server_events.recv()).await.unwrap() {
+        Some(ServerEvent::Connected { id, claim, .. }) => (id, claim),
+        event => panic!("expected a connection, got {:?}", event),
+    };
+
+    assert_eq!(server.user_data(id), None);
+    assert!(server.set_user_data(id, claim + " in lobby"));
+    assert_eq!(server.user_data(id), Some(String::from("guest in lobby")));
+
+    server.disconnect(id).unwrap();
+    assert!(matches!(
+        timeout(TIMEOUT, server_events.recv()).await.unwrap(),
+        Some(ServerEvent::Disconnected { .. })
+    ));
+    assert_eq!(server.user_data(id), None);
+    assert!(!server.set_user_data(id, String::from("closed")));
+}
@@ -0,0 +1,196 @@
+#![cfg(feature = "memory")]
+
+mod common;
+
+use common::{accept, next_client_event, next_server_event};
+use tokio::{
+    task::JoinHandle,
+    time::{Duration, Instant},
+};
+use zelda::{
+    ClientError, ClientEvent, ClientReceiver, ClientSender, Config, DisconnectReason, Link,
+    Network, ServerEvent, ServerReceiver, ServerSender,
+};
+
+/// Starts a server on the network accepting every token.
+fn listen(network: &Network, config: Config) -> (ServerSender, ServerReceiver<()>) {
+    let (sender, receiver, _, task) = network.listen(config, |_| Some(()));
+    tokio::spawn(task);
+    (sender, receiver)
+}
+
+/// Starts a client on the network, returning its task so tests can inspect how it ended.
+fn connect(
+    network: &Network,
+    config: Config,
+) -> (
+    ClientSender,
+    ClientReceiver,
+    JoinHandle<Result<(), ClientError>>,
+) {
+    let (sender, receiver, task) = network.connect(config, vec![]);
+    (sender, receiver, tokio::spawn(task))
+}
+
+/// Waits until the client is connected.
+async fn connected(receiver: &mut ClientReceiver) {
+    match next_client_event(receiver).await {
+        ClientEvent::Connected => {}
+        event => panic!("expected the connection, got {:?}", event),
+    }
+}
+
+async fn client_received(receiver: &mut ClientReceiver) -> Vec<u8> {
+    match next_client_event(receiver).await {
+        ClientEvent::Received { data, .. } => data,
+        event => panic!("expected a message, got {:?}", event),
+    }
+}
+
+async fn server_received(receiver: &mut ServerReceiver<()>) -> Vec<u8> {
+    match next_server_event(receiver).await {
+        ServerEvent::Received { data, .. } => data,
+        event => panic!("expected a message, got {:?}", event),
+    }
+}
+
+#[tokio::test]
+async fn messages_are_delivered_both_ways() {
+    let network = Network::default();
+    let (server, mut server_events) = listen(&network, Config::default());
+    let (client, mut client_events, _task) = connect(&network, Config::default());
+    let id = accept(&mut server_events).await;
+    connected(&mut client_events).await;
+
+    client.reliable(vec![1]).unwrap();
+    client.unreliable(vec![2]).unwrap();
+    assert_eq!(server_received(&mut server_events).await, vec![1]);
+    assert_eq!(server_received(&mut server_events).await, vec![2]);
+
+    server.reliable(id, vec![3]).unwrap();
+    server.unreliable(id, vec![4]).unwrap();
+    assert_eq!(client_received(&mut client_events).await, vec![3]);
+    assert_eq!(client_received(&mut client_events).await, vec![4]);
+
+    // A receipt resolves once the server task has read the message.
+    client.reliable_with_ack(vec![5]).await.unwrap();
+    assert_eq!(server_received(&mut server_events).await, vec![5]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn latency_delays_messages() {
+    let latency = Duration::from_millis(50);
+    let network = Network::new(Link { latency, loss: 0.0 });
+    let (server, mut server_events) = listen(&network, Config::default());
+    let (client, mut client_events, _task) = connect(&network, Config::default());
+    let id = accept(&mut server_events).await;
+    connected(&mut client_events).await;
+
+    let sent = Instant::now();
+    server.reliable(id, vec![1]).unwrap();
+    assert_eq!(client_received(&mut client_events).await, vec![1]);
+    assert!(sent.elapsed() >= latency);
+
+    let sent = Instant::now();
+    client.reliable(vec![2]).unwrap();
+    assert_eq!(server_received(&mut server_events).await, vec![2]);
+    assert!(sent.elapsed() >= latency);
+    assert_eq!(client.ping().await.unwrap(), latency * 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn loss_drops_only_unreliable_messages() {
+    let network = Network::new(Link {
+        latency: Duration::from_millis(10),
+        loss: 1.0,
+    });
+    let (server, mut server_events) = listen(&network, Config::default());
+    let (client, mut client_events, _task) = connect(&network, Config::default());
+    let id = accept(&mut server_events).await;
+    connected(&mut client_events).await;
+
+    // Each direction keeps the order of the messages, so the reliable message arriving first means the unreliable one was lost.
+    client.unreliable(vec![1]).unwrap();
+    client.reliable(vec![2]).unwrap();
+    assert_eq!(server_received(&mut server_events).await, vec![2]);
+
+    server.unreliable(id, vec![3]).unwrap();
+    server.reliable(id, vec![4]).unwrap();
+    assert_eq!(client_received(&mut client_events).await, vec![4]);
+}
+
+#[tokio::test]
+async fn disconnects_are_seen_by_both_sides() {
+    let network = Network::default();
+    let (server, mut server_events) = listen(&network, Config::default());
+
+    let (_client, mut client_events, task) = connect(&network, Config::default());
+    let id = accept(&mut server_events).await;
+    connected(&mut client_events).await;
+    server.disconnect(id).unwrap();
+    assert!(matches!(
+        next_server_event(&mut server_events).await,
+        ServerEvent::Disconnected { id: closed, reason: DisconnectReason::ClosedLocally } if closed == id
+    ));
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Disconnected {
+            reason: DisconnectReason::ClosedByPeer
+        }
+    ));
+    task.await.unwrap().unwrap();
+
+    let (client, mut client_events, task) = connect(&network, Config::default());
+    let id = accept(&mut server_events).await;
+    connected(&mut client_events).await;
+    client.disconnect().unwrap();
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Disconnected {
+            reason: DisconnectReason::ClosedLocally
+        }
+    ));
+    assert!(matches!(
+        next_server_event(&mut server_events).await,
+        ServerEvent::Disconnected { id: closed, reason: DisconnectReason::ClosedByPeer } if closed == id
+    ));
+    task.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn connections_past_the_maximum_are_rejected() {
+    let network = Network::default();
+    let config = Config::builder().max_connections(Some(1)).build().unwrap();
+    let (_server, mut server_events) = listen(&network, config);
+
+    let (_client, mut client_events, _task) = connect(&network, Config::default());
+    accept(&mut server_events).await;
+    connected(&mut client_events).await;
+
+    let (_rejected, _rejected_events, task) = connect(&network, Config::default());
+    assert!(matches!(task.await.unwrap(), Err(ClientError::Rejected)));
+}
+
+#[tokio::test]
+async fn invalid_tokens_are_rejected() {
+    let network = Network::default();
+    let (_server, _server_events, _, task) =
+        network.listen(Config::default(), |token| (token == b"valid").then_some(()));
+    tokio::spawn(task);
+
+    let (_client, mut client_events, task) = network.connect(Config::default(), b"valid".to_vec());
+    let _task = tokio::spawn(task);
+    connected(&mut client_events).await;
+
+    let (_client, _client_events, task) = network.connect(Config::default(), b"invalid".to_vec());
+    assert!(matches!(task.await, Err(ClientError::InvalidToken)));
+}
+
+#[tokio::test]
+async fn connecting_without_a_server_fails() {
+    let (_client, _client_events, task) = connect(&Network::default(), Config::default());
+    assert!(matches!(
+        task.await.unwrap(),
+        Err(ClientError::Unreachable(_))
+    ));
+}
@@ -0,0 +1,265 @@
+mod common;
+
+use common::{accept, connect, connect_with, listen, next_client_event, next_server_event};
+use futures::stream::{self, StreamExt};
+use tokio::time::Duration;
+use zelda::{ClientEvent, Config, Delivery, DisconnectReason, Hooks, SendError, ServerEvent};
+
+/// Timers firing while a large frame is only partly read must not cut the frame short.
+#[tokio::test]
+async fn large_frames_survive_client_timers() {
+    let (server, mut server_events, address) = listen("127.0.0.1:0", Config::default()).await;
+    let config = Config::builder()
+        .time_sync_interval(Some(Duration::from_millis(1)))
+        .keep_alive_interval(Some(Duration::from_millis(1)))
+        .build()
+        .unwrap();
+    let (_client, mut client_events, _task) = connect(address, config);
+    let id = accept(&mut server_events).await;
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+
+    for i in 0..64u8 {
+        server.reliable(id, vec![i; 256 * 1024]).unwrap();
+    }
+    for i in 0..64u8 {
+        match next_client_event(&mut client_events).await {
+            ClientEvent::Received { data, .. } => assert_eq!(data, vec![i; 256 * 1024]),
+            event => panic!("expected message {}, got {:?}", i, event),
+        }
+    }
+}
+
+/// Messages held back by a send rate or a cork are written before the stream is closed.
+#[tokio::test]
+async fn disconnect_writes_held_back_messages() {
+    let (server, mut server_events, address) = listen("127.0.0.1:0", Config::default()).await;
+    let (_client, mut client_events, _task) = connect(address, Config::default());
+    let id = accept(&mut server_events).await;
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+
+    server.set_send_rate(id, Some(1024)).unwrap();
+    for i in 0..8u8 {
+        server.reliable(id, vec![i; 1024]).unwrap();
+    }
+    server.set_send_rate(id, None).unwrap();
+    server.cork(id).unwrap();
+    server.reliable(id, vec![8; 1024]).unwrap();
+    server.disconnect(id).unwrap();
+
+    for i in 0..9u8 {
+        match next_client_event(&mut client_events).await {
+            ClientEvent::Received { data, .. } => assert_eq!(data, vec![i; 1024]),
+            event => panic!("expected message {}, got {:?}", i, event),
+        }
+    }
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Disconnected {
+            reason: DisconnectReason::ClosedByPeer
+        }
+    ));
+}
+
+/// A message exceeding the maximum size is refused when sent, rather than dropping the connection once the peer reads it.
+#[tokio::test]
+async fn oversized_message_is_refused() {
+    let config = Config::builder().max_reliable_size(1024).build().unwrap();
+    let (server, mut server_events, address) = listen("127.0.0.1:0", config).await;
+    let (client, mut client_events, _task) = connect(address, config);
+    let id = accept(&mut server_events).await;
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+
+    assert!(matches!(
+        server.reliable(id, vec![0; 1024]),
+        Err(SendError::TooLarge { size: 1024, .. })
+    ));
+    assert!(matches!(
+        client.reliable(vec![0; 1024]),
+        Err(SendError::TooLarge { size: 1024, .. })
+    ));
+
+    // The connection is unaffected.
+    server.reliable(id, vec![1; 512]).unwrap();
+    match next_client_event(&mut client_events).await {
+        ClientEvent::Received { data, .. } => assert_eq!(data, vec![1; 512]),
+        event => panic!("expected a message, got {:?}", event),
+    }
+    client.reliable(vec![2; 512]).unwrap();
+    match next_server_event(&mut server_events).await {
+        ServerEvent::Received { data, .. } => assert_eq!(data, vec![2; 512]),
+        event => panic!("expected a message, got {:?}", event),
+    }
+}
+
+/// A message sent before the handshake completes is queued and delivered exactly once.
+#[tokio::test]
+async fn message_sent_before_connecting_is_delivered_once() {
+    let (_server, mut server_events, address) = listen("127.0.0.1:0", Config::default()).await;
+    let (client, mut client_events, _task) = connect(address, Config::default());
+    client.reliable(b"early".to_vec()).unwrap();
+
+    accept(&mut server_events).await;
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+    client.reliable(b"late".to_vec()).unwrap();
+
+    for expected in [&b"early"[..], &b"late"[..]].iter() {
+        match next_server_event(&mut server_events).await {
+            ServerEvent::Received { data, .. } => assert_eq!(&data, expected),
+            event => panic!("expected a message, got {:?}", event),
+        }
+    }
+}
+
+/// Messages a client task never writes stop counting as queued once the task ends.
+#[tokio::test]
+async fn queued_messages_are_forgotten_when_the_client_ends() {
+    // Nothing listens on the port once the listener is dropped, so connecting fails.
+    let address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (client, _client_events, task) = connect(address, Config::default());
+    for _ in 0..3 {
+        client.reliable(vec![0; 8]).unwrap();
+    }
+    assert_eq!(client.queued(), 3);
+
+    assert!(task.await.unwrap().is_err());
+    assert_eq!(client.queued(), 0);
+    assert!(matches!(
+        client.reliable(vec![0; 8]),
+        Err(SendError::Disconnected)
+    ));
+    assert_eq!(client.queued(), 0);
+}
+
+/// The size limits apply to the data returned by [`Hooks::on_send`], so a message the hook grows past them is refused when sent.
+#[tokio::test]
+async fn message_grown_by_the_send_hook_is_refused() {
+    let config = Config::builder().max_reliable_size(1024).build().unwrap();
+    let (_server, mut server_events, address) = listen("127.0.0.1:0", config).await;
+    let hooks = Hooks::default().on_send(|data, _| data.extend_from_slice(&[0; 16]));
+    let (client, mut client_events, _task) = connect_with(address, config, vec![], hooks);
+    accept(&mut server_events).await;
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+
+    assert!(matches!(
+        client.reliable(vec![1; 1010]),
+        Err(SendError::TooLarge { size: 1026, .. })
+    ));
+
+    // The connection is unaffected, and the hook still applies.
+    client.reliable(vec![1; 512]).unwrap();
+    match next_server_event(&mut server_events).await {
+        ServerEvent::Received { data, .. } => assert_eq!(data.len(), 512 + 16),
+        event => panic!("expected a message, got {:?}", event),
+    }
+}
+
+/// Forwarding a stream into the server sender delivers every message, skipping those to connections that are gone.
+#[tokio::test]
+async fn stream_forwarded_into_the_server_skips_closed_connections() {
+    let (server, mut server_events, address) = listen("127.0.0.1:0", Config::default()).await;
+    let (_closed, _closed_events, _closed_task) = connect(address, Config::default());
+    let closed_id = accept(&mut server_events).await;
+    let (_client, mut client_events, _task) = connect(address, Config::default());
+    let id = accept(&mut server_events).await;
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+    server.disconnect(closed_id).unwrap();
+    assert!(matches!(
+        next_server_event(&mut server_events).await,
+        ServerEvent::Disconnected { .. }
+    ));
+
+    let messages = vec![
+        (id, vec![1], Delivery::Reliable),
+        (closed_id, vec![2], Delivery::Reliable),
+        (id, vec![3], Delivery::Reliable),
+    ];
+    stream::iter(messages.into_iter().map(Ok))
+        .forward(server.clone())
+        .await
+        .unwrap();
+
+    for expected in [vec![1], vec![3]] {
+        match next_client_event(&mut client_events).await {
+            ClientEvent::Received { data, .. } => assert_eq!(data, expected),
+            event => panic!("expected a message, got {:?}", event),
+        }
+    }
+    assert_eq!(server.stats(id).unwrap().queued, 0);
+}
+
+/// A multicast message reaches every listed connection once, and skips the connections that are gone.
+#[tokio::test]
+async fn multicast_reaches_each_listed_connection() {
+    // With compression, the server builds a compressed and an uncompressed frame for the same message.
+    #[cfg(feature = "lz4")]
+    let config = Config::builder()
+        .compression(Some(zelda::Compression::Lz4))
+        .build()
+        .unwrap();
+    #[cfg(not(feature = "lz4"))]
+    let config = Config::default();
+    let (server, mut server_events, address) = listen("127.0.0.1:0", config).await;
+
+    let mut clients = vec![];
+    let mut ids = vec![];
+    for config in [config, Config::default(), Config::default()] {
+        let (client, mut events, task) = connect(address, config);
+        ids.push(accept(&mut server_events).await);
+        assert!(matches!(
+            next_client_event(&mut events).await,
+            ClientEvent::Connected
+        ));
+        clients.push((client, events, task));
+    }
+
+    let (_closed, _closed_events, _closed_task) = connect(address, Config::default());
+    let closed_id = accept(&mut server_events).await;
+    server.disconnect(closed_id).unwrap();
+    assert!(matches!(
+        next_server_event(&mut server_events).await,
+        ServerEvent::Disconnected { .. }
+    ));
+
+    let data = vec![7; 4096];
+    let listed = [ids[0], closed_id, ids[1]];
+    assert_eq!(
+        server
+            .multicast(&listed, data.clone(), Delivery::Reliable)
+            .unwrap(),
+        2
+    );
+    server.reliable(ids[2], vec![1]).unwrap();
+
+    for (_, events, _) in &mut clients[..2] {
+        match next_client_event(events).await {
+            ClientEvent::Received { data: received, .. } => assert_eq!(received, data),
+            event => panic!("expected the multicast message, got {:?}", event),
+        }
+    }
+    // The connection that was not listed only receives its own message.
+    match next_client_event(&mut clients[2].1).await {
+        ClientEvent::Received { data, .. } => assert_eq!(data, vec![1]),
+        event => panic!("expected a message, got {:?}", event),
+    }
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::{accept, connect, listen, next_client_event, TIMEOUT};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Mutex,
+    time::{sleep, timeout, Duration},
+};
+use zelda::{ClientEvent, Config};
+
+/// Relays datagrams between the client, which sends to the socket of the relay, and the server, which sees the upstream socket as the client.
+async fn relay_datagrams(
+    socket: Arc<UdpSocket>,
+    upstream: Arc<UdpSocket>,
+    client: Arc<Mutex<Option<SocketAddr>>>,
+) {
+    let mut from_client = [0; 2048];
+    let mut from_server = [0; 2048];
+    loop {
+        tokio::select! {
+            Ok((size, address)) = socket.recv_from(&mut from_client) => {
+                *client.lock().await = Some(address);
+                let _ = upstream.send(&from_client[..size]).await;
+            },
+            Ok(size) = upstream.recv(&mut from_server) => {
+                if let Some(address) = *client.lock().await {
+                    let _ = socket.send_to(&from_server[..size], address).await;
+                }
+            },
+        }
+    }
+}
+
+/// An error receiving a datagram, here caused by an ICMP port unreachable, does not stop the client from receiving.
+#[tokio::test]
+async fn receive_error_does_not_stop_receiving() {
+    let (server, mut server_events, server_address) =
+        listen("127.0.0.1:0", Config::default()).await;
+
+    // The client sends datagrams to the address of its stream, so the relay listens for both on the same port.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut upstream = TcpStream::connect(server_address).await.unwrap();
+        let _ = copy_bidirectional(&mut stream, &mut upstream).await;
+    });
+    let upstream = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    upstream.connect(server_address).await.unwrap();
+    let client_address = Arc::new(Mutex::new(None));
+    let socket = Arc::new(UdpSocket::bind(relay_address).await.unwrap());
+    let relay = tokio::spawn(relay_datagrams(
+        socket,
+        upstream.clone(),
+        client_address.clone(),
+    ));
+
+    let (client, mut client_events, _task) = connect(relay_address, Config::default());
+    let id = accept(&mut server_events).await;
+    assert!(matches!(
+        next_client_event(&mut client_events).await,
+        ClientEvent::Connected
+    ));
+
+    // With the port closed, the next datagram of the client is answered with an ICMP port unreachable,
+    // which the connected socket of the client reports as an error on its next receive.
+    relay.abort();
+    let _ = relay.await;
+    client.unreliable(b"unreachable".to_vec()).unwrap();
+    sleep(Duration::from_millis(50)).await;
+
+    let socket = Arc::new(UdpSocket::bind(relay_address).await.unwrap());
+    tokio::spawn(relay_datagrams(socket, upstream, client_address));
+    let received = timeout(TIMEOUT, async {
+        loop {
+            server.unreliable(id, b"after error".to_vec()).unwrap();
+            match timeout(Duration::from_millis(50), client_events.recv()).await {
+                Ok(Some(ClientEvent::Received { data, .. })) => return data,
+                Ok(Some(event)) => panic!("expected a message, got {:?}", event),
+                Ok(None) => panic!("client receiver closed"),
+                Err(_) => continue,
+            }
+        }
+    })
+    .await
+    .expect("the client stopped receiving");
+    assert_eq!(received, b"after error");
+}